@@ -1,10 +1,14 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::transactions::{Amount, ClientId, Transaction, TransactionId};
-use std::collections::HashMap;
+use crate::ids::ExternalIds;
+use crate::storage::{DepositExpiry, DepositLog, MemoryStorage, RemovedDeposit, StorageBackend};
+use crate::transactions::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 /// Holds all the necessary info of an account for the output CSV
-#[derive(Serialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct CsvAccount {
     client: ClientId,
     available: Amount,
@@ -13,12 +17,443 @@ pub struct CsvAccount {
     locked: bool,
 }
 
-/// Helper struct which holds the necessary info of an account for the ClientInfoStorage
-#[derive(Clone, Copy)]
-struct Account {
+impl CsvAccount {
+    /// Creates the output of an account with the given balances, rounded like the output of
+    /// the stored accounts
+    pub(crate) fn from_balances(
+        client: ClientId,
+        available: Amount,
+        held: Amount,
+        locked: bool,
+    ) -> Self {
+        CsvAccount {
+            client,
+            available: round_to_4_dec(available),
+            held: round_to_4_dec(held),
+            total: round_to_4_dec(available + held),
+            locked,
+        }
+    }
+
+    /// Creates an account as read from an accounts output
+    pub(crate) fn from_output(
+        client: ClientId,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> Self {
+        CsvAccount {
+            client,
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+
+    /// Returns the client ID of the account
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// Returns the available amount of the account
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Returns the held amount of the account
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Returns the total amount of the account
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    /// Returns true if the account is locked
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+}
+
+/// Holds the state and the lock record of an account for the account details CSV. The IDs
+/// are strings with external IDs, see `with_external_ids`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CsvAccountDetails<C = ClientId, T = TransactionId> {
+    client: C,
     available: Amount,
     held: Amount,
+    total: Amount,
     locked: bool,
+    lock_reason: Option<LockReason>,
+    lock_tx: Option<T>,
+    lock_sequence: Option<u64>,
+    lock_events: usize,
+}
+
+impl CsvAccountDetails {
+    /// Replaces the internal IDs with the external IDs they are mapped to
+    pub fn with_external_ids(self, ids: &ExternalIds) -> CsvAccountDetails<String, String> {
+        CsvAccountDetails {
+            client: ids.client_label(self.client),
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            lock_reason: self.lock_reason,
+            lock_tx: self.lock_tx.map(|tx| ids.transaction_label(tx)),
+            lock_sequence: self.lock_sequence,
+            lock_events: self.lock_events,
+        }
+    }
+}
+
+/// Holds a single lock of an account for the lock history CSV. The IDs are strings with
+/// external IDs, see `with_external_ids`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CsvLockEvent<C = ClientId, T = TransactionId> {
+    client: C,
+    reason: LockReason,
+    tx: T,
+    sequence: u64,
+}
+
+impl CsvLockEvent {
+    /// Replaces the internal IDs with the external IDs they are mapped to
+    pub fn with_external_ids(self, ids: &ExternalIds) -> CsvLockEvent<String, String> {
+        CsvLockEvent {
+            client: ids.client_label(self.client),
+            reason: self.reason,
+            tx: ids.transaction_label(self.tx),
+            sequence: self.sequence,
+        }
+    }
+}
+
+/// Why a transaction has not been applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rejection {
+    /// The client's account is locked
+    AccountLocked,
+    /// Deposits and withdrawals of negative amounts are not processed
+    NegativeAmount,
+    /// The available funds do not cover the withdrawal
+    InsufficientFunds,
+    /// The client has no account
+    UnknownClient,
+    /// The referenced deposit does not exist
+    UnknownTransaction,
+    /// The referenced deposit is already disputed
+    AlreadyDisputed,
+    /// The referenced deposit is not disputed
+    NotDisputed,
+    /// The referenced transaction is not held for review
+    NotHeld,
+    /// A transaction with the same ID is already held for review
+    AlreadyHeld,
+    /// The transaction has been rejected by the fraud scorer
+    Fraud,
+    /// The storage backend failed to read or write the account
+    StorageFailure,
+    /// The referenced deposit has been removed by the retention policy
+    Pruned,
+}
+
+impl Rejection {
+    /// Returns a short snake case label of the rejection reason
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::AccountLocked => "account_locked",
+            Rejection::NegativeAmount => "negative_amount",
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::UnknownClient => "unknown_client",
+            Rejection::UnknownTransaction => "unknown_transaction",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::NotHeld => "not_held",
+            Rejection::AlreadyHeld => "already_held",
+            Rejection::Fraud => "fraud",
+            Rejection::StorageFailure => "storage_failure",
+            Rejection::Pruned => "pruned",
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The result of applying a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The transaction has been applied
+    Applied,
+    /// The transaction has been held for review by the fraud scorer
+    Held,
+    /// The transaction has not been applied
+    Rejected(Rejection),
+}
+
+impl From<Result<(), Rejection>> for UpdateOutcome {
+    fn from(result: Result<(), Rejection>) -> Self {
+        match result {
+            Ok(()) => UpdateOutcome::Applied,
+            Err(rejection) => UpdateOutcome::Rejected(rejection),
+        }
+    }
+}
+
+/// Why an account has been locked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockReason {
+    /// A disputed deposit has been charged back
+    ChargeBack,
+    /// Too many disputes have been opened within a window of transactions
+    TooManyDisputes,
+    /// The disputed amount exceeded the allowed share of the lifetime deposits
+    DisputedAmount,
+}
+
+/// Records why and when an account has been locked. The inputs carry no timestamps, so the
+/// time of a lock is given by the sequence number of the triggering transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AccountLock {
+    /// Why the account has been locked
+    pub reason: LockReason,
+    /// The transaction which triggered the lock
+    pub tx: TransactionId,
+    /// Sequence number of the triggering transaction in the storage's input
+    pub sequence: u64,
+}
+
+impl AccountLock {
+    fn to_csv(self, client: ClientId) -> CsvLockEvent {
+        CsvLockEvent {
+            client,
+            reason: self.reason,
+            tx: self.tx,
+            sequence: self.sequence,
+        }
+    }
+}
+
+/// Lock when more than `max_disputes` disputes are opened within `window` transactions
+/// of a client
+#[derive(Debug, Clone, Copy)]
+pub struct DisputeLimit {
+    /// Maximum number of disputes allowed within the window
+    pub max_disputes: usize,
+    /// Number of most recent transactions of the client (including the dispute itself)
+    pub window: u64,
+}
+
+/// Thresholds which lock an account on top of a charge-back
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockPolicy {
+    /// Lock when too many disputes are opened within a window of transactions
+    pub dispute_limit: Option<DisputeLimit>,
+    /// Lock when the lifetime disputed amount exceeds this fraction of the lifetime deposits
+    pub max_disputed_ratio: Option<Amount>,
+}
+
+/// How old a deposit may get before the retention policy removes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionAge {
+    /// Deposits followed by more than this many transactions of any client
+    Transactions(u64),
+    /// Deposits followed by more than this many transactions of their client
+    ClientTransactions(u64),
+}
+
+impl std::str::FromStr for RetentionAge {
+    type Err = String;
+    fn from_str(age: &str) -> Result<Self, Self::Err> {
+        let (unit, value) = age
+            .split_once(':')
+            .ok_or_else(|| format!("Expected unit:value: {}", age))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid retention age: {}", age))?;
+        match unit.trim().to_ascii_lowercase().as_str() {
+            "transactions" => Ok(RetentionAge::Transactions(value)),
+            "client-transactions" => Ok(RetentionAge::ClientTransactions(value)),
+            _ => Err(format!("Unknown retention age unit: {}", unit)),
+        }
+    }
+}
+
+/// Removes deposits from the deposit history once they are too old to be disputed
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// Deposits older than this are removed unless they are disputed
+    pub max_age: RetentionAge,
+    /// Number of transactions between two prunings of the deposit history
+    pub interval: u64,
+}
+
+/// A consistency rule which every account has to satisfy
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Invariant {
+    /// The output total equals the output available plus held funds
+    Total,
+    /// The held funds are not negative
+    NonNegativeHeld,
+    /// The held funds equal the sum of the currently disputed deposits
+    HeldDisputed,
+}
+
+impl Invariant {
+    /// Returns a short snake case label of the invariant
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Invariant::Total => "total",
+            Invariant::NonNegativeHeld => "non_negative_held",
+            Invariant::HeldDisputed => "held_disputed",
+        }
+    }
+}
+
+impl std::fmt::Display for Invariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An invariant which an account violates
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    /// Client of the account
+    pub client: ClientId,
+    /// The violated invariant
+    pub invariant: Invariant,
+    /// Value the invariant requires
+    pub expected: f64,
+    /// Value of the account
+    pub actual: f64,
+    /// Sequence number of the last transaction before the violation has been found
+    pub sequence: u64,
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "account {} violates {} after transaction {}: expected {}, found {}",
+            self.client, self.invariant, self.sequence, self.expected, self.actual
+        )
+    }
+}
+
+/// When a ClientInfoStorage checks the invariants of its accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantCheck {
+    /// Check the account of every transaction after its update
+    EveryUpdate,
+    /// Check all the accounts every this many transactions
+    Interval(u64),
+}
+
+impl std::str::FromStr for InvariantCheck {
+    type Err = String;
+    fn from_str(check: &str) -> Result<Self, Self::Err> {
+        match check.trim() {
+            "update" => Ok(InvariantCheck::EveryUpdate),
+            interval => match interval.parse() {
+                Ok(interval) if interval > 0 => Ok(InvariantCheck::Interval(interval)),
+                _ => Err(format!(
+                    "Expected `update` or a number of transactions: {}",
+                    check
+                )),
+            },
+        }
+    }
+}
+
+/// Absolute tolerance of the invariants: one unit of the output precision, as the rounded
+/// available and held funds may not add up to the rounded total, plus the f32 error
+const INVARIANT_TOLERANCE: f64 = 0.00015;
+
+/// Returns true if two amounts are equal up to the output precision, or up to the precision
+/// of f32 amounts of the given magnitude, which accumulate rounding errors
+fn amounts_agree(expected: f64, actual: f64, magnitude: f64) -> bool {
+    (expected - actual).abs() <= INVARIANT_TOLERANCE.max(magnitude.abs() * 1e-6)
+}
+
+/// Checks the invariants of an account given the sum of its disputed deposits
+fn account_violations(
+    client: ClientId,
+    account: &Account,
+    disputed_sum: f64,
+    sequence: u64,
+) -> Vec<InvariantViolation> {
+    let violation = |invariant, expected, actual| InvariantViolation {
+        client,
+        invariant,
+        expected,
+        actual,
+        sequence,
+    };
+    let mut violations = Vec::new();
+    let output = csv_account(client, account);
+    let output_sum = f64::from(output.available) + f64::from(output.held);
+    if !amounts_agree(output_sum, f64::from(output.total), output_sum) {
+        violations.push(violation(
+            Invariant::Total,
+            output_sum,
+            f64::from(output.total),
+        ));
+    }
+    // Every amount ever held has been disputed, which bounds the rounding errors of `held`
+    let magnitude = f64::from(account.disputed);
+    let held = f64::from(account.held);
+    if held < 0.0 && !amounts_agree(0.0, held, magnitude) {
+        violations.push(violation(Invariant::NonNegativeHeld, 0.0, held));
+    }
+    if !amounts_agree(disputed_sum, held, magnitude) {
+        violations.push(violation(Invariant::HeldDisputed, disputed_sum, held));
+    }
+    violations
+}
+
+impl LockPolicy {
+    fn check(&self, account: &Account) -> Option<LockReason> {
+        if let Some(limit) = self.dispute_limit {
+            if account.recent_disputes.len() > limit.max_disputes {
+                return Some(LockReason::TooManyDisputes);
+            }
+        }
+        if let Some(ratio) = self.max_disputed_ratio {
+            if account.disputed > account.deposited * ratio {
+                return Some(LockReason::DisputedAmount);
+            }
+        }
+        None
+    }
+}
+
+/// The state of an account as kept by a StorageBackend. Serializable, so that backends can
+/// persist it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+    available: Amount,
+    held: Amount,
+    lock: Option<AccountLock>,
+    lock_history: Vec<AccountLock>,
+    /// Sum of all deposits ever made to the account
+    deposited: Amount,
+    /// Sum of all disputes ever opened on the account
+    disputed: Amount,
+    /// Number of transactions referring to the account
+    transactions: u64,
+    /// Positions (in the account's transactions) of the disputes within the dispute window
+    recent_disputes: VecDeque<u64>,
 }
 
 impl Default for Account {
@@ -26,7 +461,12 @@ impl Default for Account {
         Self {
             available: 0.0,
             held: 0.0,
-            locked: false,
+            lock: None,
+            lock_history: Vec::new(),
+            deposited: 0.0,
+            disputed: 0.0,
+            transactions: 0,
+            recent_disputes: VecDeque::new(),
         }
     }
 }
@@ -35,33 +475,79 @@ fn round_to_4_dec(amount: Amount) -> Amount {
     (amount * 10000.0).round() / 10000.0
 }
 
+fn csv_account(client: ClientId, account: &Account) -> CsvAccount {
+    CsvAccount::from_balances(client, account.available, account.held, account.is_locked())
+}
+
 impl Account {
-    fn deposit(&mut self, amount: Amount) -> Self {
+    fn deposit(&mut self, amount: Amount) -> Result<(), Rejection> {
         if amount >= 0.0 {
             self.available += amount;
+            self.deposited += amount;
+            Ok(())
         } else {
-            log::error!("Do not process negative amounts");
+            log::debug!("Do not process negative amounts");
+            Err(Rejection::NegativeAmount)
         }
-        *self
     }
 
-    fn withdraw(&mut self, amount: Amount) {
+    /// Returns the available funds of the account
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Returns the held funds of the account
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Returns true if the account is locked
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn lock(&mut self, reason: LockReason, tx: TransactionId, sequence: u64) {
+        log::warn!("Account locked by transaction {}: {:?}", tx, reason);
+        let lock = AccountLock {
+            reason,
+            tx,
+            sequence,
+        };
+        self.lock = Some(lock);
+        self.lock_history.push(lock);
+    }
+
+    fn withdraw(&mut self, amount: Amount) -> Result<(), Rejection> {
         if amount >= 0.0 {
             let possible_available = self.available - amount;
             if possible_available >= 0.0 {
                 self.available = possible_available;
+                Ok(())
             } else {
-                log::error!("Not enough funds");
+                log::debug!("Not enough funds");
+                Err(Rejection::InsufficientFunds)
             }
         } else {
-            log::error!("Do not process negative amounts");
+            log::debug!("Do not process negative amounts");
+            Err(Rejection::NegativeAmount)
         }
     }
 
-    fn dispute(&mut self, amount: Amount) {
+    fn dispute(&mut self, amount: Amount, dispute_window: Option<u64>) {
         // Amount should always be >= 0 here
         self.available -= amount;
         self.held += amount;
+        self.disputed += amount;
+        if let Some(window) = dispute_window {
+            // Forget the disputes which fell out of the window
+            while let Some(&position) = self.recent_disputes.front() {
+                if self.transactions - position < window {
+                    break;
+                }
+                self.recent_disputes.pop_front();
+            }
+            self.recent_disputes.push_back(self.transactions);
+        }
     }
 
     fn resolve(&mut self, amount: Amount) {
@@ -73,18 +559,83 @@ impl Account {
     fn charge_back(&mut self, amount: Amount) {
         // Amount should always be >= 0 here
         self.held -= amount;
-        self.locked = true;
     }
 }
 
-struct DepositLog {
+/// A deposit removed by the retention policy as written to the archive CSV
+#[derive(Serialize)]
+struct CsvArchivedDeposit {
+    client: ClientId,
+    tx: TransactionId,
     amount: Amount,
-    disputed: bool,
+    sequence: u64,
+    position: u64,
+}
+
+/// A deposit as stored by the ClientInfoStorage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionRecord {
+    /// Client of the deposit
+    pub client: ClientId,
+    /// ID of the deposit
+    pub tx: TransactionId,
+    /// Deposited amount
+    pub amount: Amount,
+    /// True while the deposit is disputed
+    pub disputed: bool,
+}
+
+/// Totals over all the accounts of a ClientInfoStorage, kept up to date on every update
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StorageGauges {
+    /// Number of clients
+    pub clients: u64,
+    /// Number of deposits which are disputed and have been neither resolved nor charged back
+    pub open_disputes: u64,
+    /// Number of locked accounts
+    pub locked_accounts: u64,
+    /// Sum of the held funds of all the accounts
+    pub held_funds: f64,
+}
+
+/// An update of a ClientInfoStorage as passed to its UpdateHooks
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateEvent {
+    /// Type of the transaction
+    pub transaction_type: TransactionType,
+    /// Client of the transaction
+    pub client: ClientId,
+    /// Whether the transaction has been applied
+    pub outcome: UpdateOutcome,
+    /// Time spent applying the transaction
+    pub latency: Duration,
+    /// Totals over all the accounts after the update
+    pub gauges: StorageGauges,
+}
+
+/// Extension point which is called by the ClientInfoStorage after every update
+pub trait UpdateHook: Send {
+    /// Called after a transaction has been applied or rejected
+    fn updated(&mut self, event: &UpdateEvent);
+
+    /// Called after the retention policy has removed deposits from the deposit history
+    fn pruned(&mut self, _deposits: &[RemovedDeposit]) {}
 }
 
-/// Stores the current state of available clients, their accounts and their deposits
+/// Stores the current state of available clients, their accounts and their deposits in a
+/// StorageBackend
 pub struct ClientInfoStorage {
-    client_info: HashMap<ClientId, (Account, HashMap<TransactionId, DepositLog>)>,
+    backend: Box<dyn StorageBackend>,
+    lock_policy: LockPolicy,
+    sequence: u64,
+    gauges: StorageGauges,
+    hooks: Vec<Box<dyn UpdateHook>>,
+    retention: Option<RetentionPolicy>,
+    archive: Option<csv::Writer<Box<dyn Write + Send>>>,
+    invariant_check: Option<InvariantCheck>,
+    /// Invariants which the accounts currently violate
+    violated: HashSet<(ClientId, Invariant)>,
+    violations: Vec<InvariantViolation>,
 }
 
 // clippy suggestion
@@ -94,144 +645,599 @@ impl Default for ClientInfoStorage {
     }
 }
 
+/// Logs a failed read of the storage backend and falls back to the default value
+fn logged<T: Default>(result: std::io::Result<T>) -> T {
+    result.unwrap_or_else(|error| {
+        log::error!("Storage read error: {}", error);
+        T::default()
+    })
+}
+
 impl ClientInfoStorage {
-    /// Creates a new ClientInfoStorage
+    /// Creates a new ClientInfoStorage which keeps its accounts in memory
     pub fn new() -> Self {
+        Self::with_lock_policy(LockPolicy::default())
+    }
+
+    /// Creates a new in-memory ClientInfoStorage which locks accounts according to the given
+    /// policy
+    pub fn with_lock_policy(lock_policy: LockPolicy) -> Self {
+        Self::with_backend(Box::new(MemoryStorage::new()), lock_policy)
+    }
+
+    /// Creates a ClientInfoStorage on top of the given backend which locks accounts according
+    /// to the given policy. The backend may already hold accounts
+    pub fn with_backend(backend: Box<dyn StorageBackend>, lock_policy: LockPolicy) -> Self {
+        let mut gauges = StorageGauges::default();
+        for (_, account) in logged(backend.accounts()) {
+            gauges.clients += 1;
+            gauges.held_funds += f64::from(account.held);
+            if account.is_locked() {
+                gauges.locked_accounts += 1;
+            }
+        }
+        gauges.open_disputes = logged(backend.open_disputes()) as u64;
+        let sequence = logged(backend.last_sequence());
         Self {
-            client_info: HashMap::new(),
+            backend,
+            lock_policy,
+            sequence,
+            gauges,
+            hooks: Vec::new(),
+            retention: None,
+            archive: None,
+            invariant_check: None,
+            violated: HashSet::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    /// Checks the invariants of the accounts during the updates. Violations are logged and
+    /// kept, see `invariant_violations`
+    pub fn set_invariant_check(&mut self, check: InvariantCheck) {
+        self.invariant_check = Some(check);
+    }
+
+    /// Checks the invariants of all the accounts now and returns the number of new violations
+    pub fn check_invariants(&mut self) -> usize {
+        let clients: Vec<ClientId> = logged(self.backend.accounts())
+            .into_iter()
+            .map(|(client, _)| client)
+            .collect();
+        clients
+            .into_iter()
+            .map(|client| self.check_account_invariants(client))
+            .sum()
+    }
+
+    /// Returns the invariant violations found so far, in the order they have been found. An
+    /// invariant which stays violated is reported once
+    pub fn invariant_violations(&self) -> &[InvariantViolation] {
+        &self.violations
+    }
+
+    /// Checks the invariants of the account of the given client and returns the number of new
+    /// violations
+    fn check_account_invariants(&mut self, client: ClientId) -> usize {
+        let found = self.backend.account(client).and_then(|account| {
+            let disputed_sum = self.backend.disputed_amount(client)?;
+            Ok(account.map_or_else(Vec::new, |account| {
+                account_violations(client, &account, disputed_sum, self.sequence)
+            }))
+        });
+        let found = match found {
+            Ok(found) => found,
+            Err(error) => {
+                log::error!("Storage read error: {}", error);
+                return 0;
+            }
+        };
+        // Invariants which hold again are reported anew when they are violated later
+        self.violated.retain(|(violated_client, invariant)| {
+            *violated_client != client
+                || found
+                    .iter()
+                    .any(|violation| violation.invariant == *invariant)
+        });
+        let mut new = 0;
+        for violation in found {
+            if self.violated.insert((client, violation.invariant)) {
+                log::error!("Invariant violated: {}", violation);
+                self.violations.push(violation);
+                new += 1;
+            }
+        }
+        new
+    }
+
+    /// Prunes the deposit history according to the given policy. Pruned deposits are written
+    /// as CSV to the archive, if any, or dropped
+    pub fn set_retention(
+        &mut self,
+        policy: RetentionPolicy,
+        archive: Option<Box<dyn Write + Send>>,
+    ) {
+        self.retention = Some(policy);
+        self.archive = archive.map(csv::Writer::from_writer);
+    }
+
+    /// Removes the deposits which are not disputed and older than the retention policy allows.
+    /// Returns the number of removed deposits. Disputes of removed deposits are rejected
+    pub fn prune(&mut self) -> usize {
+        let max_age = match self.retention {
+            Some(policy) => policy.max_age,
+            None => return 0,
+        };
+        let mut removed = match self.remove_expired(max_age) {
+            Ok(removed) => removed,
+            Err(error) => {
+                log::error!("Pruning error: {}", error);
+                if let Err(error) = self.backend.rollback() {
+                    log::error!("Storage rollback error: {}", error);
+                }
+                return 0;
+            }
+        };
+        removed.sort_unstable_by_key(|(_, _, deposit)| deposit.sequence);
+        for (client, tx, deposit) in &removed {
+            if let Some(archive) = &mut self.archive {
+                let record = CsvArchivedDeposit {
+                    client: *client,
+                    tx: *tx,
+                    amount: deposit.amount,
+                    sequence: deposit.sequence,
+                    position: deposit.position,
+                };
+                if let Err(error) = archive.serialize(record) {
+                    log::error!("Archive error: {}", error);
+                }
+            }
+        }
+        if let Some(archive) = &mut self.archive {
+            let _ = archive.flush();
+        }
+        for hook in &mut self.hooks {
+            hook.pruned(&removed);
+        }
+        log::info!("Pruned {} deposits", removed.len());
+        removed.len()
+    }
+
+    fn remove_expired(&mut self, max_age: RetentionAge) -> std::io::Result<Vec<RemovedDeposit>> {
+        let expiry = match max_age {
+            RetentionAge::Transactions(max) => {
+                DepositExpiry::Sequence(self.sequence.saturating_sub(max))
+            }
+            // Only the clients with more transactions than the age can have expired deposits
+            RetentionAge::ClientTransactions(max) => DepositExpiry::Position(
+                self.backend
+                    .accounts()?
+                    .into_iter()
+                    .filter(|(_, account)| account.transactions > max + 1)
+                    .map(|(client, account)| (client, account.transactions - max))
+                    .collect(),
+            ),
+        };
+        self.backend.begin()?;
+        let removed = self.backend.remove_deposits(&expiry)?;
+        self.backend.commit()?;
+        Ok(removed)
+    }
+
+    /// Why a transaction refers to a deposit which is not stored
+    fn missing_deposit(&self, client: ClientId, tx: TransactionId) -> std::io::Result<Rejection> {
+        Ok(if self.backend.is_pruned(client, tx)? {
+            Rejection::Pruned
+        } else {
+            Rejection::UnknownTransaction
+        })
+    }
+
+    /// Adds a hook which is called after every update
+    pub fn add_hook(&mut self, hook: Box<dyn UpdateHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Makes the updates applied so far durable in the storage backend
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.backend.flush()
+    }
+
+    /// Returns the totals over all the accounts
+    pub fn gauges(&self) -> StorageGauges {
+        self.gauges
+    }
+
+    /// Returns the held funds and the lock state of the account of the given client
+    fn account_state(&self, client: ClientId) -> std::io::Result<Option<(Amount, bool)>> {
+        Ok(self
+            .backend
+            .account(client)?
+            .map(|account| (account.held, account.is_locked())))
+    }
+
+    /// Returns why and when the account of the given client has been locked
+    pub fn account_lock(&self, client: ClientId) -> std::io::Result<Option<AccountLock>> {
+        Ok(self
+            .backend
+            .account(client)?
+            .and_then(|account| account.lock))
+    }
+
+    /// Returns the number of stored clients
+    pub fn client_count(&self) -> usize {
+        self.gauges.clients as usize
+    }
+
+    /// Returns the number of deposits which are disputed and have been neither resolved nor
+    /// charged back
+    pub fn open_disputes(&self) -> usize {
+        self.gauges.open_disputes as usize
+    }
+
+    /// Returns the number of locked accounts
+    pub fn locked_accounts(&self) -> usize {
+        self.gauges.locked_accounts as usize
+    }
+
+    /// Returns the locks of the account of the given client, oldest first. Locked accounts
+    /// are never unlocked, so the history holds lock events only
+    pub fn lock_history(&self, client: ClientId) -> std::io::Result<Vec<AccountLock>> {
+        Ok(self
+            .backend
+            .account(client)?
+            .map_or_else(Vec::new, |account| account.lock_history))
+    }
+
+    /// Updates the AccountStorage based on the input Transaction. The transaction is rejected
+    /// if the storage backend fails
+    pub fn update(&mut self, transaction: Transaction) -> UpdateOutcome {
+        let started = Instant::now();
+        let (transaction_type, client) = (transaction.transaction_type(), transaction.client());
+        let result = self.backend.begin().and_then(|()| {
+            let before = self.account_state(client)?;
+            let outcome = self.apply(transaction)?;
+            let after = self.account_state(client)?;
+            self.backend.commit()?;
+            Ok((before, outcome, after))
+        });
+        let latency = started.elapsed();
+
+        let outcome = match result {
+            Ok((before, outcome, after)) => {
+                self.update_gauges(transaction_type, before, outcome, after);
+                outcome
+            }
+            Err(error) => {
+                log::error!("Storage error: {}", error);
+                if let Err(error) = self.backend.rollback() {
+                    log::error!("Storage rollback error: {}", error);
+                }
+                UpdateOutcome::Rejected(Rejection::StorageFailure)
+            }
+        };
+        if let Some(policy) = self.retention {
+            if self.sequence.is_multiple_of(policy.interval.max(1)) {
+                self.prune();
+            }
+        }
+        match self.invariant_check {
+            Some(InvariantCheck::EveryUpdate) => {
+                self.check_account_invariants(client);
+            }
+            Some(InvariantCheck::Interval(interval)) if self.sequence.is_multiple_of(interval) => {
+                self.check_invariants();
+            }
+            _ => {}
+        }
+
+        let event = UpdateEvent {
+            transaction_type,
+            client,
+            outcome,
+            latency,
+            gauges: self.gauges,
+        };
+        for hook in &mut self.hooks {
+            hook.updated(&event);
         }
+        outcome
     }
 
-    /// Updates the AccountStorage based on the input Transaction
-    pub fn update(&mut self, transaction: Transaction) {
+    /// Keeps the totals up to date from the changes of the single affected account
+    fn update_gauges(
+        &mut self,
+        transaction_type: TransactionType,
+        before: Option<(Amount, bool)>,
+        outcome: UpdateOutcome,
+        after: Option<(Amount, bool)>,
+    ) {
+        let (held_before, locked_before) = before.unwrap_or((0.0, false));
+        if let Some((held_after, locked_after)) = after {
+            if before.is_none() {
+                self.gauges.clients += 1;
+            }
+            self.gauges.held_funds += f64::from(held_after) - f64::from(held_before);
+            match (locked_before, locked_after) {
+                (false, true) => self.gauges.locked_accounts += 1,
+                (true, false) => self.gauges.locked_accounts -= 1,
+                _ => {}
+            }
+        }
+        if outcome == UpdateOutcome::Applied {
+            match transaction_type {
+                TransactionType::Dispute => self.gauges.open_disputes += 1,
+                TransactionType::Resolve | TransactionType::ChargeBack => {
+                    self.gauges.open_disputes -= 1
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn apply(&mut self, transaction: Transaction) -> std::io::Result<UpdateOutcome> {
         use Transaction::*;
-        match transaction {
+        use UpdateOutcome::{Applied, Rejected};
+        self.sequence += 1;
+        let sequence = self.sequence;
+        self.backend.put_sequence(sequence)?;
+        let client = transaction.client();
+        let mut account = self.backend.account(client)?;
+        if let Some(account) = account.as_mut() {
+            account.transactions += 1;
+        }
+        let outcome = match transaction {
             Deposit(info) => {
-                if let Some(client_info) = self.client_info.get_mut(&info.client) {
-                    if client_info.0.locked {
-                        log::warn!("Client's account  ({}) is locked", info.client);
+                if let Some(account) = account.as_mut() {
+                    if account.is_locked() {
+                        log::debug!("Client's account  ({}) is locked", info.client);
+                        Rejected(Rejection::AccountLocked)
                     } else {
-                        // Insert a new deposit to the deposit history of the specific client
-                        client_info.1.insert(
+                        // Deposit the amount to the account
+                        let outcome = account.deposit(info.amount).into();
+                        // Insert an accepted deposit to the deposit history of the specific
+                        // client, so that rejected deposits cannot be disputed
+                        if outcome == Applied {
+                            self.backend.put_deposit(
+                                info.client,
+                                info.tx,
+                                &DepositLog {
+                                    amount: info.amount,
+                                    disputed: false,
+                                    sequence,
+                                    position: account.transactions,
+                                },
+                            )?;
+                        }
+                        outcome
+                    }
+                } else {
+                    // Introduce a new client with an account which includes this first deposit
+                    // and insert an accepted deposit to the client's deposit history
+                    let mut new_account = Account {
+                        transactions: 1,
+                        ..Account::default()
+                    };
+                    let outcome = new_account.deposit(info.amount).into();
+                    self.backend.put_account(info.client, &new_account)?;
+                    if outcome == Applied {
+                        self.backend.put_deposit(
+                            info.client,
                             info.tx,
-                            DepositLog {
+                            &DepositLog {
                                 amount: info.amount,
                                 disputed: false,
+                                sequence,
+                                position: 1,
                             },
-                        );
-                        // Deposit the amount to the account
-                        client_info.0.deposit(info.amount);
+                        )?;
                     }
-                } else {
-                    let mut new_entry = HashMap::new();
-                    new_entry.insert(
-                        info.tx,
-                        DepositLog {
-                            amount: info.amount,
-                            disputed: false,
-                        },
-                    );
-                    // Introduce a new client with an account which includes this first deposit
-                    // and insert the deposit to the client's deposit history
-                    self.client_info.insert(
-                        info.client,
-                        (Account::default().deposit(info.amount), new_entry),
-                    );
+                    outcome
                 }
             }
             Withdrawal(info) => {
-                if let Some(client_info) = self.client_info.get_mut(&info.client) {
-                    if client_info.0.locked {
-                        log::warn!("Client's account  ({}) is locked", info.client);
+                if let Some(account) = account.as_mut() {
+                    if account.is_locked() {
+                        log::debug!("Client's account  ({}) is locked", info.client);
+                        Rejected(Rejection::AccountLocked)
                     } else {
                         // Withdraw the amount form the client's account
-                        client_info.0.withdraw(info.amount);
+                        account.withdraw(info.amount).into()
                     }
                 } else {
-                    log::error!("Withdraw transaction for unavailable client ID");
+                    log::debug!("Withdraw transaction for unavailable client ID");
+                    Rejected(Rejection::UnknownClient)
                 }
             }
             Dispute(info) => {
-                if let Some(client_info) = self.client_info.get_mut(&info.client) {
-                    if client_info.0.locked {
-                        log::warn!("Client's account  ({}) is locked", info.client);
-                    } else if let Some(deposit) = client_info.1.get_mut(&info.tx) {
+                if let Some(account) = account.as_mut() {
+                    if account.is_locked() {
+                        log::debug!("Client's account  ({}) is locked", info.client);
+                        Rejected(Rejection::AccountLocked)
+                    } else if let Some(mut deposit) = self.backend.deposit(info.client, info.tx)? {
                         if !deposit.disputed {
                             // Set the specific deposit as disputed
                             deposit.disputed = true;
+                            self.backend.put_deposit(info.client, info.tx, &deposit)?;
                             // Dispute the specific amount from the client's account
-                            client_info.0.dispute(deposit.amount);
+                            let dispute_window =
+                                self.lock_policy.dispute_limit.map(|limit| limit.window);
+                            account.dispute(deposit.amount, dispute_window);
+                            if let Some(reason) = self.lock_policy.check(account) {
+                                account.lock(reason, info.tx, sequence);
+                            }
+                            Applied
                         } else {
-                            log::error!("Dispute error: deposit already disputed")
+                            log::debug!("Dispute error: deposit already disputed");
+                            Rejected(Rejection::AlreadyDisputed)
                         }
                     } else {
-                        log::error!("Dispute error: Not available deposit to be disputed");
+                        log::debug!("Dispute error: Not available deposit to be disputed");
+                        Rejected(self.missing_deposit(info.client, info.tx)?)
                     }
                 } else {
-                    log::error!("Dispute error: Not available client for resolved transaction");
+                    log::debug!("Dispute error: Not available client for resolved transaction");
+                    Rejected(Rejection::UnknownClient)
                 }
             }
             Resolve(info) => {
-                if let Some(client_info) = self.client_info.get_mut(&info.client) {
-                    if client_info.0.locked {
-                        log::warn!("Client's account  ({}) is locked", info.client);
-                    } else if let Some(deposit) = client_info.1.get_mut(&info.tx) {
+                if let Some(account) = account.as_mut() {
+                    if account.is_locked() {
+                        log::debug!("Client's account  ({}) is locked", info.client);
+                        Rejected(Rejection::AccountLocked)
+                    } else if let Some(mut deposit) = self.backend.deposit(info.client, info.tx)? {
                         if !deposit.disputed {
-                            log::error!("Resolve error: Deposit has not been disputed");
+                            log::debug!("Resolve error: Deposit has not been disputed");
+                            Rejected(Rejection::NotDisputed)
                         } else {
                             // Set the specific deposit as not-disputed
                             deposit.disputed = false;
+                            self.backend.put_deposit(info.client, info.tx, &deposit)?;
                             // Resolve the specific amount from the client's account
-                            client_info.0.resolve(deposit.amount);
+                            account.resolve(deposit.amount);
+                            Applied
                         }
                     } else {
-                        log::error!("Resolve error: Not available disputed deposit to be resolved");
+                        log::debug!("Resolve error: Not available disputed deposit to be resolved");
+                        Rejected(self.missing_deposit(info.client, info.tx)?)
                     }
                 } else {
-                    log::error!("Resolve error: Not available client for resolved transaction");
+                    log::debug!("Resolve error: Not available client for resolved transaction");
+                    Rejected(Rejection::UnknownClient)
                 }
             }
             ChargeBack(info) => {
-                if let Some(client_info) = self.client_info.get_mut(&info.client) {
-                    if client_info.0.locked {
-                        log::warn!("Client's account  ({}) is locked", info.client);
-                    } else if let Some(deposit) = client_info.1.get_mut(&info.tx) {
+                if let Some(account) = account.as_mut() {
+                    if account.is_locked() {
+                        log::debug!("Client's account  ({}) is locked", info.client);
+                        Rejected(Rejection::AccountLocked)
+                    } else if let Some(mut deposit) = self.backend.deposit(info.client, info.tx)? {
                         if !deposit.disputed {
-                            log::error!("ChargeBack error: Deposit has not been disputed");
+                            log::debug!("ChargeBack error: Deposit has not been disputed");
+                            Rejected(Rejection::NotDisputed)
                         } else {
                             // Set the specific deposit as not-disputed (maybe this is not even needed)
                             deposit.disputed = false;
+                            self.backend.put_deposit(info.client, info.tx, &deposit)?;
                             // Charge back the specific amount from the client's account
-                            client_info.0.charge_back(deposit.amount);
+                            account.charge_back(deposit.amount);
+                            account.lock(LockReason::ChargeBack, info.tx, sequence);
+                            Applied
                         }
                     } else {
-                        log::error!(
+                        log::debug!(
                             "ChargeBack error: Not available disputed deposit to be charge back"
                         );
+                        Rejected(self.missing_deposit(info.client, info.tx)?)
                     }
                 } else {
-                    log::error!(
+                    log::debug!(
                         "ChargeBack error: Not available client for charge-back transaction"
                     );
+                    Rejected(Rejection::UnknownClient)
                 }
             }
+            Release(_) | Deny(_) => {
+                // Admin transactions only act on the payment engine's review queue
+                log::debug!("Admin transaction cannot be applied to a client's account");
+                Rejected(Rejection::NotHeld)
+            }
+        };
+        // Write back the changes of an existing account, including its transaction count
+        if let Some(account) = &account {
+            self.backend.put_account(client, account)?;
         }
+        Ok(outcome)
     }
 
-    /// Returns the stored accounts in a CSV format
+    /// Returns the account of the given client
+    pub fn account(&self, client: ClientId) -> std::io::Result<Option<CsvAccount>> {
+        Ok(self
+            .backend
+            .account(client)?
+            .map(|account| csv_account(client, &account)))
+    }
+
+    /// Returns an iterator over the stored accounts in no particular order
+    pub fn accounts(&self) -> std::io::Result<impl Iterator<Item = CsvAccount>> {
+        Ok(self
+            .backend
+            .accounts()?
+            .into_iter()
+            .map(|(client, account)| csv_account(client, &account)))
+    }
+
+    /// Returns the stored deposit with the given transaction ID of the given client.
+    /// Transaction IDs are only unique per client, like for disputes
+    pub fn transaction(
+        &self,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> std::io::Result<Option<TransactionRecord>> {
+        Ok(self
+            .backend
+            .deposit(client, tx)?
+            .map(|deposit| TransactionRecord {
+                client,
+                tx,
+                amount: deposit.amount,
+                disputed: deposit.disputed,
+            }))
+    }
+
+    /// Returns the stored accounts in a CSV format, sorted by client ID. A failed read of the
+    /// storage backend is logged and returns no accounts, see `try_csv_format_accounts`
     pub fn get_csv_format_accounts(&self) -> Vec<CsvAccount> {
-        let records = self
-            .client_info
-            .iter()
-            .map(|(client, client_info)| CsvAccount {
-                client: *client,
-                available: round_to_4_dec(client_info.0.available),
-                held: round_to_4_dec(client_info.0.held),
-                total: round_to_4_dec(client_info.0.available + client_info.0.held),
-                locked: client_info.0.locked,
+        logged(self.try_csv_format_accounts())
+    }
+
+    /// Returns the stored accounts in a CSV format, sorted by client ID
+    pub fn try_csv_format_accounts(&self) -> std::io::Result<Vec<CsvAccount>> {
+        let mut records: Vec<CsvAccount> = self.accounts()?.collect();
+        records.sort_by_key(|record| record.client);
+        Ok(records)
+    }
+
+    /// Returns the stored accounts along with their lock records in a CSV format, sorted by
+    /// client ID
+    pub fn get_csv_format_account_details(&self) -> std::io::Result<Vec<CsvAccountDetails>> {
+        let mut records: Vec<CsvAccountDetails> = self
+            .backend
+            .accounts()?
+            .into_iter()
+            .map(|(client, account)| CsvAccountDetails {
+                client,
+                available: round_to_4_dec(account.available),
+                held: round_to_4_dec(account.held),
+                total: round_to_4_dec(account.available + account.held),
+                locked: account.is_locked(),
+                lock_reason: account.lock.map(|lock| lock.reason),
+                lock_tx: account.lock.map(|lock| lock.tx),
+                lock_sequence: account.lock.map(|lock| lock.sequence),
+                lock_events: account.lock_history.len(),
             })
             .collect();
-        records
+        records.sort_by_key(|record| record.client);
+        Ok(records)
+    }
+
+    /// Returns the locks of all stored accounts in a CSV format, sorted by
+    /// client ID and sequence number
+    pub fn get_csv_format_lock_history(&self) -> std::io::Result<Vec<CsvLockEvent>> {
+        let mut records: Vec<CsvLockEvent> = self
+            .backend
+            .accounts()?
+            .into_iter()
+            .flat_map(|(client, account)| {
+                account
+                    .lock_history
+                    .into_iter()
+                    .map(move |lock| lock.to_csv(client))
+            })
+            .collect();
+        // Events of a client are already ordered by sequence number
+        records.sort_by_key(|record| record.client);
+        Ok(records)
     }
 }
 
@@ -524,6 +1530,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_client_info_not_registered_client() {
         let mut client_storage = ClientInfoStorage::new();
         // Test Withdraw
@@ -641,12 +1648,404 @@ mod tests {
             total: 0.5,
             locked: true,
         };
-        if records[1].client == 1 {
-            assert_eq!(records[1], expected_records_1);
-            assert_eq!(records[0], expected_records_2);
-        } else {
-            assert_eq!(records[0], expected_records_1);
-            assert_eq!(records[1], expected_records_2);
+        assert_eq!(records[0], expected_records_1);
+        assert_eq!(records[1], expected_records_2);
+    }
+
+    #[test]
+    fn test_lock_policy_too_many_disputes() {
+        let mut client_storage = ClientInfoStorage::with_lock_policy(LockPolicy {
+            dispute_limit: Some(DisputeLimit {
+                max_disputes: 1,
+                window: 3,
+            }),
+            max_disputed_ratio: None,
+        });
+        for tx in 1..=3 {
+            client_storage.update(Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx,
+                amount: 1.0,
+            }));
+        }
+        // Transactions 4 and 7 of the client are disputes, too far apart for the window
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 2 }));
+        assert_eq!(client_storage.account_lock(1).unwrap(), None);
+        // Second dispute within 3 transactions locks the account
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 3 }));
+        let lock = client_storage.account_lock(1).unwrap().unwrap();
+        assert_eq!(lock.reason, LockReason::TooManyDisputes);
+        assert_eq!(lock.tx, 3);
+        assert_eq!(lock.sequence, 8);
+        let records = client_storage.get_csv_format_accounts();
+        let expected_records = CsvAccount {
+            client: 1,
+            available: 1.0,
+            held: 2.0,
+            total: 3.0,
+            locked: true,
+        };
+        assert_eq!(records[0], expected_records);
+    }
+
+    #[test]
+    fn test_lock_policy_disputed_amount() {
+        let mut client_storage = ClientInfoStorage::with_lock_policy(LockPolicy {
+            dispute_limit: None,
+            max_disputed_ratio: Some(0.5),
+        });
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+        }));
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 2,
+            amount: 1.0,
+        }));
+        // 1.0 out of 4.0 disputed
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 2 }));
+        client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 2 }));
+        assert_eq!(client_storage.account_lock(1).unwrap(), None);
+        // 4.0 out of 4.0 disputed
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        let lock = client_storage.account_lock(1).unwrap().unwrap();
+        assert_eq!(lock.reason, LockReason::DisputedAmount);
+        assert_eq!(lock.tx, 1);
+    }
+
+    #[test]
+    fn test_update_outcome() {
+        let mut client_storage = ClientInfoStorage::new();
+        let outcome = client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+        }));
+        assert_eq!(outcome, UpdateOutcome::Applied);
+        let outcome = client_storage.update(Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 2,
+            amount: 4.0,
+        }));
+        assert_eq!(
+            outcome,
+            UpdateOutcome::Rejected(Rejection::InsufficientFunds)
+        );
+        let outcome = client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 1 }));
+        assert_eq!(outcome, UpdateOutcome::Rejected(Rejection::NotDisputed));
+        let outcome = client_storage.update(Transaction::Dispute(DisputeInfo { client: 2, tx: 1 }));
+        assert_eq!(outcome, UpdateOutcome::Rejected(Rejection::UnknownClient));
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::ChargeBack(ChargeBackInfo { client: 1, tx: 1 }));
+        let outcome = client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 3,
+            amount: 1.0,
+        }));
+        assert_eq!(outcome, UpdateOutcome::Rejected(Rejection::AccountLocked));
+    }
+
+    #[test]
+    fn test_storage_gauges() {
+        let mut client_storage = ClientInfoStorage::new();
+        for tx in 1..=3 {
+            client_storage.update(Transaction::Deposit(DepositInfo {
+                client: tx as ClientId,
+                tx,
+                amount: 2.0,
+            }));
+            client_storage.update(Transaction::Dispute(DisputeInfo {
+                client: tx as ClientId,
+                tx,
+            }));
+        }
+        client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::ChargeBack(ChargeBackInfo { client: 2, tx: 2 }));
+        // Rejected transactions do not change the gauges
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 3, tx: 3 }));
+        let gauges = client_storage.gauges();
+        assert_eq!(gauges.clients, client_storage.client_count() as u64);
+        // The gauges match the stored accounts and deposits
+        let accounts = client_storage.backend.accounts().unwrap();
+        assert_eq!(
+            gauges.open_disputes,
+            client_storage.backend.open_disputes().unwrap() as u64
+        );
+        assert_eq!(
+            gauges.locked_accounts,
+            accounts
+                .iter()
+                .filter(|(_, account)| account.is_locked())
+                .count() as u64
+        );
+        assert_eq!(
+            (gauges.clients, gauges.open_disputes, gauges.locked_accounts),
+            (3, 1, 1)
+        );
+        assert_eq!(gauges.held_funds, 2.0);
+    }
+
+    #[test]
+    fn test_account_and_transaction_lookups() {
+        let mut client_storage = ClientInfoStorage::new();
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+        }));
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 2,
+            tx: 2,
+            amount: 1.5,
+        }));
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        let account = client_storage.account(1).unwrap().unwrap();
+        assert_eq!(
+            (account.available(), account.held(), account.total()),
+            (0.0, 3.0, 3.0)
+        );
+        assert_eq!(client_storage.account(3).unwrap(), None);
+        assert_eq!(client_storage.accounts().unwrap().count(), 2);
+        assert_eq!(
+            client_storage.transaction(1, 1).unwrap(),
+            Some(TransactionRecord {
+                client: 1,
+                tx: 1,
+                amount: 3.0,
+                disputed: true,
+            })
+        );
+        assert_eq!(
+            client_storage
+                .transaction(2, 2)
+                .unwrap()
+                .map(|record| record.client),
+            Some(2)
+        );
+        assert_eq!(client_storage.transaction(1, 3).unwrap(), None);
+        // The same transaction ID of another client is a different deposit
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 2,
+            tx: 1,
+            amount: 5.0,
+        }));
+        assert_eq!(
+            client_storage
+                .transaction(1, 1)
+                .unwrap()
+                .map(|record| record.amount),
+            Some(3.0)
+        );
+        assert_eq!(
+            client_storage
+                .transaction(2, 1)
+                .unwrap()
+                .map(|record| record.amount),
+            Some(5.0)
+        );
+        assert_eq!(client_storage.transaction(1, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let archive = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        struct SharedWriter(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for SharedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut client_storage = ClientInfoStorage::new();
+        client_storage.set_retention(
+            RetentionPolicy {
+                max_age: "transactions:2".parse().unwrap(),
+                interval: 1,
+            },
+            Some(Box::new(SharedWriter(archive.clone()))),
+        );
+        for tx in 1..=6 {
+            client_storage.update(Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx,
+                amount: 1.0,
+            }));
+            if tx == 2 {
+                client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+            }
+        }
+        // Deposit 1 is disputed, 2 and 3 have been pruned by the 7th transaction
+        assert_eq!(
+            client_storage
+                .transaction(1, 1)
+                .unwrap()
+                .map(|record| record.tx),
+            Some(1)
+        );
+        assert_eq!(client_storage.transaction(1, 2).unwrap(), None);
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 3 })),
+            UpdateOutcome::Rejected(Rejection::Pruned)
+        );
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 9 })),
+            UpdateOutcome::Rejected(Rejection::UnknownTransaction)
+        );
+        assert_eq!(client_storage.account(1).unwrap().unwrap().total(), 6.0);
+        let archive = String::from_utf8(archive.lock().unwrap().clone()).unwrap();
+        let mut lines = archive.lines();
+        assert_eq!(lines.next(), Some("client,tx,amount,sequence,position"));
+        assert_eq!(lines.next(), Some("1,2,1.0,2,2"));
+        assert_eq!(lines.next(), Some("1,3,1.0,4,4"));
+        // Deposits 4 and 5 have been pruned by the rejected disputes
+        assert_eq!(lines.count(), 2);
+        // IDs below the pruned ones which have never been deposited are still unknown
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 0 })),
+            UpdateOutcome::Rejected(Rejection::UnknownTransaction)
+        );
+
+        assert_eq!(
+            "client-transactions:10".parse(),
+            Ok(RetentionAge::ClientTransactions(10))
+        );
+        assert!("days:1".parse::<RetentionAge>().is_err());
+        assert!("seconds:60".parse::<RetentionAge>().is_err());
+    }
+
+    #[test]
+    fn test_charge_back_lock_reason() {
+        let mut client_storage = ClientInfoStorage::new();
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+        }));
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        assert_eq!(client_storage.account_lock(1).unwrap(), None);
+        client_storage.update(Transaction::ChargeBack(ChargeBackInfo { client: 1, tx: 1 }));
+        let lock = client_storage.account_lock(1).unwrap().unwrap();
+        assert_eq!(lock.reason, LockReason::ChargeBack);
+        assert_eq!(lock.tx, 1);
+        assert_eq!(lock.sequence, 3);
+    }
+
+    #[test]
+    fn test_lock_history() {
+        let mut client_storage = ClientInfoStorage::new();
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+        }));
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 2,
+            tx: 2,
+            amount: 1.0,
+        }));
+        assert!(client_storage.lock_history(1).unwrap().is_empty());
+
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        client_storage.update(Transaction::ChargeBack(ChargeBackInfo { client: 1, tx: 1 }));
+        let expected_lock = AccountLock {
+            reason: LockReason::ChargeBack,
+            tx: 1,
+            sequence: 4,
+        };
+        assert_eq!(client_storage.lock_history(1).unwrap(), [expected_lock]);
+
+        let records = client_storage.get_csv_format_account_details().unwrap();
+        let expected_records = CsvAccountDetails {
+            client: 1,
+            available: 0.0,
+            held: 0.0,
+            total: 0.0,
+            locked: true,
+            lock_reason: Some(LockReason::ChargeBack),
+            lock_tx: Some(1),
+            lock_sequence: Some(4),
+            lock_events: 1,
+        };
+        assert_eq!(records[0], expected_records);
+        assert_eq!(records[1].lock_events, 0);
+
+        let events = client_storage.get_csv_format_lock_history().unwrap();
+        let expected_events = CsvLockEvent {
+            client: 1,
+            reason: LockReason::ChargeBack,
+            tx: 1,
+            sequence: 4,
+        };
+        assert_eq!(events, [expected_events]);
+    }
+
+    #[test]
+    fn test_invariant_checks() {
+        let mut client_storage = ClientInfoStorage::new();
+        client_storage.set_invariant_check(InvariantCheck::EveryUpdate);
+        for tx in 1..=3 {
+            client_storage.update(Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx,
+                amount: 0.1 * tx as Amount,
+            }));
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx }));
         }
+        client_storage.update(Transaction::Resolve(ResolveInfo { client: 1, tx: 2 }));
+        client_storage.update(Transaction::ChargeBack(ChargeBackInfo { client: 1, tx: 3 }));
+        assert_eq!(client_storage.check_invariants(), 0);
+        assert!(client_storage.invariant_violations().is_empty());
+
+        // An account whose held funds do not match its disputed deposits
+        let mut backend = MemoryStorage::new();
+        let account = Account {
+            held: -1.0,
+            ..Account::default()
+        };
+        backend.put_account(2, &account).unwrap();
+        let deposit = DepositLog {
+            amount: 2.0,
+            disputed: true,
+            sequence: 1,
+            position: 1,
+        };
+        backend.put_deposit(2, 7, &deposit).unwrap();
+        let mut client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        client_storage.set_invariant_check(InvariantCheck::Interval(2));
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 3,
+            tx: 8,
+            amount: 1.0,
+        }));
+        assert!(client_storage.invariant_violations().is_empty());
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 2,
+            tx: 9,
+            amount: 1.0,
+        }));
+        let invariants: Vec<Invariant> = client_storage
+            .invariant_violations()
+            .iter()
+            .map(|violation| violation.invariant)
+            .collect();
+        assert_eq!(
+            invariants,
+            [Invariant::NonNegativeHeld, Invariant::HeldDisputed]
+        );
+        assert_eq!(client_storage.invariant_violations()[1].expected, 2.0);
+        // Violations which persist are reported once
+        assert_eq!(client_storage.check_invariants(), 0);
+
+        assert_eq!("update".parse(), Ok(InvariantCheck::EveryUpdate));
+        assert_eq!("100".parse(), Ok(InvariantCheck::Interval(100)));
+        assert!("0".parse::<InvariantCheck>().is_err());
     }
 }
@@ -0,0 +1,313 @@
+use crate::accounts::CsvAccount;
+use crate::ids::ExternalIds;
+use crate::output::AccountColumn;
+use crate::transactions::{Amount, ClientId, Transaction, TransactionError, TransactionId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+/// An account as recomputed from its transactions, independently of the ClientInfoStorage
+#[derive(Debug, Default)]
+struct AuditedAccount {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    /// Amount and disputed flag of every deposit
+    deposits: HashMap<TransactionId, (Amount, bool)>,
+}
+
+impl AuditedAccount {
+    fn apply(&mut self, transaction: &Transaction) {
+        if self.locked {
+            return;
+        }
+        match transaction {
+            Transaction::Deposit(info) if info.amount >= 0.0 => {
+                self.available += info.amount;
+                self.deposits.insert(info.tx, (info.amount, false));
+            }
+            Transaction::Withdrawal(info)
+                if info.amount >= 0.0 && self.available - info.amount >= 0.0 =>
+            {
+                self.available -= info.amount;
+            }
+            Transaction::Dispute(info) => {
+                if let Some((amount, disputed @ false)) = self.deposits.get_mut(&info.tx) {
+                    self.available -= *amount;
+                    self.held += *amount;
+                    *disputed = true;
+                }
+            }
+            Transaction::Resolve(info) => {
+                if let Some((amount, disputed @ true)) = self.deposits.get_mut(&info.tx) {
+                    self.available += *amount;
+                    self.held -= *amount;
+                    *disputed = false;
+                }
+            }
+            Transaction::ChargeBack(info) => {
+                if let Some((amount, disputed @ true)) = self.deposits.get_mut(&info.tx) {
+                    self.held -= *amount;
+                    *disputed = false;
+                    self.locked = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Recomputes the accounts from the given transactions with the engine's default policies:
+/// no fraud rules, no lock thresholds besides charge-backs and no retention. Records which
+/// fail to parse are skipped. Returns the accounts sorted by client
+pub fn recompute_accounts(
+    transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+) -> Vec<CsvAccount> {
+    let mut accounts: BTreeMap<ClientId, AuditedAccount> = BTreeMap::new();
+    for transaction in transactions.flatten() {
+        let account = match transaction {
+            // A deposit opens the account of a new client, even if it is rejected
+            Transaction::Deposit(_) => accounts.entry(transaction.client()).or_default(),
+            _ => match accounts.get_mut(&transaction.client()) {
+                Some(account) => account,
+                None => continue,
+            },
+        };
+        account.apply(&transaction);
+    }
+    accounts
+        .into_iter()
+        .map(|(client, account)| {
+            CsvAccount::from_balances(client, account.available, account.held, account.locked)
+        })
+        .collect()
+}
+
+/// A difference between an account recomputed from the transactions and the audited output.
+/// The client is a string with external IDs, see `with_external_ids`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Discrepancy<C = ClientId> {
+    /// Client of the account
+    pub client: C,
+    /// Differing column, or `account` if the account exists on one side only
+    pub column: &'static str,
+    /// Value recomputed from the transactions, empty if the account should not exist
+    pub expected: String,
+    /// Value of the audited output, empty if the account is missing
+    pub actual: String,
+}
+
+impl Discrepancy {
+    /// Replaces the internal client ID with the external ID it is mapped to
+    pub fn with_external_ids(self, ids: &ExternalIds) -> Discrepancy<String> {
+        Discrepancy {
+            client: ids.client_label(self.client),
+            column: self.column,
+            expected: self.expected,
+            actual: self.actual,
+        }
+    }
+}
+
+/// Returns true if two output amounts differ by more than the output precision
+fn amounts_differ(expected: Amount, actual: Amount) -> bool {
+    (f64::from(expected) - f64::from(actual)).abs() > 0.00015
+}
+
+/// Compares the accounts recomputed by `recompute_accounts` with the audited accounts and
+/// returns their discrepancies, sorted by client
+pub fn audit_accounts(expected: &[CsvAccount], actual: &[CsvAccount]) -> Vec<Discrepancy> {
+    let mut actual: BTreeMap<ClientId, &CsvAccount> = actual
+        .iter()
+        .map(|account| (account.client(), account))
+        .collect();
+    let mut discrepancies = Vec::new();
+    for expected in expected {
+        let client = expected.client();
+        let actual = match actual.remove(&client) {
+            Some(actual) => actual,
+            None => {
+                discrepancies.push(Discrepancy {
+                    client,
+                    column: "account",
+                    expected: "present".to_string(),
+                    actual: String::new(),
+                });
+                continue;
+            }
+        };
+        let amounts = [
+            (
+                AccountColumn::Available,
+                expected.available(),
+                actual.available(),
+            ),
+            (AccountColumn::Held, expected.held(), actual.held()),
+            (AccountColumn::Total, expected.total(), actual.total()),
+        ];
+        for (column, expected, actual) in amounts {
+            if amounts_differ(expected, actual) {
+                discrepancies.push(Discrepancy {
+                    client,
+                    column: column.name(),
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        if expected.locked() != actual.locked() {
+            discrepancies.push(Discrepancy {
+                client,
+                column: AccountColumn::Locked.name(),
+                expected: expected.locked().to_string(),
+                actual: actual.locked().to_string(),
+            });
+        }
+    }
+    for client in actual.into_keys() {
+        discrepancies.push(Discrepancy {
+            client,
+            column: "account",
+            expected: String::new(),
+            actual: "present".to_string(),
+        });
+    }
+    discrepancies.sort_by_key(|discrepancy| discrepancy.client);
+    discrepancies
+}
+
+/// Reads an accounts output, CSV with a header and all the columns
+pub fn read_accounts(output: impl Read) -> csv::Result<Vec<CsvAccount>> {
+    csv::Reader::from_reader(output).deserialize().collect()
+}
+
+/// An account of an accounts output with external client IDs
+#[derive(Deserialize)]
+struct ExternalAccount {
+    client: String,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Reads an accounts output with external client IDs like `read_accounts`. The client IDs are
+/// mapped to internal IDs with the mapping the audited transactions have been read with
+pub fn read_accounts_with_ids(
+    output: impl Read,
+    ids: &ExternalIds,
+) -> csv::Result<Vec<CsvAccount>> {
+    csv::Reader::from_reader(output)
+        .deserialize()
+        .map(|account| {
+            let account: ExternalAccount = account?;
+            Ok(CsvAccount::from_output(
+                ids.client_id(&account.client),
+                account.available,
+                account.held,
+                account.total,
+                account.locked,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::transactions::read_transactions;
+
+    #[test]
+    fn test_audit_matches_engine() {
+        for path in [
+            "example_inputs/transactions.csv",
+            "example_inputs/transactions_chargeback.csv",
+        ] {
+            let input = std::fs::read(path).unwrap();
+            let engine = PaymentEngine::run(read_transactions(input.as_slice()));
+            let mut output = Vec::new();
+            engine.output_to_csv_format(&mut output);
+            let expected = recompute_accounts(read_transactions(input.as_slice()));
+            let actual = read_accounts(output.as_slice()).unwrap();
+            assert_eq!(audit_accounts(&expected, &actual), [], "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_audit_matches_engine_on_rejected_deposits() {
+        // A rejected deposit is not kept, so its dispute is rejected by both
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     deposit,1,2,-1.0\n\
+                     dispute,1,2,\n";
+        let engine = PaymentEngine::run(read_transactions(input.as_bytes()));
+        let mut output = Vec::new();
+        engine.output_to_csv_format(&mut output);
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n"
+        );
+        let expected = recompute_accounts(read_transactions(input.as_bytes()));
+        let actual = read_accounts(output.as_slice()).unwrap();
+        assert_eq!(audit_accounts(&expected, &actual), []);
+    }
+
+    #[test]
+    fn test_audit_reports_discrepancies() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     deposit,2,2,3.0\n\
+                     dispute,2,2,\n\
+                     chargeback,2,2,\n\
+                     deposit,2,3,1.0\n";
+        let expected = recompute_accounts(read_transactions(input.as_bytes()));
+        let output = "client,available,held,total,locked\n\
+                      2,0.0,0.5,0.5,false\n\
+                      3,1.0,0.0,1.0,false\n";
+        let actual = read_accounts(output.as_bytes()).unwrap();
+        let discrepancies: Vec<(ClientId, &str)> = audit_accounts(&expected, &actual)
+            .iter()
+            .map(|discrepancy| (discrepancy.client, discrepancy.column))
+            .collect();
+        assert_eq!(
+            discrepancies,
+            [
+                (1, "account"),
+                (2, "held"),
+                (2, "total"),
+                (2, "locked"),
+                (3, "account")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit_with_external_ids() {
+        let ids = ExternalIds::new();
+        let input = "type,client,tx,amount\n\
+                     deposit,alice,a-1,2.0\n\
+                     deposit,bob,b-1,3.0\n";
+        let expected = recompute_accounts(crate::transactions::read_transactions_with_ids(
+            input.as_bytes(),
+            &crate::dialect::CsvDialect::default(),
+            Some(ids.clone()),
+        ));
+        let output = "client,available,held,total,locked\n\
+                      bob,3.0,0.0,3.0,false\n\
+                      alice,1.0,0.0,1.0,false\n";
+        let actual = read_accounts_with_ids(output.as_bytes(), &ids).unwrap();
+        let discrepancies: Vec<_> = audit_accounts(&expected, &actual)
+            .into_iter()
+            .map(|discrepancy| discrepancy.with_external_ids(&ids))
+            .map(|discrepancy| (discrepancy.client, discrepancy.column))
+            .collect();
+        assert_eq!(
+            discrepancies,
+            [
+                ("alice".to_string(), "available"),
+                ("alice".to_string(), "total")
+            ]
+        );
+    }
+}
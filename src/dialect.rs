@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+/// Names of the transaction columns in their default order
+pub const TRANSACTION_COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "target"];
+
+/// Delimiter, quoting, header and column names of a CSV input or output
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvDialect {
+    /// Field delimiter
+    pub delimiter: u8,
+    /// Quote character
+    pub quote: u8,
+    /// Interpret (input) or write (output) quotes. Quote characters are plain data if false
+    pub quoting: bool,
+    /// The first record is a header. Without a header the transaction columns are expected
+    /// in their default order
+    pub has_headers: bool,
+    /// Trim whitespace around fields and headers
+    pub trim: bool,
+    /// Names of the input columns keyed by the transaction column they hold, e.g.
+    /// `client` => `client_id`. Columns without a name keep their default name
+    pub column_names: HashMap<String, String>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            quoting: true,
+            has_headers: true,
+            trim: true,
+            column_names: HashMap::new(),
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Returns the header of an input without a header
+    pub fn default_headers() -> csv::StringRecord {
+        csv::StringRecord::from(TRANSACTION_COLUMNS.to_vec())
+    }
+
+    /// Returns a CSV reader builder for this dialect
+    pub fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .quoting(self.quoting)
+            .has_headers(self.has_headers)
+            .trim(if self.trim {
+                csv::Trim::All
+            } else {
+                csv::Trim::None
+            });
+        builder
+    }
+
+    /// Returns the parser of the CSV reader for this dialect, which finds the records without
+    /// decoding them
+    pub(crate) fn core_reader(&self) -> csv_core::Reader {
+        csv_core::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .quoting(self.quoting)
+            .build()
+    }
+
+    /// Returns a CSV writer builder for this dialect. Headers are left to the caller
+    pub fn writer_builder(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .quote_style(if self.quoting {
+                csv::QuoteStyle::Necessary
+            } else {
+                csv::QuoteStyle::Never
+            })
+            .has_headers(false);
+        builder
+    }
+
+    /// Renames the columns of an input header to the transaction columns they hold. Unknown
+    /// columns are kept as they are and ignored when deserializing
+    pub fn map_headers(&self, headers: &csv::StringRecord) -> csv::StringRecord {
+        headers
+            .iter()
+            .map(|header| {
+                self.column_names
+                    .iter()
+                    .find(|(_, name)| name.as_str() == header)
+                    .map_or(header, |(column, _)| column.as_str())
+            })
+            .collect()
+    }
+}
+
+/// Parses a delimiter or quote character given as a single ASCII character or as `tab`
+pub fn parse_csv_byte(value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!("Expected a single ASCII character: {}", value)),
+    }
+}
+
+/// Parses a `column=name` mapping of a transaction column to the name of an input column
+pub fn parse_column_name(value: &str) -> Result<(String, String), String> {
+    let (column, name) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected column=name: {}", value))?;
+    let column = column.trim().to_ascii_lowercase();
+    if !TRANSACTION_COLUMNS.contains(&column.as_str()) {
+        return Err(format!("Unknown transaction column: {}", column));
+    }
+    Ok((column, name.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{read_transactions_with, Transaction};
+
+    fn deposits(
+        input: &str,
+        dialect: &CsvDialect,
+    ) -> Vec<(crate::transactions::ClientId, crate::transactions::Amount)> {
+        read_transactions_with(input.as_bytes(), dialect)
+            .map(|transaction| match transaction.unwrap() {
+                Transaction::Deposit(info) => (info.client, info.amount),
+                transaction => panic!("Unexpected transaction: {:?}", transaction),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_semicolon_dialect_with_column_names() {
+        let dialect = CsvDialect {
+            delimiter: b';',
+            column_names: [
+                ("type".to_string(), "transaction_type".to_string()),
+                ("client".to_string(), "client_id".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..CsvDialect::default()
+        };
+        let input = "transaction_type;client_id;tx;amount;channel\n\
+                     Deposit;1;1;1.5;web\n\
+                     DEPOSIT;2;2;\"2.5\";app\n";
+        assert_eq!(deposits(input, &dialect), vec![(1, 1.5), (2, 2.5)]);
+    }
+
+    #[test]
+    fn test_dialect_without_headers() {
+        let dialect = CsvDialect {
+            has_headers: false,
+            quoting: false,
+            ..CsvDialect::default()
+        };
+        assert_eq!(
+            deposits("deposit, 1, 1, 1.5\ndeposit, 2, 2, 2.5\n", &dialect),
+            vec![(1, 1.5), (2, 2.5)]
+        );
+        let error = read_transactions_with("deposit,x,1,1.0\n".as_bytes(), &dialect)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.field(), Some("client"));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse_csv_byte(";"), Ok(b';'));
+        assert_eq!(parse_csv_byte("tab"), Ok(b'\t'));
+        assert!(parse_csv_byte(";;").is_err());
+        assert_eq!(
+            parse_column_name("Client=client_id"),
+            Ok(("client".to_string(), "client_id".to_string()))
+        );
+        assert!(parse_column_name("balance=amount").is_err());
+        assert!(parse_column_name("client").is_err());
+    }
+}
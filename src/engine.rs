@@ -1,37 +1,548 @@
-use crate::accounts::ClientInfoStorage;
-use crate::transactions::{Transaction, TransactionError};
+use crate::accounts::{
+    AccountLock, ClientInfoStorage, CsvAccount, InvariantViolation, Rejection, TransactionRecord,
+    UpdateOutcome,
+};
+use crate::fraud::{
+    ApproveAll, FraudScorer, HeldTransaction, ReviewQueue, TransactionHistory, Verdict,
+};
+use crate::ids::ExternalIds;
+use crate::logging::REJECTION_TARGET;
+use crate::metrics::LiveMetrics;
+use crate::output::OutputOptions;
+use crate::stats::RunStats;
+use crate::transactions::{
+    ClientId, Transaction, TransactionError, TransactionId, TransactionType,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::time::Instant;
+
+/// Number of erroneous records a run tolerates before it is aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorLimit {
+    /// Log and skip every erroneous record
+    Unlimited,
+    /// Abort once more than this many records failed to parse or have been rejected
+    Max(usize),
+}
+
+impl ErrorLimit {
+    /// Abort on the first erroneous record
+    pub const STRICT: ErrorLimit = ErrorLimit::Max(0);
+
+    fn exceeded_by(&self, errors: usize) -> bool {
+        match self {
+            ErrorLimit::Unlimited => false,
+            ErrorLimit::Max(max) => errors > *max,
+        }
+    }
+}
+
+/// Why a single input record has not been applied
+#[derive(Debug)]
+pub enum RecordError {
+    /// The record could not be parsed
+    Parse(TransactionError),
+    /// The transaction has been rejected by the client storage or the fraud scorer
+    Rejected {
+        /// Type of the rejected transaction
+        transaction_type: TransactionType,
+        /// Client of the rejected transaction
+        client: ClientId,
+        /// ID of the rejected transaction
+        tx: TransactionId,
+        /// Why the transaction has been rejected
+        rejection: Rejection,
+    },
+}
+
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordError::Parse(error) => write!(f, "{}", error),
+            RecordError::Rejected {
+                transaction_type,
+                client,
+                tx,
+                rejection,
+            } => write!(
+                f,
+                "{} {} of client {} rejected: {}",
+                transaction_type, tx, client, rejection
+            ),
+        }
+    }
+}
+
+/// A rejected transaction as written to the rejection report. The IDs are strings with
+/// external IDs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CsvRejection<C = ClientId, T = TransactionId> {
+    /// Client of the rejected transaction
+    pub client: C,
+    /// ID of the rejected transaction
+    pub tx: T,
+    /// Type of the rejected transaction
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    /// Why the transaction has been rejected
+    pub reason: String,
+}
+
+/// Statistics of a single input processed by the Payment Engine
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct InputStats {
+    /// Number of records read from the input
+    pub records: u64,
+    /// Number of transactions which have been applied
+    pub applied: u64,
+    /// Number of transactions which have been held for review
+    pub held: u64,
+    /// Number of records which failed to parse
+    pub parse_errors: u64,
+    /// Number of transactions which have been rejected
+    pub rejected: u64,
+}
+
+/// A run which has been aborted because it exceeded its ErrorLimit
+#[derive(Debug)]
+pub struct RunError {
+    /// Number of erroneous records up to and including the one which aborted the run
+    pub errors: usize,
+    /// Position (starting from 1) of the record which aborted the run in its input
+    pub record: u64,
+    /// The error of the record which aborted the run
+    pub error: RecordError,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "run aborted at record {} ({} erroneous records): {}",
+            self.record, self.errors, self.error
+        )
+    }
+}
+
+impl std::error::Error for RunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.error {
+            RecordError::Parse(error) => Some(error),
+            RecordError::Rejected { .. } => None,
+        }
+    }
+}
 
 /// The main struct of the payment engine. Contains the complete client storage
 pub struct PaymentEngine {
     client_storage: ClientInfoStorage,
+    scorer: Box<dyn FraudScorer>,
+    history: TransactionHistory,
+    review_queue: ReviewQueue,
+    errors: usize,
+    stats: RunStats,
+    rejection_report: Option<csv::Writer<Box<dyn Write + Send>>>,
+    external_ids: Option<ExternalIds>,
+    metrics: Option<LiveMetrics>,
+}
+
+// clippy suggestion
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PaymentEngine {
+    /// Creates a Payment Engine without any transactions which approves every transaction.
+    /// Transactions are applied one at a time with `apply`
+    pub fn new() -> Self {
+        Self::with(ClientInfoStorage::new(), Box::new(ApproveAll))
+    }
+
     /// Runs the Payment Engine
     pub fn run(transactions: impl Iterator<Item = Result<Transaction, TransactionError>>) -> Self {
+        Self::run_with_scorer(transactions, Box::new(ApproveAll))
+    }
+
+    /// Runs the Payment Engine passing every transaction through the given FraudScorer
+    pub fn run_with_scorer(
+        transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+        scorer: Box<dyn FraudScorer>,
+    ) -> Self {
         // Create a new ClientStorage
-        let mut client_storage = ClientInfoStorage::new();
-        for transaction_result in transactions {
-            match transaction_result {
+        Self::run_with(transactions, ClientInfoStorage::new(), scorer)
+    }
+
+    /// Runs the Payment Engine on top of the given ClientInfoStorage passing every
+    /// transaction through the given FraudScorer
+    pub fn run_with(
+        transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+        client_storage: ClientInfoStorage,
+        scorer: Box<dyn FraudScorer>,
+    ) -> Self {
+        match Self::try_run_with(transactions, client_storage, scorer, ErrorLimit::Unlimited) {
+            Ok(engine) => engine,
+            Err(_) => unreachable!("an unlimited run is never aborted"),
+        }
+    }
+
+    /// Runs the Payment Engine like `run_with`, but aborts as soon as the number of records
+    /// which failed to parse or have been rejected exceeds the given ErrorLimit
+    pub fn try_run_with(
+        transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+        client_storage: ClientInfoStorage,
+        scorer: Box<dyn FraudScorer>,
+        error_limit: ErrorLimit,
+    ) -> Result<Self, RunError> {
+        let mut engine = Self::with(client_storage, scorer);
+        engine.run_input(transactions, error_limit)?;
+        engine.log_pending_review();
+        Ok(engine)
+    }
+
+    /// Creates a Payment Engine on top of the given ClientInfoStorage which passes every
+    /// transaction through the given FraudScorer. Inputs are processed with `run_input`
+    pub fn with(client_storage: ClientInfoStorage, scorer: Box<dyn FraudScorer>) -> Self {
+        Self {
+            client_storage,
+            scorer,
+            history: TransactionHistory::default(),
+            review_queue: ReviewQueue::default(),
+            errors: 0,
+            stats: RunStats::default(),
+            rejection_report: None,
+            external_ids: None,
+            metrics: None,
+        }
+    }
+
+    /// Writes every rejected transaction with its client, ID, type and rejection reason as
+    /// CSV to the given writer
+    pub fn set_rejection_report(&mut self, writer: Box<dyn Write + Send>) {
+        self.rejection_report = Some(csv::Writer::from_writer(writer));
+    }
+
+    /// Writes the rejection report, the account details and the lock history with the
+    /// external IDs of the given mapping, which the inputs are read with
+    pub fn set_external_ids(&mut self, ids: ExternalIds) {
+        self.external_ids = Some(ids);
+    }
+
+    /// Records the outcome of every transaction in the given metrics after the fraud decision,
+    /// so that held transactions and fraud rejections are counted as well. The metrics are
+    /// registered as an UpdateHook of the client storage for its gauges
+    pub fn set_metrics(&mut self, metrics: LiveMetrics) {
+        self.client_storage.add_hook(Box::new(metrics.clone()));
+        self.metrics = Some(metrics);
+    }
+
+    /// Processes the transactions of a single input and returns its statistics. Erroneous
+    /// records are counted across all the inputs of the engine, and the input is aborted as
+    /// soon as their number exceeds the given ErrorLimit
+    pub fn run_input(
+        &mut self,
+        transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+        error_limit: ErrorLimit,
+    ) -> Result<InputStats, RunError> {
+        let started = Instant::now();
+        let mut stats = InputStats::default();
+        for (record, transaction_result) in (1..).zip(transactions) {
+            stats.records = record;
+            let error = match transaction_result {
                 Ok(transaction) => {
-                    log::debug!("{:?}", transaction);
-                    // Update ClientStorage based on new transaction
-                    client_storage.update(transaction);
+                    let (transaction_type, client, tx) = (
+                        transaction.transaction_type(),
+                        transaction.client(),
+                        transaction.tx(),
+                    );
+                    match self.apply(transaction) {
+                        UpdateOutcome::Applied => {
+                            stats.applied += 1;
+                            continue;
+                        }
+                        UpdateOutcome::Held => {
+                            stats.held += 1;
+                            continue;
+                        }
+                        UpdateOutcome::Rejected(rejection) => {
+                            stats.rejected += 1;
+                            RecordError::Rejected {
+                                transaction_type,
+                                client,
+                                tx,
+                                rejection,
+                            }
+                        }
+                    }
                 }
                 Err(error) => {
-                    log::error!("Failed to deserialize transaction: {:?}", error);
+                    log::error!(
+                        "kind":% = error.kind();
+                        "Failed to deserialize transaction: {}", error
+                    );
+                    stats.parse_errors += 1;
+                    self.stats.record_parse_error(error.kind());
+                    RecordError::Parse(error)
                 }
+            };
+            self.errors += 1;
+            if error_limit.exceeded_by(self.errors) {
+                log::error!("Run aborted at record {}: {}", record, error);
+                self.flush_rejection_report();
+                self.stats.add_elapsed(started.elapsed());
+                return Err(RunError {
+                    errors: self.errors,
+                    record,
+                    error,
+                });
             }
         }
-        Self { client_storage }
+        self.flush_rejection_report();
+        self.stats.add_elapsed(started.elapsed());
+        Ok(stats)
+    }
+
+    fn flush_rejection_report(&mut self) {
+        if let Some(report) = &mut self.rejection_report {
+            let _ = report.flush();
+        }
+    }
+
+    /// Returns the counters and timings of all the inputs processed so far
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
     }
 
-    /// Outputs the stored accounts to a CSV format to stdout
+    /// Scores and applies a single transaction, or holds it for review
+    pub fn apply(&mut self, transaction: Transaction) -> UpdateOutcome {
+        log::debug!("{:?}", transaction);
+        let (transaction_type, client, tx) = (
+            transaction.transaction_type(),
+            transaction.client(),
+            transaction.tx(),
+        );
+        let started = Instant::now();
+        let outcome = self.process(transaction);
+        let latency = started.elapsed();
+        self.stats
+            .record_outcome(transaction_type, outcome, latency);
+        if let Some(metrics) = &self.metrics {
+            metrics.record(transaction_type, outcome, latency);
+        }
+        if let UpdateOutcome::Rejected(rejection) = outcome {
+            log::warn!(
+                target: REJECTION_TARGET,
+                client,
+                tx,
+                "type":% = transaction_type,
+                "reason":% = rejection;
+                "Transaction {} rejected: {}", tx, rejection
+            );
+            if let Some(report) = &mut self.rejection_report {
+                let (transaction_type, reason) =
+                    (transaction_type.to_string(), rejection.to_string());
+                let _ = match &self.external_ids {
+                    Some(ids) => report.serialize(CsvRejection {
+                        client: ids.client_label(client),
+                        tx: ids.transaction_label(tx),
+                        transaction_type,
+                        reason,
+                    }),
+                    None => report.serialize(CsvRejection {
+                        client,
+                        tx,
+                        transaction_type,
+                        reason,
+                    }),
+                };
+            }
+        }
+        outcome
+    }
+
+    /// Returns the account of the given client. Fails if the storage backend cannot be read
+    pub fn account(&self, client: ClientId) -> std::io::Result<Option<CsvAccount>> {
+        self.client_storage.account(client)
+    }
+
+    /// Returns an iterator over the accounts in no particular order
+    pub fn accounts(&self) -> std::io::Result<impl Iterator<Item = CsvAccount>> {
+        self.client_storage.accounts()
+    }
+
+    /// Returns the deposit with the given transaction ID of the given client. The client is
+    /// part of the lookup because the deposits are stored by client and transaction ID:
+    /// transaction IDs are only checked for uniqueness per client, so the same ID can refer
+    /// to deposits of different clients
+    pub fn transaction(
+        &self,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> std::io::Result<Option<TransactionRecord>> {
+        self.client_storage.transaction(client, tx)
+    }
+
+    /// Logs a warning if transactions are still held for review
+    pub fn log_pending_review(&self) {
+        if !self.review_queue.is_empty() {
+            log::warn!(
+                "{} transactions are still held for review",
+                self.review_queue.len()
+            );
+        }
+    }
+
+    /// Returns the number of clients in the client storage
+    pub fn client_count(&self) -> usize {
+        self.client_storage.client_count()
+    }
+
+    /// Returns the number of deposits which are disputed and have been neither resolved nor
+    /// charged back
+    pub fn open_disputes(&self) -> usize {
+        self.client_storage.open_disputes()
+    }
+
+    /// Returns the number of locked accounts
+    pub fn locked_accounts(&self) -> usize {
+        self.client_storage.locked_accounts()
+    }
+
+    /// Returns why and when the account of the given client has been locked
+    pub fn account_lock(&self, client: ClientId) -> std::io::Result<Option<AccountLock>> {
+        self.client_storage.account_lock(client)
+    }
+
+    /// Returns the locks of the account of the given client, oldest first. Locked accounts
+    /// are never unlocked, so the history holds lock events only
+    pub fn lock_history(&self, client: ClientId) -> std::io::Result<Vec<AccountLock>> {
+        self.client_storage.lock_history(client)
+    }
+
+    /// Checks the invariants of all the accounts now and returns the number of new violations
+    pub fn check_invariants(&mut self) -> usize {
+        self.client_storage.check_invariants()
+    }
+
+    /// Returns the invariant violations of the accounts found so far
+    pub fn invariant_violations(&self) -> &[InvariantViolation] {
+        self.client_storage.invariant_violations()
+    }
+
+    /// Returns the transactions which are held for review
+    pub fn pending_review(&self) -> impl Iterator<Item = &HeldTransaction> {
+        self.review_queue.pending()
+    }
+
+    /// Outputs the stored accounts sorted by client ID to a CSV format. A failed read of the
+    /// storage backend is logged and outputs no accounts, see `output_accounts`
     pub fn output_to_csv_format(&self, writer: impl std::io::Write) {
-        let records = self.client_storage.get_csv_format_accounts();
+        if let Err(error) = self.output_accounts(writer, &OutputOptions::default()) {
+            log::error!("Storage read error: {}", error);
+        }
+    }
+
+    /// Outputs the stored accounts in the format, order and with the rows and columns
+    /// selected by the given options. Fails without writing anything if the storage backend
+    /// cannot be read
+    pub fn output_accounts(
+        &self,
+        writer: impl std::io::Write,
+        options: &OutputOptions,
+    ) -> std::io::Result<()> {
+        let records = self.client_storage.try_csv_format_accounts()?;
+        options.write(records, writer);
+        Ok(())
+    }
+
+    /// Outputs the stored accounts along with their lock records in a CSV format
+    pub fn output_account_details(&self, writer: impl std::io::Write) -> std::io::Result<()> {
+        let records = self.client_storage.get_csv_format_account_details()?;
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        for record in records {
+            let _ = match &self.external_ids {
+                Some(ids) => csv_writer.serialize(record.with_external_ids(ids)),
+                None => csv_writer.serialize(record),
+            };
+        }
+        Ok(())
+    }
+
+    /// Outputs the locks of the stored accounts in a CSV format
+    pub fn output_lock_history(&self, writer: impl std::io::Write) -> std::io::Result<()> {
+        let records = self.client_storage.get_csv_format_lock_history()?;
         let mut csv_writer = csv::Writer::from_writer(writer);
         for record in records {
-            let _ = csv_writer.serialize(record);
+            let _ = match &self.external_ids {
+                Some(ids) => csv_writer.serialize(record.with_external_ids(ids)),
+                None => csv_writer.serialize(record),
+            };
+        }
+        Ok(())
+    }
+
+    fn process(&mut self, transaction: Transaction) -> UpdateOutcome {
+        match transaction {
+            Transaction::Release(info) => self.release(info.client, info.tx, info.target),
+            Transaction::Deny(info) => self.deny(info.client, info.tx, info.target),
+            transaction => {
+                let history = self.history.client(transaction.client());
+                match self.scorer.score(&transaction, history) {
+                    Verdict::Approve => self.commit(transaction),
+                    Verdict::Reject(reason) => {
+                        log::debug!("Transaction {} rejected: {}", transaction.tx(), reason);
+                        UpdateOutcome::Rejected(Rejection::Fraud)
+                    }
+                    Verdict::Hold(reason) => {
+                        log::warn!("Transaction {} held: {}", transaction.tx(), reason);
+                        let tx = transaction.tx();
+                        if self.review_queue.hold(transaction, reason) {
+                            UpdateOutcome::Held
+                        } else {
+                            log::debug!("Transaction {} is already held for review", tx);
+                            UpdateOutcome::Rejected(Rejection::AlreadyHeld)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn commit(&mut self, transaction: Transaction) -> UpdateOutcome {
+        self.history.record(&transaction);
+        // Update ClientStorage based on new transaction
+        self.client_storage.update(transaction)
+    }
+
+    fn release(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        target: Option<TransactionType>,
+    ) -> UpdateOutcome {
+        if let Some(held) = self.review_queue.take(client, tx, target) {
+            log::info!("Transaction {} released", tx);
+            self.commit(held.transaction)
+        } else {
+            log::debug!("Release error: Not available held transaction");
+            UpdateOutcome::Rejected(Rejection::NotHeld)
+        }
+    }
+
+    fn deny(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        target: Option<TransactionType>,
+    ) -> UpdateOutcome {
+        if self.review_queue.take(client, tx, target).is_some() {
+            log::info!("Transaction {} denied", tx);
+            UpdateOutcome::Applied
+        } else {
+            log::debug!("Deny error: Not available held transaction");
+            UpdateOutcome::Rejected(Rejection::NotHeld)
         }
     }
 }
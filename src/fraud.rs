@@ -0,0 +1,387 @@
+use crate::transactions::{Amount, ClientId, Transaction, TransactionId, TransactionType};
+use std::collections::{HashMap, VecDeque};
+
+/// The decision of a FraudScorer for a single transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// Apply the transaction to the client's account
+    Approve,
+    /// Drop the transaction with the given reason
+    Reject(String),
+    /// Keep the transaction in the review queue until it is released or denied
+    Hold(String),
+}
+
+/// Extension point which inspects every transaction before it is applied to the
+/// client storage
+pub trait FraudScorer {
+    /// Scores a transaction given the history of the client it refers to
+    fn score(&mut self, transaction: &Transaction, history: &ClientHistory) -> Verdict;
+}
+
+/// Scorer which approves every transaction
+#[derive(Default)]
+pub struct ApproveAll;
+
+impl FraudScorer for ApproveAll {
+    fn score(&mut self, _transaction: &Transaction, _history: &ClientHistory) -> Verdict {
+        Verdict::Approve
+    }
+}
+
+/// A transaction of a client which has been passed on to the client storage
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Position of the transaction in the input
+    pub sequence: u64,
+    /// Type of the transaction
+    pub transaction_type: TransactionType,
+    /// ID of the transaction, or of the referenced transaction for disputes
+    pub tx: TransactionId,
+    /// Amount of deposits and withdrawals
+    pub amount: Option<Amount>,
+}
+
+/// The most recent transactions of a client, oldest first
+#[derive(Debug, Default)]
+pub struct ClientHistory {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl ClientHistory {
+    /// Returns an iterator over the recorded transactions, most recent first
+    pub fn recent(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter().rev()
+    }
+
+    /// Returns the number of recorded transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no transaction of the client has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push(&mut self, entry: HistoryEntry, capacity: usize) {
+        if self.entries.len() == capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// Keeps a bounded history of the transactions of every client
+pub struct TransactionHistory {
+    capacity: usize,
+    sequence: u64,
+    clients: HashMap<ClientId, ClientHistory>,
+    empty: ClientHistory,
+}
+
+/// Default number of transactions kept per client
+pub const DEFAULT_HISTORY_CAPACITY: usize = 100;
+
+impl Default for TransactionHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_CAPACITY)
+    }
+}
+
+impl TransactionHistory {
+    /// Creates a new TransactionHistory which keeps up to `capacity` transactions per client
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            sequence: 0,
+            clients: HashMap::new(),
+            empty: ClientHistory::default(),
+        }
+    }
+
+    /// Returns the history of the given client
+    pub fn client(&self, client: ClientId) -> &ClientHistory {
+        self.clients.get(&client).unwrap_or(&self.empty)
+    }
+
+    /// Records a transaction to the history of its client
+    pub fn record(&mut self, transaction: &Transaction) {
+        self.sequence += 1;
+        let entry = HistoryEntry {
+            sequence: self.sequence,
+            transaction_type: transaction.transaction_type(),
+            tx: transaction.tx(),
+            amount: transaction.amount(),
+        };
+        self.clients
+            .entry(transaction.client())
+            .or_default()
+            .push(entry, self.capacity);
+    }
+}
+
+/// Thresholds used by the RulesScorer
+#[derive(Debug, Clone)]
+pub struct RulesConfig {
+    /// Number of most recent transactions of a client inspected for disputes
+    pub dispute_window: usize,
+    /// A dispute is held if the client already has this many disputes in the window
+    pub max_disputes: usize,
+    /// Number of most recent transactions of a client inspected for deposits
+    pub withdrawal_window: usize,
+    /// A withdrawal is held if it takes out at least this fraction of a deposit in the window
+    pub withdrawal_ratio: Amount,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            dispute_window: 10,
+            max_disputes: 3,
+            withdrawal_window: 1,
+            withdrawal_ratio: 0.9,
+        }
+    }
+}
+
+/// Built-in rules-based scorer
+#[derive(Default)]
+pub struct RulesScorer {
+    config: RulesConfig,
+}
+
+impl RulesScorer {
+    /// Creates a new RulesScorer with the given thresholds
+    pub fn new(config: RulesConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl FraudScorer for RulesScorer {
+    fn score(&mut self, transaction: &Transaction, history: &ClientHistory) -> Verdict {
+        match transaction {
+            Transaction::Dispute(_) => {
+                let disputes = history
+                    .recent()
+                    .take(self.config.dispute_window)
+                    .filter(|entry| entry.transaction_type == TransactionType::Dispute)
+                    .count();
+                if disputes >= self.config.max_disputes {
+                    return Verdict::Hold(format!(
+                        "{} disputes within the last {} transactions",
+                        disputes, self.config.dispute_window
+                    ));
+                }
+            }
+            Transaction::Withdrawal(info) => {
+                let deposit = history
+                    .recent()
+                    .take(self.config.withdrawal_window)
+                    .filter(|entry| entry.transaction_type == TransactionType::Deposit)
+                    .find(|entry| {
+                        matches!(entry.amount, Some(amount)
+                            if amount > 0.0 && info.amount >= amount * self.config.withdrawal_ratio)
+                    });
+                if let Some(deposit) = deposit {
+                    return Verdict::Hold(format!(
+                        "withdrawal right after deposit (tx {})",
+                        deposit.tx
+                    ));
+                }
+            }
+            _ => {}
+        }
+        Verdict::Approve
+    }
+}
+
+/// A transaction which waits in the review queue
+#[derive(Debug)]
+pub struct HeldTransaction {
+    /// The held transaction, applied as it is once released
+    pub transaction: Transaction,
+    /// Why the FraudScorer held the transaction
+    pub reason: String,
+}
+
+/// Transactions held by a FraudScorer, keyed by their client, transaction ID and type
+#[derive(Default)]
+pub struct ReviewQueue {
+    held: HashMap<(ClientId, TransactionId, TransactionType), HeldTransaction>,
+}
+
+impl ReviewQueue {
+    /// Holds a transaction for review. Returns false if a transaction of the same client,
+    /// ID and type is already held
+    pub fn hold(&mut self, transaction: Transaction, reason: String) -> bool {
+        let key = (
+            transaction.client(),
+            transaction.tx(),
+            transaction.transaction_type(),
+        );
+        if self.held.contains_key(&key) {
+            return false;
+        }
+        self.held.insert(
+            key,
+            HeldTransaction {
+                transaction,
+                reason,
+            },
+        );
+        true
+    }
+
+    /// Removes a held transaction of the given client from the queue. With a target type
+    /// only a transaction of that type is taken. Otherwise, if transactions of several types
+    /// with the same ID are held, a deposit is taken before a withdrawal and a withdrawal
+    /// before a dispute
+    pub fn take(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        target: Option<TransactionType>,
+    ) -> Option<HeldTransaction> {
+        match target {
+            Some(transaction_type) => self.held.remove(&(client, tx, transaction_type)),
+            None => TransactionType::ALL
+                .into_iter()
+                .find_map(|transaction_type| self.held.remove(&(client, tx, transaction_type))),
+        }
+    }
+
+    /// Returns an iterator over the transactions waiting for review
+    pub fn pending(&self) -> impl Iterator<Item = &HeldTransaction> {
+        self.held.values()
+    }
+
+    /// Returns the number of transactions waiting for review
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Returns true if no transaction waits for review
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{DepositInfo, DisputeInfo, WithdrawalInfo};
+
+    #[test]
+    fn test_rules_scorer_disputes() {
+        let mut history = TransactionHistory::default();
+        let mut scorer = RulesScorer::default();
+        for tx in 1..=3 {
+            history.record(&Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx,
+                amount: 1.0,
+            }));
+        }
+        for tx in 1..=3 {
+            let dispute = Transaction::Dispute(DisputeInfo { client: 1, tx });
+            assert_eq!(scorer.score(&dispute, history.client(1)), Verdict::Approve);
+            history.record(&dispute);
+        }
+        // Fourth dispute within the window is held
+        let dispute = Transaction::Dispute(DisputeInfo { client: 1, tx: 4 });
+        assert!(matches!(
+            scorer.score(&dispute, history.client(1)),
+            Verdict::Hold(_)
+        ));
+        // Other clients are not affected
+        let dispute = Transaction::Dispute(DisputeInfo { client: 2, tx: 4 });
+        assert_eq!(scorer.score(&dispute, history.client(2)), Verdict::Approve);
+    }
+
+    #[test]
+    fn test_rules_scorer_withdrawal_after_deposit() {
+        let mut history = TransactionHistory::default();
+        let mut scorer = RulesScorer::default();
+        history.record(&Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 10.0,
+        }));
+        let small = Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 2,
+            amount: 1.0,
+        });
+        assert_eq!(scorer.score(&small, history.client(1)), Verdict::Approve);
+        let large = Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 3,
+            amount: 9.5,
+        });
+        assert!(matches!(
+            scorer.score(&large, history.client(1)),
+            Verdict::Hold(_)
+        ));
+        // Deposit is no longer the last transaction of the client
+        history.record(&small);
+        assert_eq!(scorer.score(&large, history.client(1)), Verdict::Approve);
+    }
+
+    #[test]
+    fn test_review_queue() {
+        let mut queue = ReviewQueue::default();
+        let deposit = Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 1.0,
+        });
+        assert!(queue.hold(deposit, "reason".to_string()));
+        let duplicate = Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 2.0,
+        });
+        assert!(!queue.hold(duplicate, "reason".to_string()));
+        assert_eq!(queue.len(), 1);
+        // Wrong client cannot take the transaction
+        assert!(queue.take(2, 1, None).is_none());
+        assert!(queue.take(1, 1, None).is_some());
+        assert!(queue.is_empty());
+
+        // The same ID of another type or another client does not collide
+        let dispute = Transaction::Dispute(DisputeInfo { client: 1, tx: 1 });
+        let other_client = Transaction::Deposit(DepositInfo {
+            client: 2,
+            tx: 1,
+            amount: 1.0,
+        });
+        let deposit = Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 1.0,
+        });
+        assert!(queue.hold(dispute, "reason".to_string()));
+        assert!(queue.hold(other_client, "reason".to_string()));
+        assert!(queue.hold(deposit, "reason".to_string()));
+        assert_eq!(queue.len(), 3);
+        let taken = |held: Option<HeldTransaction>| held.map(|held| held.transaction);
+        // The target type selects the dispute although the deposit comes first
+        assert!(queue
+            .take(1, 1, Some(TransactionType::Withdrawal))
+            .is_none());
+        assert!(matches!(
+            taken(queue.take(1, 1, Some(TransactionType::Dispute))),
+            Some(Transaction::Dispute(_))
+        ));
+        assert!(matches!(
+            taken(queue.take(1, 1, None)),
+            Some(Transaction::Deposit(_))
+        ));
+        assert_eq!(
+            taken(queue.take(2, 1, None)).map(|held| held.client()),
+            Some(2)
+        );
+        assert!(queue.is_empty());
+    }
+}
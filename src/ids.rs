@@ -0,0 +1,176 @@
+use crate::transactions::{ClientId, TransactionId};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// External IDs of one kind. Internal IDs are assigned in the order the external IDs are
+/// seen, starting from 1. Both directions share the same string
+#[derive(Debug, Default)]
+struct IdTable {
+    ids: HashMap<Arc<str>, u64>,
+    names: HashMap<u64, Arc<str>>,
+    last: u64,
+}
+
+impl IdTable {
+    fn id(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        self.last += 1;
+        let name: Arc<str> = Arc::from(name);
+        self.ids.insert(name.clone(), self.last);
+        self.names.insert(self.last, name);
+        self.last
+    }
+
+    fn name(&self, id: u64) -> Option<&str> {
+        self.names.get(&id).map(|name| &**name)
+    }
+
+    /// Forgets the given internal ID. Its internal ID is not assigned again
+    fn release(&mut self, id: u64) {
+        if let Some(name) = self.names.remove(&id) {
+            self.ids.remove(&name);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IdTables {
+    clients: IdTable,
+    transactions: IdTable,
+}
+
+/// Maps external client and transaction IDs, e.g. UUIDs, to internal IDs and back. Clones
+/// share the same mapping, so that the inputs and the outputs of a run agree on the IDs.
+///
+/// The IDs of pruned deposits are kept, so that later disputes of them map to the pruned IDs
+/// and are rejected as pruned. The mapping therefore grows with the IDs of the run even with
+/// a retention policy
+#[derive(Debug, Clone, Default)]
+pub struct ExternalIds {
+    tables: Arc<Mutex<IdTables>>,
+}
+
+impl ExternalIds {
+    /// Creates an empty mapping
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, IdTables> {
+        // The tables stay consistent even if a holder of the lock panicked
+        match self.tables.lock() {
+            Ok(tables) => tables,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns the internal ID of the given external client ID, assigning the next free ID to
+    /// an unseen one
+    pub fn client_id(&self, name: &str) -> ClientId {
+        self.tables().clients.id(name)
+    }
+
+    /// Returns the internal ID of the given external transaction ID, assigning the next free
+    /// ID to an unseen one
+    pub fn transaction_id(&self, name: &str) -> TransactionId {
+        self.tables().transactions.id(name)
+    }
+
+    /// Returns the external ID of the given internal client ID
+    pub fn client_name(&self, client: ClientId) -> Option<String> {
+        self.tables().clients.name(client).map(String::from)
+    }
+
+    /// Returns the external ID of the given internal transaction ID
+    pub fn transaction_name(&self, tx: TransactionId) -> Option<String> {
+        self.tables().transactions.name(tx).map(String::from)
+    }
+
+    /// Returns the external ID of the given internal client ID, or the internal ID if it is
+    /// not mapped
+    pub fn client_label(&self, client: ClientId) -> String {
+        self.client_name(client)
+            .unwrap_or_else(|| client.to_string())
+    }
+
+    /// Returns the external ID of the given internal transaction ID, or the internal ID if it
+    /// is not mapped, e.g. because it has been released
+    pub fn transaction_label(&self, tx: TransactionId) -> String {
+        self.transaction_name(tx).unwrap_or_else(|| tx.to_string())
+    }
+
+    /// Forgets the external ID of the given internal transaction ID, e.g. once the transaction
+    /// cannot be referred to anymore. The internal ID is not assigned again
+    pub fn release_transaction(&self, tx: TransactionId) {
+        self.tables().transactions.release(tx);
+    }
+
+    /// Writes the mapping as CSV with the columns kind (client or tx), id and external_id,
+    /// sorted by internal ID. Released IDs are not written
+    pub fn write_csv(&self, writer: impl Write) -> csv::Result<()> {
+        let tables = self.tables();
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        csv_writer.write_record(["kind", "id", "external_id"])?;
+        for (kind, table) in [("client", &tables.clients), ("tx", &tables.transactions)] {
+            let mut names: Vec<_> = table.names.iter().collect();
+            names.sort_unstable_by_key(|(id, _)| **id);
+            for (id, name) in names {
+                csv_writer.serialize((kind, id, &**name))?;
+            }
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_ids() {
+        let ids = ExternalIds::new();
+        let shared = ids.clone();
+        assert_eq!(ids.client_id("7f9c1e2a-8d3b-4c5e-9a1f-2b3c4d5e6f70"), 1);
+        assert_eq!(ids.client_id("alice"), 2);
+        assert_eq!(shared.client_id("7f9c1e2a-8d3b-4c5e-9a1f-2b3c4d5e6f70"), 1);
+        // Clients and transactions are numbered independently
+        assert_eq!(ids.transaction_id("alice"), 1);
+        assert_eq!(shared.client_name(2).as_deref(), Some("alice"));
+        assert_eq!(shared.transaction_name(1).as_deref(), Some("alice"));
+        assert_eq!(ids.client_name(0), None);
+        assert_eq!(ids.client_name(3), None);
+        assert_eq!(ids.client_label(3), "3");
+
+        let mut mapping = Vec::new();
+        ids.write_csv(&mut mapping).unwrap();
+        assert_eq!(
+            String::from_utf8(mapping).unwrap(),
+            "kind,id,external_id\n\
+             client,1,7f9c1e2a-8d3b-4c5e-9a1f-2b3c4d5e6f70\n\
+             client,2,alice\n\
+             tx,1,alice\n"
+        );
+    }
+
+    #[test]
+    fn test_release_transactions() {
+        let ids = ExternalIds::new();
+        let (first, second) = (ids.transaction_id("a"), ids.transaction_id("b"));
+        ids.release_transaction(first);
+        assert_eq!(ids.transaction_name(first), None);
+        assert_eq!(ids.transaction_label(first), "1");
+        assert_eq!(ids.transaction_name(second).as_deref(), Some("b"));
+        // A released ID is not assigned again
+        assert_eq!(ids.transaction_id("a"), 3);
+        let mut mapping = Vec::new();
+        ids.write_csv(&mut mapping).unwrap();
+        assert_eq!(
+            String::from_utf8(mapping).unwrap(),
+            "kind,id,external_id\ntx,2,b\ntx,3,a\n"
+        );
+    }
+}
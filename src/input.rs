@@ -0,0 +1,756 @@
+use crate::dialect::CsvDialect;
+use crate::ids::ExternalIds;
+use crate::transactions::{
+    read_transactions_with_ids, Amount, ChargeBackInfo, ClientId, CsvTransaction, DenyInfo,
+    DepositInfo, DisputeInfo, InputPosition, ReleaseInfo, ResolveInfo, Transaction,
+    TransactionError, TransactionErrorKind, TransactionId, TransactionType, WithdrawalInfo,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The format of the transactions input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// CSV with a `type,client,tx,amount` header
+    Csv,
+    /// One JSON object per line with `type`, `client`, `tx` and an optional `amount`
+    JsonLines,
+    /// Length-prefixed binary records (see `write_binary_transaction`)
+    Binary,
+}
+
+impl InputFormat {
+    /// Detects the input format from the extension of the given file path. The extension of a
+    /// compressed file (e.g. `.csv.gz`) is looked up before its compression extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let mut extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        if matches!(extension.as_str(), "gz" | "zst" | "zstd") {
+            let stem = Path::new(path.file_stem()?);
+            extension = stem.extension()?.to_str()?.to_ascii_lowercase();
+        }
+        match extension.as_str() {
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" | "ndjson" => Some(InputFormat::JsonLines),
+            "bin" => Some(InputFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" | "json-lines" => Ok(InputFormat::JsonLines),
+            "binary" | "bin" => Ok(InputFormat::Binary),
+            _ => Err(format!("Unknown input format: {}", format)),
+        }
+    }
+}
+
+/// Read transactions of the given format from input reader
+pub fn read_transactions_as(
+    format: InputFormat,
+    reader: impl Read + 'static,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>>> {
+    read_transactions_as_dialect(format, &CsvDialect::default(), reader)
+}
+
+/// Read transactions of the given format from input reader. CSV input is read in the given
+/// dialect
+pub fn read_transactions_as_dialect(
+    format: InputFormat,
+    dialect: &CsvDialect,
+    reader: impl Read + 'static,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>>> {
+    read_transactions_as_with_ids(format, dialect, None, reader)
+}
+
+/// Read transactions of the given format from input reader, mapping their client and
+/// transaction IDs with the given external IDs. CSV and JSON Lines IDs are read as strings;
+/// the numeric IDs of binary input are mapped by their decimal representation
+pub fn read_transactions_as_with_ids(
+    format: InputFormat,
+    dialect: &CsvDialect,
+    ids: Option<ExternalIds>,
+    reader: impl Read + 'static,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>>> {
+    match format {
+        InputFormat::Csv => Box::new(read_transactions_with_ids(reader, dialect, ids)),
+        InputFormat::JsonLines => Box::new(read_json_lines_transactions_with_ids(reader, ids)),
+        InputFormat::Binary => {
+            let transactions = read_binary_transactions(reader);
+            match ids {
+                Some(ids) => Box::new(transactions.map(move |transaction| {
+                    transaction.and_then(|transaction| map_binary_ids(transaction, &ids))
+                })),
+                None => Box::new(transactions),
+            }
+        }
+    }
+}
+
+/// Magic bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Magic bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Wraps the given reader in a gzip or zstd decoder if its content starts with the magic bytes
+/// of either format. Other content is read as it is
+pub fn decompress(mut reader: impl Read + 'static) -> std::io::Result<Box<dyn Read>> {
+    // Read the magic bytes upfront, as a pipe may return fewer bytes per read
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut reader)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    let reader = std::io::Cursor::new(magic.clone()).chain(reader);
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Returns true if the given content starts with the magic bytes of gzip or zstd
+pub fn is_compressed(content: &[u8]) -> bool {
+    content.starts_with(&GZIP_MAGIC) || content.starts_with(&ZSTD_MAGIC)
+}
+
+/// Memory-maps the given input file. The file must not be modified while it is mapped
+pub fn map_file(path: &Path) -> std::io::Result<memmap2::Mmap> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: the mapping is only read, and input files are not expected to change during a run.
+    // A file truncated by another process could still fault the read
+    unsafe { memmap2::Mmap::map(&file) }
+}
+
+/// Opens the given input file as a memory mapping, which spares the read calls. The CSV
+/// reader still copies the mapped bytes into its buffer. The file must not be modified while
+/// it is read
+pub fn map_input(path: &Path) -> std::io::Result<Box<dyn Read>> {
+    Ok(Box::new(std::io::Cursor::new(map_file(path)?)))
+}
+
+/// Expands the given input arguments into the files to process, in argument order. A directory
+/// expands to the files it contains and a glob pattern to the files it matches, both sorted by
+/// path. Other arguments are kept as they are
+pub fn expand_inputs(arguments: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for argument in arguments {
+        let pattern = argument.to_string_lossy();
+        if argument.is_dir() {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(argument)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    files.push(path);
+                }
+            }
+            files.sort();
+            inputs.extend(files);
+        } else if !argument.exists() && pattern.contains(['*', '?', '[']) {
+            let invalid_input =
+                |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+            let paths = glob::glob(&pattern).map_err(|error| {
+                invalid_input(format!("Invalid pattern {}: {}", pattern, error))
+            })?;
+            let mut files = Vec::new();
+            for path in paths {
+                let path = path.map_err(std::io::Error::from)?;
+                if path.is_file() {
+                    files.push(path);
+                }
+            }
+            if files.is_empty() {
+                return Err(invalid_input(format!("No input matches {}", pattern)));
+            }
+            files.sort();
+            inputs.extend(files);
+        } else {
+            inputs.push(argument.clone());
+        }
+    }
+    Ok(inputs)
+}
+
+/// Iterator over the transactions of a JSON Lines input
+struct JsonLinesTransactions<R> {
+    reader: R,
+    line: u64,
+    byte: u64,
+    record: u64,
+    buffer: Vec<u8>,
+    done: bool,
+    ids: Option<ExternalIds>,
+}
+
+impl<R: BufRead> JsonLinesTransactions<R> {
+    fn parse(&self, position: InputPosition) -> Result<Transaction, TransactionError> {
+        let error = |cause: Box<dyn std::error::Error + Send + Sync>| {
+            TransactionError::new(TransactionErrorKind::JsonDeserializeError).with_cause(cause)
+        };
+        let line = std::str::from_utf8(&self.buffer).map_err(|cause| error(cause.into()))?;
+        let json_transaction = match &self.ids {
+            Some(ids) => serde_json::from_str::<CsvTransaction<String, String>>(line.trim())
+                .map(|json_transaction| json_transaction.with_internal_ids(ids)),
+            None => serde_json::from_str::<CsvTransaction>(line.trim()),
+        };
+        json_transaction
+            .map_err(|cause| error(cause.into()))
+            .and_then(|json_transaction| json_transaction.try_into())
+            .map_err(|error| {
+                let raw_record = line.trim_end_matches(['\n', '\r']);
+                error.with_position(Some(position)).with_record(raw_record)
+            })
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLinesTransactions<R> {
+    type Item = Result<Transaction, TransactionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buffer.clear();
+            let position = InputPosition {
+                record: self.record + 1,
+                line: Some(self.line + 1),
+                byte: self.byte,
+            };
+            let read = match self.reader.read_until(b'\n', &mut self.buffer) {
+                Ok(0) => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(read) => read,
+                Err(cause) => {
+                    // Do not retry a failing reader forever
+                    self.done = true;
+                    return Some(Err(TransactionError::new(
+                        TransactionErrorKind::JsonDeserializeError,
+                    )
+                    .with_position(Some(position))
+                    .with_cause(cause)));
+                }
+            };
+            self.line += 1;
+            self.byte += read as u64;
+            // Skip empty lines
+            if self.buffer.iter().all(|byte| byte.is_ascii_whitespace()) {
+                continue;
+            }
+            self.record += 1;
+            return Some(self.parse(position));
+        }
+        None
+    }
+}
+
+/// Read transactions from JSON Lines input reader. Empty lines are skipped
+pub fn read_json_lines_transactions(
+    reader: impl Read,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    read_json_lines_transactions_with_ids(reader, None)
+}
+
+/// Read transactions from JSON Lines input reader. With external IDs the client and tx fields
+/// must be strings, which are mapped to internal IDs
+pub fn read_json_lines_transactions_with_ids(
+    reader: impl Read,
+    ids: Option<ExternalIds>,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    JsonLinesTransactions {
+        reader: BufReader::new(reader),
+        line: 0,
+        byte: 0,
+        record: 0,
+        buffer: Vec::new(),
+        done: false,
+        ids,
+    }
+}
+
+// Binary record layout (little endian):
+//   u32 payload length | u8 type | u64 client | u64 tx | f32 amount (deposits and withdrawals)
+//   or u8 target type (optional, releases and denies)
+const HEADER_LEN: usize = 1 + 8 + 8;
+const AMOUNT_LEN: usize = 4;
+/// Records longer than this are considered corrupted input
+const MAX_RECORD_LEN: usize = 64;
+
+fn type_code(transaction_type: TransactionType) -> u8 {
+    match transaction_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::ChargeBack => 4,
+        TransactionType::Release => 5,
+        TransactionType::Deny => 6,
+    }
+}
+
+/// Writes a transaction as a length-prefixed binary record
+pub fn write_binary_transaction(
+    writer: &mut impl Write,
+    transaction: &Transaction,
+) -> std::io::Result<()> {
+    let mut payload = Vec::with_capacity(HEADER_LEN + AMOUNT_LEN);
+    payload.push(type_code(transaction.transaction_type()));
+    payload.extend_from_slice(&transaction.client().to_le_bytes());
+    payload.extend_from_slice(&transaction.tx().to_le_bytes());
+    if let Some(amount) = transaction.amount() {
+        payload.extend_from_slice(&amount.to_le_bytes());
+    }
+    if let Some(target) = transaction.target() {
+        payload.push(type_code(target));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)
+}
+
+fn decode_error(field: &str) -> TransactionError {
+    TransactionError::new(TransactionErrorKind::BinaryDecodeError).with_field(field)
+}
+
+/// Maps the numeric IDs of a decoded transaction like external IDs
+fn map_binary_ids(
+    transaction: Transaction,
+    ids: &ExternalIds,
+) -> Result<Transaction, TransactionError> {
+    CsvTransaction {
+        transaction_type: transaction.transaction_type(),
+        client: transaction.client().to_string(),
+        tx: transaction.tx().to_string(),
+        amount: transaction.amount(),
+        target: transaction.target(),
+    }
+    .with_internal_ids(ids)
+    .try_into()
+}
+
+fn decode_binary_transaction(payload: &[u8]) -> Result<Transaction, TransactionError> {
+    if payload.len() < HEADER_LEN {
+        let field = match payload.len() {
+            0 => "type",
+            1..=8 => "client",
+            _ => "tx",
+        };
+        return Err(decode_error(field));
+    }
+    let id = |start: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&payload[start..start + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let client: ClientId = id(1);
+    let tx: TransactionId = id(9);
+    let amount = || -> Result<Amount, TransactionError> {
+        match payload[HEADER_LEN..] {
+            [a, b, c, d] => Ok(Amount::from_le_bytes([a, b, c, d])),
+            _ => Err(TransactionError::new(TransactionErrorKind::WrongFormat).with_field("amount")),
+        }
+    };
+    let target = || -> Result<Option<TransactionType>, TransactionError> {
+        match payload[HEADER_LEN..] {
+            [] => Ok(None),
+            [code] => TransactionType::ALL
+                .get(usize::from(code))
+                .copied()
+                .map(Some)
+                .ok_or_else(|| decode_error("target")),
+            _ => Err(decode_error("target")),
+        }
+    };
+    match payload[0] {
+        0 => Ok(Transaction::Deposit(DepositInfo {
+            client,
+            tx,
+            amount: amount()?,
+        })),
+        1 => Ok(Transaction::Withdrawal(WithdrawalInfo {
+            client,
+            tx,
+            amount: amount()?,
+        })),
+        2 => Ok(Transaction::Dispute(DisputeInfo { client, tx })),
+        3 => Ok(Transaction::Resolve(ResolveInfo { client, tx })),
+        4 => Ok(Transaction::ChargeBack(ChargeBackInfo { client, tx })),
+        5 => Ok(Transaction::Release(ReleaseInfo {
+            client,
+            tx,
+            target: target()?,
+        })),
+        6 => Ok(Transaction::Deny(DenyInfo {
+            client,
+            tx,
+            target: target()?,
+        })),
+        _ => Err(decode_error("type")),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Iterator over the transactions of a binary input
+struct BinaryTransactions<R> {
+    reader: R,
+    done: bool,
+    record: u64,
+    byte: u64,
+}
+
+impl<R: Read> BinaryTransactions<R> {
+    /// Fills the buffer. Returns false on a clean end of input before the first byte
+    fn read_exact_or_eof(
+        &mut self,
+        buffer: &mut [u8],
+        field: &str,
+    ) -> Result<bool, TransactionError> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => {
+                    return Err(decode_error(field)
+                        .with_cause("unexpected end of input")
+                        .with_record(hex(&buffer[..filled])))
+                }
+                Ok(read) => filled += read,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(decode_error(field).with_cause(error)),
+            }
+        }
+        Ok(true)
+    }
+
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>, TransactionError> {
+        let mut length = [0; 4];
+        if !self.read_exact_or_eof(&mut length, "length")? {
+            return Ok(None);
+        }
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_RECORD_LEN {
+            return Err(decode_error("length").with_cause(format!(
+                "record length {} exceeds {} bytes",
+                length, MAX_RECORD_LEN
+            )));
+        }
+        let mut payload = vec![0; length];
+        if length > 0 && !self.read_exact_or_eof(&mut payload, "payload")? {
+            return Err(decode_error("payload").with_cause("unexpected end of input"));
+        }
+        Ok(Some(payload))
+    }
+}
+
+impl<R: Read> Iterator for BinaryTransactions<R> {
+    type Item = Result<Transaction, TransactionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let position = InputPosition {
+            record: self.record + 1,
+            line: None,
+            byte: self.byte,
+        };
+        match self.next_record() {
+            Ok(Some(payload)) => {
+                self.record += 1;
+                self.byte += (4 + payload.len()) as u64;
+                Some(decode_binary_transaction(&payload).map_err(|error| {
+                    error
+                        .with_position(Some(position))
+                        .with_record(hex(&payload))
+                }))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                // The record boundaries cannot be trusted anymore
+                self.done = true;
+                Some(Err(error.with_position(Some(position))))
+            }
+        }
+    }
+}
+
+/// Read transactions from length-prefixed binary input reader
+pub fn read_binary_transactions(
+    reader: impl Read,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    BinaryTransactions {
+        reader: BufReader::new(reader),
+        done: false,
+        record: 0,
+        byte: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(input.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(input.as_bytes(), 0).unwrap();
+        for compressed in [gzip, zstd, input.as_bytes().to_vec()] {
+            let mut output = String::new();
+            decompress(std::io::Cursor::new(compressed))
+                .unwrap()
+                .read_to_string(&mut output)
+                .unwrap();
+            assert_eq!(output, input);
+        }
+        // Inputs shorter than the magic bytes are passed through
+        let mut output = Vec::new();
+        decompress(&b"\x1f"[..])
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, b"\x1f");
+    }
+
+    #[test]
+    fn test_map_input() {
+        let path = Path::new("example_inputs/transactions.csv");
+        let mut mapped = Vec::new();
+        map_input(path).unwrap().read_to_end(&mut mapped).unwrap();
+        assert_eq!(mapped, std::fs::read(path).unwrap());
+
+        let directory = tempfile::tempdir().unwrap();
+        let empty = directory.path().join("empty.csv");
+        std::fs::write(&empty, "").unwrap();
+        let transactions = read_transactions_as(InputFormat::Csv, map_input(&empty).unwrap());
+        assert_eq!(transactions.count(), 0);
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let inputs = expand_inputs(&[
+            PathBuf::from("example_inputs/transactions.csv"),
+            PathBuf::from("example_inputs/simple_*.csv"),
+            PathBuf::from("example_inputs"),
+        ])
+        .unwrap();
+        let mut files: Vec<PathBuf> = std::fs::read_dir("example_inputs")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(inputs[0], PathBuf::from("example_inputs/transactions.csv"));
+        assert_eq!(
+            inputs[1],
+            PathBuf::from("example_inputs/simple_transactions.csv")
+        );
+        assert_eq!(inputs[2..], files[..]);
+        assert!(expand_inputs(&[PathBuf::from("example_inputs/*.parquet")]).is_err());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":1.5,"currency":"EUR"}
+
+{"type":"withdrawal","client":1,"tx":2}
+{"type":"dispute","client":1,"tx":1}
+not json
+"#;
+        let transactions: Vec<_> = read_json_lines_transactions(input.as_bytes()).collect();
+        assert_eq!(transactions.len(), 4);
+        assert!(matches!(
+            transactions[0],
+            Ok(Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx: 1,
+                amount,
+            })) if amount == 1.5
+        ));
+        let error = transactions[1].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::WrongFormat);
+        assert_eq!(error.field(), Some("amount"));
+        assert_eq!(
+            error.position(),
+            Some(InputPosition {
+                record: 2,
+                line: Some(3),
+                byte: 68,
+            })
+        );
+        assert!(matches!(
+            transactions[2],
+            Ok(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }))
+        ));
+        let error = transactions[3].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::JsonDeserializeError);
+        assert_eq!(error.record(), Some("not json"));
+        assert_eq!(error.position().unwrap().line, Some(5));
+    }
+
+    #[test]
+    fn test_external_ids() {
+        let ids = ExternalIds::new();
+        let read = |format, input: Vec<u8>| {
+            read_transactions_as_with_ids(
+                format,
+                &CsvDialect::default(),
+                Some(ids.clone()),
+                std::io::Cursor::new(input),
+            )
+            .collect::<Vec<_>>()
+        };
+        let csv = "type,client,tx,amount\n\
+                   deposit,8c4f0a6e-1d2b-4f5a-9c3e-7b6a5d4c3b2a,tx-1,1.0\n\
+                   deposit,70000,tx-2,2.0\n";
+        let transactions = read(InputFormat::Csv, csv.as_bytes().to_vec());
+        let json = r#"{"type":"dispute","client":"8c4f0a6e-1d2b-4f5a-9c3e-7b6a5d4c3b2a","tx":"tx-1"}
+{"type":"dispute","client":70000,"tx":"tx-2"}
+"#;
+        let json_transactions = read(InputFormat::JsonLines, json.as_bytes().to_vec());
+        let mut binary = Vec::new();
+        write_binary_transaction(
+            &mut binary,
+            &Transaction::Resolve(ResolveInfo {
+                client: 70000,
+                tx: 3,
+            }),
+        )
+        .unwrap();
+        let binary_transactions = read(InputFormat::Binary, binary);
+
+        let ids_of = |transaction: &Result<Transaction, TransactionError>| {
+            let transaction = transaction.as_ref().unwrap();
+            (transaction.client(), transaction.tx())
+        };
+        assert_eq!(ids_of(&transactions[0]), (1, 1));
+        assert_eq!(ids_of(&transactions[1]), (2, 2));
+        assert_eq!(ids_of(&json_transactions[0]), (1, 1));
+        // External IDs of JSON input are strings
+        let error = json_transactions[1].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::JsonDeserializeError);
+        // Binary IDs are mapped by their decimal representation
+        assert_eq!(ids_of(&binary_transactions[0]), (2, 3));
+        assert_eq!(ids.client_name(2).as_deref(), Some("70000"));
+        assert_eq!(ids.transaction_name(3).as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let transactions = vec![
+            Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx: 1,
+                amount: 2.5,
+            }),
+            Transaction::Withdrawal(WithdrawalInfo {
+                client: 1,
+                tx: 2,
+                amount: 1.25,
+            }),
+            Transaction::ChargeBack(ChargeBackInfo {
+                client: u64::MAX,
+                tx: u64::MAX,
+            }),
+            Transaction::Release(ReleaseInfo {
+                client: 1,
+                tx: 1,
+                target: Some(TransactionType::Dispute),
+            }),
+        ];
+        let mut encoded = Vec::new();
+        for transaction in &transactions {
+            write_binary_transaction(&mut encoded, transaction).unwrap();
+        }
+        let decoded: Vec<_> = read_binary_transactions(encoded.as_slice()).collect();
+        assert_eq!(decoded.len(), 4);
+        for (decoded, transaction) in decoded.iter().zip(&transactions) {
+            let decoded = decoded.as_ref().unwrap();
+            assert_eq!(decoded.transaction_type(), transaction.transaction_type());
+            assert_eq!(decoded.client(), transaction.client());
+            assert_eq!(decoded.tx(), transaction.tx());
+            assert_eq!(decoded.amount(), transaction.amount());
+            assert_eq!(decoded.target(), transaction.target());
+        }
+    }
+
+    #[test]
+    fn test_binary_errors() {
+        let mut encoded = Vec::new();
+        // Unknown transaction type is skipped
+        encoded.extend_from_slice(&17u32.to_le_bytes());
+        encoded.push(9);
+        encoded.extend_from_slice(&1u64.to_le_bytes());
+        encoded.extend_from_slice(&1u64.to_le_bytes());
+        // Deposit without an amount
+        encoded.extend_from_slice(&17u32.to_le_bytes());
+        encoded.push(0);
+        encoded.extend_from_slice(&1u64.to_le_bytes());
+        encoded.extend_from_slice(&2u64.to_le_bytes());
+        write_binary_transaction(
+            &mut encoded,
+            &Transaction::Dispute(DisputeInfo { client: 1, tx: 2 }),
+        )
+        .unwrap();
+        // Truncated record ends the input
+        encoded.extend_from_slice(&17u32.to_le_bytes());
+        encoded.extend_from_slice(&[2, 1]);
+        let decoded: Vec<_> = read_binary_transactions(encoded.as_slice()).collect();
+        assert_eq!(decoded.len(), 4);
+        let error = decoded[0].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::BinaryDecodeError);
+        assert_eq!(error.field(), Some("type"));
+        assert_eq!(error.record(), Some("0901000000000000000100000000000000"));
+        let error = decoded[1].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::WrongFormat);
+        assert_eq!(
+            error.position(),
+            Some(InputPosition {
+                record: 2,
+                line: None,
+                byte: 21,
+            })
+        );
+        assert!(matches!(
+            decoded[2],
+            Ok(Transaction::Dispute(DisputeInfo { client: 1, tx: 2 }))
+        ));
+        let error = decoded[3].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::BinaryDecodeError);
+        assert_eq!(error.field(), Some("payload"));
+        assert_eq!(error.position().unwrap().byte, 63);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            InputFormat::from_path(Path::new("feed.JSONL")),
+            Some(InputFormat::JsonLines)
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("feed.bin")),
+            Some(InputFormat::Binary)
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("feed.csv")),
+            Some(InputFormat::Csv)
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("feed.jsonl.zst")),
+            Some(InputFormat::JsonLines)
+        );
+        assert_eq!(InputFormat::from_path(Path::new("feed.gz")), None);
+        assert_eq!(InputFormat::from_path(Path::new("feed")), None);
+    }
+}
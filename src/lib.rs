@@ -1,8 +1,37 @@
 #[deny(missing_docs)]
-
 /// Accounts related types and functions.
 pub mod accounts;
+/// Recomputation of the accounts from their transactions and audit of an accounts output.
+pub mod audit;
+/// CSV dialect of the transactions input and the accounts output.
+pub mod dialect;
 /// Includes the PaymentEngine struct and their methods.
 pub mod engine;
+/// Fraud scoring hook, built-in scorers and the review queue.
+pub mod fraud;
+/// Mapping of external client and transaction IDs to internal IDs.
+pub mod ids;
+/// Input formats of transactions.
+pub mod input;
+/// Logger setup with configurable destination, level and format.
+pub mod logging;
+/// Live Prometheus metrics of the payment engine and their HTTP endpoint.
+pub mod metrics;
+/// Output formats, sorting, filtering and column selection of the accounts output.
+pub mod output;
+/// Pipelined reading of transaction inputs on parser threads.
+pub mod pipeline;
+/// Partitioning of transaction inputs into shards by client and merging of their results.
+pub mod shard;
+/// Storage backend which spills the deposit history to disk beyond a memory budget.
+pub mod spill;
+/// SQLite storage backend of the client accounts and their deposit history.
+pub mod sqlite;
+/// Counters and timings of a run and their JSON and Prometheus outputs.
+pub mod stats;
+/// Storage backends of the client accounts and their deposit history.
+pub mod storage;
 /// Transactions related types and functions.
 pub mod transactions;
+/// Dry run of transaction inputs and their validation summary.
+pub mod validation;
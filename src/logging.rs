@@ -0,0 +1,197 @@
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Target of the log records of rejected transactions
+pub const REJECTION_TARGET: &str = "payment_engine::rejection";
+
+/// Where the log records are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogDestination {
+    /// Write to this file, replacing a previous log
+    File(PathBuf),
+    /// Write to stderr
+    Stderr,
+    /// Do not log
+    Off,
+}
+
+impl FromStr for LogDestination {
+    type Err = String;
+    fn from_str(destination: &str) -> Result<Self, Self::Err> {
+        match destination.trim() {
+            "" => Err("Empty log destination".to_string()),
+            "stderr" => Ok(LogDestination::Stderr),
+            "none" | "off" => Ok(LogDestination::Off),
+            // Parallel runs in the same directory can log to their own file
+            path => Ok(LogDestination::File(PathBuf::from(
+                path.replace("{pid}", &std::process::id().to_string()),
+            ))),
+        }
+    }
+}
+
+/// The format of the log records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line of plain text per record
+    Text,
+    /// One JSON object per record and line, including the key-values of the record
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", format)),
+        }
+    }
+}
+
+/// Initializes the global logger. Does nothing if a logger has already been initialized
+pub fn init_logging(
+    destination: &LogDestination,
+    level: LevelFilter,
+    format: LogFormat,
+) -> std::io::Result<()> {
+    let writer: Box<dyn Write + Send> = match destination {
+        LogDestination::File(path) => Box::new(File::create(path)?),
+        LogDestination::Stderr => Box::new(std::io::stderr()),
+        LogDestination::Off => return Ok(()),
+    };
+    match format {
+        LogFormat::Text => {
+            let _ = simplelog::WriteLogger::init(level, simplelog::Config::default(), writer);
+        }
+        LogFormat::Json => {
+            let logger = JsonLogger {
+                level,
+                writer: Mutex::new(writer),
+            };
+            if log::set_boxed_logger(Box::new(logger)).is_ok() {
+                log::set_max_level(level);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Logger which writes every record as a JSON object per line
+struct JsonLogger {
+    level: LevelFilter,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Collects the key-values of a record into a JSON object
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_f64() {
+            number.into()
+        } else if let Some(boolean) = value.to_bool() {
+            boolean.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Formats a log record as a JSON object
+fn json_record(record: &Record) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64);
+    object.insert("timestamp_ms".to_string(), timestamp.into());
+    object.insert("level".to_string(), record.level().as_str().into());
+    object.insert("target".to_string(), record.target().into());
+    object.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    serde_json::Value::Object(object)
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = serde_json::to_writer(&mut *writer, &json_record(record));
+            let _ = writeln!(writer);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let key_values: [(&str, Value); 4] = [
+            ("client", 1u16.into()),
+            ("tx", 5u32.into()),
+            ("type", "withdrawal".into()),
+            ("reason", "insufficient_funds".into()),
+        ];
+        let record = json_record(
+            &Record::builder()
+                .level(log::Level::Warn)
+                .target(REJECTION_TARGET)
+                .args(format_args!("Transaction rejected"))
+                .key_values(&key_values)
+                .build(),
+        );
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["target"], REJECTION_TARGET);
+        assert_eq!(record["message"], "Transaction rejected");
+        assert_eq!(record["client"], 1);
+        assert_eq!(record["tx"], 5);
+        assert_eq!(record["type"], "withdrawal");
+        assert_eq!(record["reason"], "insufficient_funds");
+    }
+
+    #[test]
+    fn test_log_options() {
+        assert_eq!("stderr".parse(), Ok(LogDestination::Stderr));
+        assert_eq!("none".parse(), Ok(LogDestination::Off));
+        assert_eq!(
+            "run.log".parse(),
+            Ok(LogDestination::File(PathBuf::from("run.log")))
+        );
+        assert_eq!(
+            "run-{pid}.log".parse(),
+            Ok(LogDestination::File(PathBuf::from(format!(
+                "run-{}.log",
+                std::process::id()
+            ))))
+        );
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
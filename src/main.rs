@@ -1,34 +1,911 @@
 use log::info;
-use payment_engine::engine::PaymentEngine;
-use payment_engine::transactions::read_transactions;
+use payment_engine::accounts::{
+    ClientInfoStorage, DisputeLimit, InvariantCheck, LockPolicy, RetentionAge, RetentionPolicy,
+};
+use payment_engine::audit::{
+    audit_accounts, read_accounts, read_accounts_with_ids, recompute_accounts,
+};
+use payment_engine::dialect::{parse_column_name, parse_csv_byte, CsvDialect};
+use payment_engine::engine::{ErrorLimit, PaymentEngine};
+use payment_engine::fraud::{ApproveAll, FraudScorer, RulesScorer};
+use payment_engine::ids::ExternalIds;
+use payment_engine::input::{
+    decompress, expand_inputs, is_compressed, map_file, map_input, read_transactions_as_with_ids,
+    InputFormat,
+};
+use payment_engine::logging::{init_logging, LogDestination, LogFormat};
+use payment_engine::metrics::{serve_metrics, LiveMetrics};
+use payment_engine::output::{AccountColumn, OutputFormat, OutputOptions};
+use payment_engine::pipeline::{pipelined, read_csv_parallel, PipelineOptions};
+use payment_engine::shard::{
+    errors_path, merge_accounts, merge_rejections, write_rejections, CsvSplitError, ShardWriter,
+};
+use payment_engine::spill::SpillingStorage;
+use payment_engine::sqlite::SqliteStorage;
+use payment_engine::transactions::{Transaction, TransactionError};
+use payment_engine::validation::validate;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+#[derive(Debug, StructOpt)]
+struct CsvOpt {
+    /// Field delimiter of CSV input (a single character or `tab`).
+    #[structopt(
+        long = "csv-delimiter",
+        default_value = ",",
+        parse(try_from_str = "parse_csv_byte")
+    )]
+    csv_delimiter: u8,
+    /// Quote character of CSV input.
+    #[structopt(
+        long = "csv-quote",
+        default_value = "\"",
+        parse(try_from_str = "parse_csv_byte")
+    )]
+    csv_quote: u8,
+    /// Read quote characters of CSV input as plain data.
+    #[structopt(long = "csv-no-quoting")]
+    csv_no_quoting: bool,
+    /// CSV input has no header; the columns are expected in the order
+    /// type,client,tx,amount,target.
+    #[structopt(long = "csv-no-headers")]
+    csv_no_headers: bool,
+    /// Do not trim whitespace around the fields of CSV input.
+    #[structopt(long = "csv-no-trim")]
+    csv_no_trim: bool,
+    /// Comma separated names of the CSV input columns holding the transaction columns,
+    /// e.g. type=transaction_type,client=client_id. Other columns are ignored.
+    #[structopt(
+        long = "csv-columns",
+        raw(use_delimiter = "true"),
+        parse(try_from_str = "parse_column_name")
+    )]
+    csv_columns: Vec<(String, String)>,
+}
+
+impl CsvOpt {
+    fn dialect(&self) -> CsvDialect {
+        CsvDialect {
+            delimiter: self.csv_delimiter,
+            quote: self.csv_quote,
+            quoting: !self.csv_no_quoting,
+            has_headers: !self.csv_no_headers,
+            trim: !self.csv_no_trim,
+            column_names: self.csv_columns.iter().cloned().collect(),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct Opt {
-    /// Transaction input file path.
+    /// Transaction input files, directories or glob patterns, processed in the given order.
+    /// `-` reads from stdin. Gzip and zstd compressed inputs are decompressed on the fly.
+    /// Required unless a subcommand is given.
     #[structopt(parse(from_os_str))]
-    input_file_path: PathBuf,
+    input_paths: Vec<PathBuf>,
+    /// Format of the input files (csv, jsonl or binary). Detected from the file extension
+    /// if not given, falling back to csv.
+    #[structopt(long = "input-format")]
+    input_format: Option<InputFormat>,
+    #[structopt(flatten)]
+    csv: CsvOpt,
+    /// Read the client and tx fields as external string IDs, e.g. UUIDs, which are mapped to
+    /// internal IDs. The outputs and reports show the external IDs. Not supported with
+    /// --sqlite, as the mapping is not kept in the database.
+    #[structopt(long = "external-ids", raw(conflicts_with = "\"sqlite\""))]
+    external_ids: bool,
+    /// Write the mapping of the external IDs to the internal IDs as CSV to this file.
+    #[structopt(
+        long = "id-map",
+        parse(from_os_str),
+        raw(requires = "\"external_ids\"")
+    )]
+    id_map: Option<PathBuf>,
+    /// Process the input files sorted by file name instead of in the given order.
+    #[structopt(long = "sort-inputs")]
+    sort_inputs: bool,
+    /// Memory-map the input files instead of reading them. The files must not change while
+    /// the engine runs.
+    #[structopt(long = "mmap")]
+    mmap: bool,
+    /// Parse the inputs on this many threads, which feed the engine while it applies the
+    /// transactions. Uncompressed CSV files are split into chunks parsed in parallel, other
+    /// inputs are parsed by a single thread.
+    #[structopt(long = "parser-threads")]
+    parser_threads: Option<usize>,
+    /// Write the number of records, applied, held and rejected transactions and parse errors
+    /// of every input file as CSV to this file.
+    #[structopt(long = "input-stats", parse(from_os_str))]
+    input_stats: Option<PathBuf>,
+    /// Abort without writing any output on the first record which fails to parse or is rejected.
+    #[structopt(long = "strict", raw(conflicts_with = "\"max_errors\""))]
+    strict: bool,
+    /// Abort without writing any output once more than this many records failed to parse
+    /// or have been rejected.
+    #[structopt(long = "max-errors")]
+    max_errors: Option<usize>,
+    /// Hold suspicious transactions for review using the built-in fraud rules.
+    #[structopt(long = "fraud-rules")]
+    fraud_rules: bool,
+    /// Lock an account when more disputes than this are opened within the dispute window.
+    #[structopt(long = "lock-max-disputes")]
+    lock_max_disputes: Option<usize>,
+    /// Number of most recent transactions of a client considered by --lock-max-disputes.
+    #[structopt(long = "lock-dispute-window", default_value = "10")]
+    lock_dispute_window: u64,
+    /// Lock an account when its disputed amount exceeds this percentage of its lifetime deposits.
+    #[structopt(long = "lock-disputed-percent")]
+    lock_disputed_percent: Option<f32>,
+    /// Keep the accounts and their deposits in this SQLite database instead of memory. The
+    /// accounts of a previous run in the database are continued.
+    #[structopt(long = "sqlite", parse(from_os_str))]
+    sqlite: Option<PathBuf>,
+    /// Commit the SQLite updates in batches of this many updates instead of one by one. Faster,
+    /// but a crash loses the updates of the open batch although they have been applied.
+    #[structopt(long = "sqlite-batch", raw(requires = "\"sqlite\""))]
+    sqlite_batch: Option<usize>,
+    /// Bound the memory of the deposit history: deposits beyond --memory-budget spill to an
+    /// on-disk index at this path, which is removed at the end of the run.
+    #[structopt(
+        long = "spill-index",
+        parse(from_os_str),
+        raw(conflicts_with = "\"sqlite\"")
+    )]
+    spill_index: Option<PathBuf>,
+    /// Memory budget of the cached deposit history in MiB when spilling with --spill-index.
+    #[structopt(long = "memory-budget", default_value = "256")]
+    memory_budget: usize,
+    /// Remove deposits which are not disputed from the history once they are older than this:
+    /// transactions:N or client-transactions:N (not with --spill-index). Later disputes of them
+    /// are rejected.
+    #[structopt(long = "retain")]
+    retain: Option<RetentionAge>,
+    /// Number of transactions between two prunings of the deposit history with --retain.
+    #[structopt(long = "retention-interval", default_value = "10000")]
+    retention_interval: u64,
+    /// Check the invariants of the accounts: `update` checks the account of every transaction
+    /// after its update, a number N checks all the accounts every N transactions. All the
+    /// accounts are checked again at the end; violations are logged, printed to stderr and
+    /// make the run exit with status 1 after writing its outputs.
+    #[structopt(long = "check-invariants")]
+    check_invariants: Option<InvariantCheck>,
+    /// Write the deposits removed by --retain as CSV to this file instead of dropping them.
+    #[structopt(
+        long = "retention-archive",
+        parse(from_os_str),
+        raw(requires = "\"retain\"")
+    )]
+    retention_archive: Option<PathBuf>,
+    /// Write the accounts along with their lock records as CSV to this file.
+    #[structopt(long = "account-details", parse(from_os_str))]
+    account_details: Option<PathBuf>,
+    /// Write the locks of the accounts as CSV to this file.
+    #[structopt(long = "lock-history", parse(from_os_str))]
+    lock_history: Option<PathBuf>,
+    /// Write the rejected transactions with their client, tx, type and reason as CSV to this
+    /// file.
+    #[structopt(long = "rejections", parse(from_os_str))]
+    rejections: Option<PathBuf>,
+    /// Output format of the accounts (csv, json, jsonl or json-columns).
+    #[structopt(long = "format", default_value = "csv")]
+    format: OutputFormat,
+    /// Sort the accounts by this column (client, available, held, total or locked).
+    #[structopt(long = "sort-by", default_value = "client")]
+    sort_by: AccountColumn,
+    /// Sort the accounts in descending order.
+    #[structopt(long = "descending")]
+    descending: bool,
+    /// Output only locked accounts.
+    #[structopt(long = "only-locked")]
+    only_locked: bool,
+    /// Output only accounts with a non-zero balance.
+    #[structopt(long = "non-zero")]
+    non_zero: bool,
+    /// Comma separated list of the columns to output.
+    #[structopt(long = "columns", raw(use_delimiter = "true"))]
+    columns: Vec<AccountColumn>,
+    /// Field delimiter of the CSV output (a single character or `tab`).
+    #[structopt(
+        long = "output-delimiter",
+        default_value = ",",
+        parse(try_from_str = "parse_csv_byte")
+    )]
+    output_delimiter: u8,
+    /// Do not write a header to the CSV output.
+    #[structopt(long = "output-no-headers")]
+    output_no_headers: bool,
+    /// Write the counters and timings of the run as JSON to this file.
+    #[structopt(long = "stats-json", parse(from_os_str))]
+    stats_json: Option<PathBuf>,
+    /// Write the counters and timings of the run in the Prometheus text format to this file.
+    #[structopt(long = "stats-prometheus", parse(from_os_str))]
+    stats_prometheus: Option<PathBuf>,
+    /// Serve live metrics in the Prometheus text format on http://<address>/metrics while
+    /// the inputs are processed, e.g. 127.0.0.1:9898.
+    #[structopt(long = "metrics-address")]
+    metrics_address: Option<String>,
+    /// Log destination: a file path (`{pid}` is replaced by the process ID), `stderr` or `none`.
+    /// A file is only written if its path is given.
+    #[structopt(
+        long = "log",
+        default_value = "stderr",
+        raw(env = "\"PAYMENT_ENGINE_LOG\"")
+    )]
+    log: LogDestination,
+    /// Log level (off, error, warn, info, debug or trace).
+    #[structopt(
+        long = "log-level",
+        default_value = "info",
+        raw(env = "\"PAYMENT_ENGINE_LOG_LEVEL\"")
+    )]
+    log_level: log::LevelFilter,
+    /// Log format (text or json). JSON records of rejected transactions carry the client,
+    /// tx, type and reason fields.
+    #[structopt(
+        long = "log-format",
+        default_value = "text",
+        raw(env = "\"PAYMENT_ENGINE_LOG_FORMAT\"")
+    )]
+    log_format: LogFormat,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Parse and apply the input file without writing the accounts and print a validation
+    /// summary instead. The transactions are applied in memory, never to the storage of
+    /// --sqlite or --spill-index. Exits with status 1 if any record fails to parse or is
+    /// rejected.
+    #[structopt(name = "validate")]
+    Validate {
+        /// Transaction input files, directories or glob patterns, processed in the given order.
+        /// `-` reads from stdin.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        input_paths: Vec<PathBuf>,
+        /// Format of the input files (csv, jsonl or binary). Detected from the file extension
+        /// if not given, falling back to csv.
+        #[structopt(long = "input-format")]
+        input_format: Option<InputFormat>,
+        #[structopt(flatten)]
+        csv: CsvOpt,
+        /// Read the client and tx fields as external string IDs, e.g. UUIDs.
+        #[structopt(long = "external-ids")]
+        external_ids: bool,
+        /// Process the input files sorted by file name instead of in the given order.
+        #[structopt(long = "sort-inputs")]
+        sort_inputs: bool,
+        /// Memory-map the input files instead of reading them.
+        #[structopt(long = "mmap")]
+        mmap: bool,
+        /// Print the summary as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Split the input files into shard files by client, which can be processed independently
+    /// and merged with `merge`. The shards are written as CSV to shard-NNNN.csv files and the
+    /// records which fail to parse to errors.csv.
+    #[structopt(name = "split")]
+    Split {
+        /// Transaction input files, directories or glob patterns, processed in the given order.
+        /// `-` reads from stdin.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        input_paths: Vec<PathBuf>,
+        /// Format of the input files (csv, jsonl or binary). Detected from the file extension
+        /// if not given, falling back to csv.
+        #[structopt(long = "input-format")]
+        input_format: Option<InputFormat>,
+        #[structopt(flatten)]
+        csv: CsvOpt,
+        /// Read the client and tx fields as external string IDs, e.g. UUIDs. The shards keep
+        /// the external IDs and are assigned by the external client ID.
+        #[structopt(long = "external-ids")]
+        external_ids: bool,
+        /// Process the input files sorted by file name instead of in the given order.
+        #[structopt(long = "sort-inputs")]
+        sort_inputs: bool,
+        /// Number of shards.
+        #[structopt(long = "shards")]
+        shards: usize,
+        /// Directory the shard files are written to. Created if it does not exist.
+        #[structopt(long = "output-dir", parse(from_os_str))]
+        output_dir: PathBuf,
+    },
+    /// Recompute the accounts from the input files and compare them with an accounts output.
+    /// Prints the discrepancies as CSV and exits with status 1 if there are any. Assumes the
+    /// default policies and refuses --fraud-rules, lock thresholds and --retain.
+    #[structopt(name = "audit")]
+    Audit {
+        /// Transaction input files, directories or glob patterns, processed in the given order.
+        /// `-` reads from stdin.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        input_paths: Vec<PathBuf>,
+        /// Format of the input files (csv, jsonl or binary). Detected from the file extension
+        /// if not given, falling back to csv.
+        #[structopt(long = "input-format")]
+        input_format: Option<InputFormat>,
+        #[structopt(flatten)]
+        csv: CsvOpt,
+        /// Read the client and tx fields as external string IDs, e.g. UUIDs, like the audited
+        /// run. The accounts output is read and the discrepancies are printed with them.
+        #[structopt(long = "external-ids")]
+        external_ids: bool,
+        /// Process the input files sorted by file name instead of in the given order.
+        #[structopt(long = "sort-inputs")]
+        sort_inputs: bool,
+        /// Accounts output to audit (CSV with a header and all the columns).
+        #[structopt(long = "accounts", parse(from_os_str))]
+        accounts: PathBuf,
+    },
+    /// Merge the account outputs of shards into a single output sorted by client.
+    #[structopt(name = "merge")]
+    Merge {
+        /// Account outputs of the shards (CSV with a header and all the columns), directories or
+        /// glob patterns.
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        account_paths: Vec<PathBuf>,
+        /// Rejection reports of the shards, directories or glob patterns.
+        #[structopt(
+            long = "rejections",
+            parse(from_os_str),
+            raw(number_of_values = "1", requires = "\"rejections_output\"")
+        )]
+        rejections: Vec<PathBuf>,
+        /// Write the merged rejection reports to this file.
+        #[structopt(long = "rejections-output", parse(from_os_str))]
+        rejections_output: Option<PathBuf>,
+        /// Output format of the accounts (csv, json, jsonl or json-columns).
+        #[structopt(long = "format", default_value = "csv")]
+        format: OutputFormat,
+    },
+}
+
+/// Opens the input file, or stdin for `-`, decompresses it if needed and reads transactions
+/// from it in the given or detected format
+fn open_input(
+    path: &Path,
+    input_format: Option<InputFormat>,
+    dialect: &CsvDialect,
+    ids: &Option<ExternalIds>,
+    mmap: bool,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>>> {
+    let input_file: Box<dyn Read> = if path == Path::new("-") {
+        Box::new(std::io::stdin())
+    } else if mmap {
+        map_input(path).expect("Unable to open input file")
+    } else {
+        Box::new(File::open(path).expect("Unable to open input file"))
+    };
+    let input_file = decompress(input_file).expect("Unable to read input file");
+    let input_format = input_format
+        .or_else(|| InputFormat::from_path(path))
+        .unwrap_or(InputFormat::Csv);
+    read_transactions_as_with_ids(input_format, dialect, ids.clone(), input_file)
+}
+
+/// Opens the input file like `open_input`, but parses it on parser threads. Uncompressed CSV
+/// files are parsed in parallel chunks unless the IDs are mapped, as that depends on the
+/// order in which the IDs are read
+fn open_pipelined(
+    path: &Path,
+    input_format: Option<InputFormat>,
+    dialect: &CsvDialect,
+    ids: &Option<ExternalIds>,
+    mmap: bool,
+    options: &PipelineOptions,
+) -> Box<dyn Iterator<Item = Result<Transaction, TransactionError>>> {
+    let format = input_format
+        .or_else(|| InputFormat::from_path(path))
+        .unwrap_or(InputFormat::Csv);
+    if options.parsers > 1 && format == InputFormat::Csv && ids.is_none() && path != Path::new("-")
+    {
+        let input = map_file(path).expect("Unable to open input file");
+        if !is_compressed(&input) {
+            return Box::new(read_csv_parallel(input, dialect, options));
+        }
+    }
+    let (path, dialect, ids) = (path.to_path_buf(), dialect.clone(), ids.clone());
+    Box::new(pipelined(
+        move || open_input(&path, input_format, &dialect, &ids, mmap),
+        options,
+    ))
+}
+
+/// Expands the input arguments into the input files, optionally sorted by file name
+fn input_files(input_paths: &[PathBuf], sort_inputs: bool) -> Vec<PathBuf> {
+    let mut input_files = expand_inputs(input_paths).expect("Unable to read input paths");
+    if sort_inputs {
+        input_files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    }
+    input_files
+}
+
+/// Splits the transactions of the input files into shard files by client
+fn split(
+    input_files: Vec<PathBuf>,
+    input_format: Option<InputFormat>,
+    dialect: &CsvDialect,
+    ids: Option<ExternalIds>,
+    shards: usize,
+    output_dir: &Path,
+) {
+    if shards == 0 {
+        structopt::clap::Error::with_description(
+            "At least one shard is required",
+            structopt::clap::ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    let mut writer =
+        ShardWriter::create(output_dir, shards).expect("Unable to create the shard files");
+    let mut errors =
+        csv::Writer::from_path(errors_path(output_dir)).expect("Unable to create the errors file");
+    let mut parse_errors = 0;
+    for path in input_files {
+        for transaction_result in open_input(&path, input_format, dialect, &ids, false) {
+            match transaction_result {
+                Ok(transaction) => {
+                    match &ids {
+                        Some(ids) => writer.write_with_ids(&transaction, ids),
+                        None => writer.write(&transaction),
+                    }
+                    .expect("Unable to write the shard files");
+                }
+                Err(error) => {
+                    log::error!(
+                        "kind":% = error.kind();
+                        "Failed to deserialize transaction: {}", error
+                    );
+                    errors
+                        .serialize(CsvSplitError::new(&path, &error))
+                        .expect("Unable to write the errors file");
+                    parse_errors += 1;
+                }
+            }
+        }
+    }
+    let transactions = writer.finish().expect("Unable to write the shard files");
+    errors.flush().expect("Unable to write the errors file");
+    info!("Split into {} shards: {:?}", shards, transactions);
+    if parse_errors > 0 {
+        eprintln!(
+            "{} records failed to parse and have been written to {} instead of a shard",
+            parse_errors,
+            errors_path(output_dir).display()
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Merges the account outputs and rejection reports of shards
+fn merge(
+    account_paths: &[PathBuf],
+    rejections: &[PathBuf],
+    rejections_output: Option<PathBuf>,
+    format: OutputFormat,
+) {
+    let open = |paths: &[PathBuf]| -> Vec<File> {
+        input_files(paths, false)
+            .iter()
+            .map(|path| File::open(path).expect("Unable to open shard output"))
+            .collect()
+    };
+    let accounts = match merge_accounts(open(account_paths)) {
+        Ok(accounts) => accounts,
+        Err(error) => {
+            eprintln!("Unable to merge the account outputs: {}", error);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = rejections_output {
+        let rejections = match merge_rejections(open(rejections)) {
+            Ok(rejections) => rejections,
+            Err(error) => {
+                eprintln!("Unable to merge the rejection reports: {}", error);
+                std::process::exit(1);
+            }
+        };
+        let rejections_file = File::create(path).expect("Unable to create rejections file");
+        let _ = write_rejections(&rejections, rejections_file);
+    }
+    let output_options = OutputOptions {
+        format,
+        ..OutputOptions::default()
+    };
+    output_options.write(accounts, std::io::stdout());
+}
+
+/// Recomputes the accounts from the input files and prints their discrepancies with the given
+/// accounts output
+fn audit(
+    input_files: Vec<PathBuf>,
+    input_format: Option<InputFormat>,
+    dialect: &CsvDialect,
+    ids: Option<ExternalIds>,
+    accounts: &Path,
+) {
+    let transactions = input_files
+        .into_iter()
+        .flat_map(|path| open_input(&path, input_format, dialect, &ids, false));
+    // The IDs of the output are mapped after the ones of the inputs, so that they agree
+    let expected = recompute_accounts(transactions);
+    let accounts_file = File::open(accounts).expect("Unable to open accounts output");
+    let actual = match &ids {
+        Some(ids) => read_accounts_with_ids(accounts_file, ids),
+        None => read_accounts(accounts_file),
+    };
+    let actual = match actual {
+        Ok(actual) => actual,
+        Err(error) => {
+            eprintln!("Unable to read the accounts output: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let discrepancies = audit_accounts(&expected, &actual);
+    // The header is written explicitly, so that it is printed without discrepancies as well
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(std::io::stdout());
+    let _ = csv_writer.write_record(["client", "column", "expected", "actual"]);
+    for discrepancy in &discrepancies {
+        let _ = match &ids {
+            Some(ids) => csv_writer.serialize(discrepancy.clone().with_external_ids(ids)),
+            None => csv_writer.serialize(discrepancy),
+        };
+    }
+    let _ = csv_writer.flush();
+    info!("Audit found {} discrepancies", discrepancies.len());
+    if !discrepancies.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Creates the fraud scorer selected by the options
+fn fraud_scorer(args: &Opt) -> Box<dyn FraudScorer> {
+    if args.fraud_rules {
+        Box::new(RulesScorer::default())
+    } else {
+        Box::new(ApproveAll)
+    }
+}
+
+/// Creates the lock policy selected by the options
+fn lock_policy(args: &Opt) -> LockPolicy {
+    LockPolicy {
+        dispute_limit: args.lock_max_disputes.map(|max_disputes| DisputeLimit {
+            max_disputes,
+            window: args.lock_dispute_window,
+        }),
+        max_disputed_ratio: args.lock_disputed_percent.map(|percent| percent / 100.0),
+    }
+}
+
+/// Returns the retention policy selected by the options, if any
+fn retention_policy(args: &Opt) -> Option<RetentionPolicy> {
+    args.retain.map(|max_age| RetentionPolicy {
+        max_age,
+        interval: args.retention_interval,
+    })
+}
+
+/// Creates the Payment Engine with the fraud scorer, lock policies, storage backend, retention,
+/// invariant checks, metrics and rejection report selected by the options
+fn payment_engine(args: &Opt) -> PaymentEngine {
+    let lock_policy = lock_policy(args);
+    let mut client_storage = if let Some(path) = &args.sqlite {
+        let mut backend = SqliteStorage::open(path).expect("Unable to open the SQLite database");
+        if let Some(updates) = args.sqlite_batch {
+            backend.set_batch_updates(updates);
+        }
+        ClientInfoStorage::with_backend(Box::new(backend), lock_policy)
+    } else if let Some(path) = &args.spill_index {
+        let backend = SpillingStorage::with_memory_budget(path, args.memory_budget << 20)
+            .expect("Unable to create the spill index");
+        ClientInfoStorage::with_backend(Box::new(backend), lock_policy)
+    } else {
+        ClientInfoStorage::with_lock_policy(lock_policy)
+    };
+    if let Some(policy) = retention_policy(args) {
+        if args.spill_index.is_some()
+            && matches!(policy.max_age, RetentionAge::ClientTransactions(_))
+        {
+            structopt::clap::Error::with_description(
+                "The spill index only supports --retain transactions:N",
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+            .exit()
+        }
+        let archive = args.retention_archive.as_ref().map(|path| {
+            let file = File::create(path).expect("Unable to create the retention archive");
+            Box::new(file) as Box<dyn std::io::Write + Send>
+        });
+        client_storage.set_retention(policy, archive);
+    }
+    if let Some(check) = args.check_invariants {
+        client_storage.set_invariant_check(check);
+    }
+    let mut payment_engine = PaymentEngine::with(client_storage, fraud_scorer(args));
+    if let Some(address) = &args.metrics_address {
+        let metrics = LiveMetrics::default();
+        serve_metrics(address.as_str(), metrics.clone()).expect("Unable to serve metrics");
+        payment_engine.set_metrics(metrics);
+    }
+    if let Some(path) = &args.rejections {
+        let rejections_file = File::create(path).expect("Unable to create rejections file");
+        payment_engine.set_rejection_report(Box::new(rejections_file));
+    }
+    payment_engine
+}
+
+/// Creates the Payment Engine of a dry run with the fraud scorer, lock policies, retention and
+/// invariant checks selected by the options. It keeps its state in memory and writes no
+/// archive, metrics or rejection report, so that the dry run leaves the storage backend of the
+/// options untouched
+fn dry_run_engine(args: &Opt) -> PaymentEngine {
+    let mut client_storage = ClientInfoStorage::with_lock_policy(lock_policy(args));
+    if let Some(policy) = retention_policy(args) {
+        client_storage.set_retention(policy, None);
+    }
+    if let Some(check) = args.check_invariants {
+        client_storage.set_invariant_check(check);
+    }
+    PaymentEngine::with(client_storage, fraud_scorer(args))
+}
+
+/// Writes the statistics of the run to the requested files
+fn write_stats(payment_engine: &PaymentEngine, args: &Opt) {
+    let stats = payment_engine.stats();
+    info!(
+        "Processed {} records ({} applied, {} held, {} rejected, {} parse errors) in {:.3}s",
+        stats.records,
+        stats.applied,
+        stats.held,
+        stats.rejected,
+        stats.parse_errors,
+        stats.elapsed_seconds
+    );
+    if let Some(path) = &args.stats_json {
+        let stats_file = File::create(path).expect("Unable to create stats file");
+        let _ = stats.write_json(stats_file);
+    }
+    if let Some(path) = &args.stats_prometheus {
+        let stats_file = File::create(path).expect("Unable to create stats file");
+        let _ = stats.write_prometheus(stats_file);
+    }
 }
 
 /// Entrypoint of the application
 fn main() {
-    let log_file = File::create("log.txt").expect("Unable to open log file");
-    let _ = simplelog::WriteLogger::init(
-        log::LevelFilter::Debug,
-        simplelog::Config::default(),
-        Box::new(log_file),
-    );
-    info!("Start toy payment engine!");
     let args = Opt::from_args();
+    if let Err(error) = init_logging(&args.log, args.log_level, args.log_format) {
+        eprintln!("Unable to open log file, logging is disabled: {}", error);
+    }
+    info!("Start toy payment engine!");
+
+    match args.command {
+        Some(Command::Split {
+            input_paths,
+            input_format,
+            csv,
+            external_ids,
+            sort_inputs,
+            shards,
+            output_dir,
+        }) => {
+            let input_files = input_files(&input_paths, sort_inputs);
+            split(
+                input_files,
+                input_format,
+                &csv.dialect(),
+                external_ids.then(ExternalIds::new),
+                shards,
+                &output_dir,
+            );
+            return;
+        }
+        Some(Command::Audit {
+            input_paths,
+            input_format,
+            csv,
+            external_ids,
+            sort_inputs,
+            accounts,
+        }) => {
+            // The model of the audit only knows the default policies
+            let policies = [
+                ("--fraud-rules", args.fraud_rules),
+                ("--lock-max-disputes", args.lock_max_disputes.is_some()),
+                (
+                    "--lock-disputed-percent",
+                    args.lock_disputed_percent.is_some(),
+                ),
+                ("--retain", args.retain.is_some()),
+            ];
+            if let Some((option, _)) = policies.iter().find(|(_, given)| *given) {
+                structopt::clap::Error::with_description(
+                    &format!("audit does not support runs with {}", option),
+                    structopt::clap::ErrorKind::ArgumentConflict,
+                )
+                .exit()
+            }
+            let input_files = input_files(&input_paths, sort_inputs);
+            let ids = external_ids.then(ExternalIds::new);
+            audit(input_files, input_format, &csv.dialect(), ids, &accounts);
+            return;
+        }
+        Some(Command::Merge {
+            account_paths,
+            rejections,
+            rejections_output,
+            format,
+        }) => {
+            merge(&account_paths, &rejections, rejections_output, format);
+            return;
+        }
+        _ => {}
+    }
 
-    let input_file = File::open(args.input_file_path).expect("Unable to open input file");
+    if let Some(Command::Validate {
+        input_paths,
+        input_format,
+        csv,
+        external_ids,
+        sort_inputs,
+        mmap,
+        json,
+    }) = &args.command
+    {
+        let (input_format, mmap) = (*input_format, *mmap);
+        let dialect = csv.dialect();
+        let ids = external_ids.then(ExternalIds::new);
+        // The dry run applies the same policies as a run of the main command, but on a storage
+        // of its own which starts empty
+        let mut payment_engine = dry_run_engine(&args);
+        if let Some(ids) = &ids {
+            payment_engine.set_external_ids(ids.clone());
+        }
+        let transactions = input_files(input_paths, *sort_inputs)
+            .into_iter()
+            .flat_map(move |path| open_input(&path, input_format, &dialect, &ids, mmap));
+        let summary = validate(transactions, &mut payment_engine);
+        payment_engine.log_pending_review();
+        if *json {
+            let _ = serde_json::to_writer_pretty(std::io::stdout(), &summary);
+            println!();
+        } else {
+            let _ = summary.write_text(std::io::stdout());
+        }
+        if !summary.is_valid() {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    // Read transactions from CSV
-    let transactions = read_transactions(input_file);
-    // Run payment engine for the given transactions
-    let payment_engine = PaymentEngine::run(transactions);
-    // Output the payment engine's results in a CSV format to stdout
-    payment_engine.output_to_csv_format(std::io::stdout());
+    if args.input_paths.is_empty() {
+        structopt::clap::Error::with_description(
+            "At least one input file path is required",
+            structopt::clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit()
+    }
+    let input_files = input_files(&args.input_paths, args.sort_inputs);
+    let dialect = args.csv.dialect();
+    let ids = args.external_ids.then(ExternalIds::new);
+    let error_limit = if args.strict {
+        ErrorLimit::STRICT
+    } else {
+        args.max_errors
+            .map_or(ErrorLimit::Unlimited, ErrorLimit::Max)
+    };
+    let mut payment_engine = payment_engine(&args);
+    if let Some(ids) = &ids {
+        payment_engine.set_external_ids(ids.clone());
+    }
+    let mut input_stats = Vec::new();
+    for path in input_files {
+        // Read transactions from the input file
+        let transactions = match args.parser_threads {
+            Some(parsers) => {
+                let options = PipelineOptions {
+                    parsers,
+                    ..PipelineOptions::default()
+                };
+                open_pipelined(
+                    &path,
+                    args.input_format,
+                    &dialect,
+                    &ids,
+                    args.mmap,
+                    &options,
+                )
+            }
+            None => open_input(&path, args.input_format, &dialect, &ids, args.mmap),
+        };
+        match payment_engine.run_input(transactions, error_limit) {
+            Ok(stats) => {
+                info!("Processed {}: {:?}", path.display(), stats);
+                input_stats.push((path, stats));
+            }
+            Err(error) => {
+                eprintln!("Error in {}: {}", path.display(), error);
+                write_stats(&payment_engine, &args);
+                // Commits the SQLite batch and removes the spill index, which exiting would skip
+                drop(payment_engine);
+                std::process::exit(1);
+            }
+        }
+    }
+    payment_engine.log_pending_review();
+    write_stats(&payment_engine, &args);
+    // Output the payment engine's results to stdout
+    let output_options = OutputOptions {
+        format: args.format,
+        sort_by: args.sort_by,
+        descending: args.descending,
+        only_locked: args.only_locked,
+        non_zero: args.non_zero,
+        columns: args.columns,
+        csv_dialect: CsvDialect {
+            delimiter: args.output_delimiter,
+            has_headers: !args.output_no_headers,
+            ..CsvDialect::default()
+        },
+        external_ids: ids.clone(),
+    };
+    payment_engine
+        .output_accounts(std::io::stdout(), &output_options)
+        .expect("Unable to read the accounts");
+    if let Some(path) = args.account_details {
+        let details_file = File::create(path).expect("Unable to create account details file");
+        payment_engine
+            .output_account_details(details_file)
+            .expect("Unable to read the account details");
+    }
+    if let Some(path) = args.lock_history {
+        let history_file = File::create(path).expect("Unable to create lock history file");
+        payment_engine
+            .output_lock_history(history_file)
+            .expect("Unable to read the lock history");
+    }
+    if let (Some(path), Some(ids)) = (args.id_map, &ids) {
+        let map_file = File::create(path).expect("Unable to create ID map file");
+        let _ = ids.write_csv(map_file);
+    }
+    if args.check_invariants.is_some() {
+        payment_engine.check_invariants();
+    }
+    let violations = payment_engine.invariant_violations();
+    for violation in violations {
+        eprintln!("Invariant violated: {}", violation);
+    }
+    let violated = !violations.is_empty();
+    if let Some(path) = args.input_stats {
+        let stats_file = File::create(path).expect("Unable to create input stats file");
+        let mut csv_writer = csv::Writer::from_writer(stats_file);
+        let _ = csv_writer.write_record([
+            "input",
+            "records",
+            "applied",
+            "held",
+            "parse_errors",
+            "rejected",
+        ]);
+        for (path, stats) in input_stats {
+            let _ = csv_writer.serialize((
+                path.display().to_string(),
+                stats.records,
+                stats.applied,
+                stats.held,
+                stats.parse_errors,
+                stats.rejected,
+            ));
+        }
+    }
+    if violated {
+        // Removes the spill index, which exiting would leave behind
+        drop(payment_engine);
+        std::process::exit(1);
+    }
 }
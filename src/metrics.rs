@@ -0,0 +1,337 @@
+use crate::accounts::{StorageGauges, UpdateEvent, UpdateHook, UpdateOutcome};
+use crate::stats::labels;
+use crate::transactions::TransactionType;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the buckets of the update latency histograms
+const LATENCY_BUCKETS: [f64; 10] = [
+    1e-6, 2.5e-6, 5e-6, 1e-5, 2.5e-5, 5e-5, 1e-4, 2.5e-4, 1e-3, 1e-2,
+];
+
+/// Largest request accepted by the metrics endpoint
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Number of threads which accept and answer the connections of the metrics endpoint
+const ENDPOINT_THREADS: usize = 4;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    /// Number of transactions by type and outcome
+    transactions: BTreeMap<(String, &'static str), u64>,
+    /// Number of rejected transactions by type and reason
+    rejections: BTreeMap<(String, &'static str), u64>,
+    /// Processing latency by type
+    latency: BTreeMap<String, Histogram>,
+    gauges: StorageGauges,
+}
+
+/// Live metrics of a PaymentEngine, shared with the metrics endpoint. The engine records every
+/// transaction after its fraud decision, see `PaymentEngine::set_metrics`. Registered as an
+/// UpdateHook of the client storage the metrics keep the storage gauges up to date
+#[derive(Clone, Default)]
+pub struct LiveMetrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+impl UpdateHook for LiveMetrics {
+    fn updated(&mut self, event: &UpdateEvent) {
+        self.state().gauges = event.gauges;
+    }
+}
+
+impl LiveMetrics {
+    fn state(&self) -> MutexGuard<'_, MetricsState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Records the outcome of a transaction and the time spent processing it
+    pub fn record(
+        &self,
+        transaction_type: TransactionType,
+        outcome: UpdateOutcome,
+        latency: Duration,
+    ) {
+        let mut state = self.state();
+        let transaction_type = transaction_type.to_string();
+        let outcome = match outcome {
+            UpdateOutcome::Applied => "applied",
+            UpdateOutcome::Held => "held",
+            UpdateOutcome::Rejected(rejection) => {
+                *state
+                    .rejections
+                    .entry((transaction_type.clone(), rejection.as_str()))
+                    .or_default() += 1;
+                "rejected"
+            }
+        };
+        *state
+            .transactions
+            .entry((transaction_type.clone(), outcome))
+            .or_default() += 1;
+        state
+            .latency
+            .entry(transaction_type)
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let state = self.state();
+        let mut output = String::new();
+        let header = |output: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(output, "# HELP payment_engine_{} {}", name, help);
+            let _ = writeln!(output, "# TYPE payment_engine_{} {}", name, kind);
+        };
+
+        header(
+            &mut output,
+            "transactions_total",
+            "counter",
+            "Transactions by type and outcome",
+        );
+        for ((transaction_type, outcome), count) in &state.transactions {
+            let labels = labels(&[("type", transaction_type), ("outcome", outcome)]);
+            let _ = writeln!(
+                output,
+                "payment_engine_transactions_total{} {}",
+                labels, count
+            );
+        }
+        header(
+            &mut output,
+            "rejections_total",
+            "counter",
+            "Rejected transactions by type and reason",
+        );
+        for ((transaction_type, reason), count) in &state.rejections {
+            let labels = labels(&[("type", transaction_type), ("reason", reason)]);
+            let _ = writeln!(
+                output,
+                "payment_engine_rejections_total{} {}",
+                labels, count
+            );
+        }
+
+        let gauges = [
+            ("clients", "Number of clients", state.gauges.clients as f64),
+            (
+                "open_disputes",
+                "Disputes which have been neither resolved nor charged back",
+                state.gauges.open_disputes as f64,
+            ),
+            (
+                "locked_accounts",
+                "Number of locked accounts",
+                state.gauges.locked_accounts as f64,
+            ),
+            (
+                "held_funds",
+                "Sum of the held funds of all the accounts",
+                state.gauges.held_funds,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut output, name, "gauge", help);
+            let _ = writeln!(output, "payment_engine_{} {}", name, value);
+        }
+
+        let name = "payment_engine_update_latency_seconds";
+        header(
+            &mut output,
+            "update_latency_seconds",
+            "histogram",
+            "Time spent processing a transaction by type",
+        );
+        for (transaction_type, histogram) in &state.latency {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let labels = labels(&[("type", transaction_type), ("le", &bound.to_string())]);
+                let _ = writeln!(output, "{}_bucket{} {}", name, labels, count);
+            }
+            let labels_inf = labels(&[("type", transaction_type), ("le", "+Inf")]);
+            let _ = writeln!(output, "{}_bucket{} {}", name, labels_inf, histogram.count);
+            let type_labels = labels(&[("type", transaction_type)]);
+            let _ = writeln!(output, "{}_sum{} {}", name, type_labels, histogram.sum);
+            let _ = writeln!(output, "{}_count{} {}", name, type_labels, histogram.count);
+        }
+        output
+    }
+}
+
+/// Answers a single HTTP request of the metrics endpoint
+fn respond(mut stream: TcpStream, metrics: &LiveMetrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() > MAX_REQUEST_LEN {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request_line = request.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+    let mut parts = std::str::from_utf8(request_line)
+        .unwrap_or("")
+        .split_whitespace();
+    let method = parts.next();
+    let path = parts.next().and_then(|path| path.split('?').next());
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serves the given metrics in the Prometheus text format on `GET /metrics` from a fixed pool
+/// of background threads. Each thread accepts and answers one connection at a time, so that a
+/// slow or idle client holds up only one of them. Returns the address the endpoint is
+/// listening on
+pub fn serve_metrics(
+    address: impl ToSocketAddrs,
+    metrics: LiveMetrics,
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    for _ in 0..ENDPOINT_THREADS {
+        let (listener, metrics) = (listener.try_clone()?, metrics.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| respond(stream, &metrics));
+                if let Err(error) = result {
+                    log::warn!("Metrics request failed: {}", error);
+                }
+            }
+        });
+    }
+    log::info!("Serving metrics on http://{}/metrics", address);
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::ClientInfoStorage;
+    use crate::engine::PaymentEngine;
+    use crate::fraud::{ClientHistory, FraudScorer, Verdict};
+    use crate::transactions::{DepositInfo, DisputeInfo, Transaction, WithdrawalInfo};
+
+    /// Holds the deposits of the given amount for review
+    struct HoldAmount(f32);
+
+    impl FraudScorer for HoldAmount {
+        fn score(&mut self, transaction: &Transaction, _history: &ClientHistory) -> Verdict {
+            match transaction {
+                Transaction::Deposit(info) if info.amount == self.0 => {
+                    Verdict::Hold("amount".to_string())
+                }
+                _ => Verdict::Approve,
+            }
+        }
+    }
+
+    fn engine_with_metrics() -> LiveMetrics {
+        let metrics = LiveMetrics::default();
+        let mut engine = PaymentEngine::with(ClientInfoStorage::new(), Box::new(HoldAmount(7.0)));
+        engine.set_metrics(metrics.clone());
+        engine.apply(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 2.0,
+        }));
+        engine.apply(Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 2,
+            amount: 3.0,
+        }));
+        engine.apply(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        engine.apply(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 3,
+            amount: 7.0,
+        }));
+        metrics
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let output = engine_with_metrics().render();
+        assert!(output.contains(
+            "payment_engine_transactions_total{type=\"deposit\",outcome=\"applied\"} 1\n"
+        ));
+        assert!(output.contains(
+            "payment_engine_rejections_total{type=\"withdrawal\",reason=\"insufficient_funds\"} 1\n"
+        ));
+        assert!(output.contains("payment_engine_open_disputes 1\n"));
+        assert!(output.contains("payment_engine_held_funds 2\n"));
+        assert!(output.contains("# TYPE payment_engine_update_latency_seconds histogram\n"));
+        assert!(output.contains(
+            "payment_engine_update_latency_seconds_bucket{type=\"dispute\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(
+            output.contains("payment_engine_update_latency_seconds_count{type=\"deposit\"} 2\n")
+        );
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let address = serve_metrics("127.0.0.1:0", engine_with_metrics()).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("payment_engine_locked_accounts 0\n"));
+        // Transactions held by the fraud scorer never reach the storage but are counted
+        assert!(response
+            .contains("payment_engine_transactions_total{type=\"deposit\",outcome=\"held\"} 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // An idle connection does not delay the requests of other clients
+        let _idle = TcpStream::connect(address).unwrap();
+        let started = std::time::Instant::now();
+        assert!(get("/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::accounts::CsvAccount;
+use crate::dialect::CsvDialect;
+use crate::ids::ExternalIds;
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// The format of the accounts output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One CSV record per account, preceded by a header
+    Csv,
+    /// A JSON array with one object per account
+    Json,
+    /// One JSON object per account and line
+    JsonLines,
+    /// A JSON object with one array of values per column. This is plain JSON in a column
+    /// oriented layout, not Parquet or Arrow
+    JsonColumns,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "json-lines" => Ok(OutputFormat::JsonLines),
+            "json-columns" => Ok(OutputFormat::JsonColumns),
+            _ => Err(format!("Unknown output format: {}", format)),
+        }
+    }
+}
+
+/// A column of the accounts output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+/// All the columns of the accounts output in their default order
+pub const ALL_COLUMNS: [AccountColumn; 5] = [
+    AccountColumn::Client,
+    AccountColumn::Available,
+    AccountColumn::Held,
+    AccountColumn::Total,
+    AccountColumn::Locked,
+];
+
+impl AccountColumn {
+    /// Returns the name of the column as it appears in the output header
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountColumn::Client => "client",
+            AccountColumn::Available => "available",
+            AccountColumn::Held => "held",
+            AccountColumn::Total => "total",
+            AccountColumn::Locked => "locked",
+        }
+    }
+
+    fn serialize_value<S: Serializer>(
+        &self,
+        account: &CsvAccount,
+        ids: Option<&ExternalIds>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match self {
+            AccountColumn::Client => match ids.and_then(|ids| ids.client_name(account.client())) {
+                Some(name) => name.serialize(serializer),
+                None => account.client().serialize(serializer),
+            },
+            AccountColumn::Available => account.available().serialize(serializer),
+            AccountColumn::Held => account.held().serialize(serializer),
+            AccountColumn::Total => account.total().serialize(serializer),
+            AccountColumn::Locked => account.locked().serialize(serializer),
+        }
+    }
+
+    fn compare(&self, a: &CsvAccount, b: &CsvAccount, ids: Option<&ExternalIds>) -> Ordering {
+        match self {
+            AccountColumn::Client => match ids {
+                Some(ids) => ids
+                    .client_name(a.client())
+                    .cmp(&ids.client_name(b.client())),
+                None => a.client().cmp(&b.client()),
+            },
+            AccountColumn::Available => a.available().total_cmp(&b.available()),
+            AccountColumn::Held => a.held().total_cmp(&b.held()),
+            AccountColumn::Total => a.total().total_cmp(&b.total()),
+            AccountColumn::Locked => a.locked().cmp(&b.locked()),
+        }
+    }
+}
+
+impl FromStr for AccountColumn {
+    type Err = String;
+    fn from_str(column: &str) -> Result<Self, Self::Err> {
+        ALL_COLUMNS
+            .iter()
+            .find(|candidate| candidate.name().eq_ignore_ascii_case(column.trim()))
+            .copied()
+            .ok_or_else(|| format!("Unknown account column: {}", column))
+    }
+}
+
+/// A single value of an account
+struct Cell<'a>(AccountColumn, &'a CsvAccount, Option<&'a ExternalIds>);
+
+impl Serialize for Cell<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_value(self.1, self.2, serializer)
+    }
+}
+
+/// An account with the selected columns, serialized as a record (CSV) or an object (JSON)
+struct Row<'a> {
+    columns: &'a [AccountColumn],
+    account: &'a CsvAccount,
+    ids: Option<&'a ExternalIds>,
+}
+
+impl Row<'_> {
+    fn cells(&self) -> Vec<Cell<'_>> {
+        self.columns
+            .iter()
+            .map(|column| Cell(*column, self.account, self.ids))
+            .collect()
+    }
+}
+
+impl Serialize for Row<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for column in self.columns {
+            map.serialize_entry(column.name(), &Cell(*column, self.account, self.ids))?;
+        }
+        map.end()
+    }
+}
+
+/// All the values of a single column
+struct Column<'a> {
+    column: AccountColumn,
+    accounts: &'a [CsvAccount],
+    ids: Option<&'a ExternalIds>,
+}
+
+impl Serialize for Column<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.accounts.len()))?;
+        for account in self.accounts {
+            seq.serialize_element(&Cell(self.column, account, self.ids))?;
+        }
+        seq.end()
+    }
+}
+
+/// The selected columns of all the accounts, serialized column by column
+struct Columns<'a> {
+    columns: &'a [AccountColumn],
+    accounts: &'a [CsvAccount],
+    ids: Option<&'a ExternalIds>,
+}
+
+impl Serialize for Columns<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for column in self.columns {
+            let values = Column {
+                column: *column,
+                accounts: self.accounts,
+                ids: self.ids,
+            };
+            map.serialize_entry(column.name(), &values)?;
+        }
+        map.end()
+    }
+}
+
+/// Controls the format, the order, the rows and the columns of the accounts output
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    /// Format of the output
+    pub format: OutputFormat,
+    /// Column the accounts are sorted by. Ties are broken by the client ID
+    pub sort_by: AccountColumn,
+    /// Sort in descending instead of ascending order
+    pub descending: bool,
+    /// Output only locked accounts
+    pub only_locked: bool,
+    /// Output only accounts with a non-zero available, held or total amount
+    pub non_zero: bool,
+    /// Columns to output, in this order. All columns are output if empty
+    pub columns: Vec<AccountColumn>,
+    /// Delimiter, quoting and header of the CSV output. Column names are ignored
+    pub csv_dialect: CsvDialect,
+    /// Output the external client IDs the accounts have been read with and sort by them
+    pub external_ids: Option<ExternalIds>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Csv,
+            sort_by: AccountColumn::Client,
+            descending: false,
+            only_locked: false,
+            non_zero: false,
+            columns: Vec::new(),
+            csv_dialect: CsvDialect::default(),
+            external_ids: None,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Filters and sorts the given accounts
+    pub fn apply(&self, mut accounts: Vec<CsvAccount>) -> Vec<CsvAccount> {
+        accounts.retain(|account| {
+            (!self.only_locked || account.locked())
+                && (!self.non_zero
+                    || account.available() != 0.0
+                    || account.held() != 0.0
+                    || account.total() != 0.0)
+        });
+        accounts.sort_by(|a, b| {
+            let ordering = self
+                .sort_by
+                .compare(a, b, self.external_ids.as_ref())
+                .then_with(|| a.client().cmp(&b.client()));
+            if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        accounts
+    }
+
+    /// Returns the columns to output
+    pub fn columns(&self) -> &[AccountColumn] {
+        if self.columns.is_empty() {
+            &ALL_COLUMNS
+        } else {
+            &self.columns
+        }
+    }
+
+    /// Writes the given accounts in the selected format
+    pub fn write(&self, accounts: Vec<CsvAccount>, mut writer: impl std::io::Write) {
+        let columns = self.columns();
+        let accounts = self.apply(accounts);
+        let ids = self.external_ids.as_ref();
+        let rows = accounts.iter().map(|account| Row {
+            columns,
+            account,
+            ids,
+        });
+        match self.format {
+            OutputFormat::Csv => {
+                let mut csv_writer = self.csv_dialect.writer_builder().from_writer(writer);
+                if self.csv_dialect.has_headers {
+                    let _ = csv_writer.write_record(columns.iter().map(|column| column.name()));
+                }
+                for row in rows {
+                    let _ = csv_writer.serialize(row.cells());
+                }
+            }
+            OutputFormat::Json => {
+                let rows: Vec<Row> = rows.collect();
+                let _ = serde_json::to_writer(&mut writer, &rows);
+                let _ = writeln!(writer);
+            }
+            OutputFormat::JsonLines => {
+                for row in rows {
+                    let _ = serde_json::to_writer(&mut writer, &row);
+                    let _ = writeln!(writer);
+                }
+            }
+            OutputFormat::JsonColumns => {
+                let columns = Columns {
+                    columns,
+                    accounts: &accounts,
+                    ids,
+                };
+                let _ = serde_json::to_writer(&mut writer, &columns);
+                let _ = writeln!(writer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::ClientInfoStorage;
+    use crate::transactions::{
+        ChargeBackInfo, ClientId, DepositInfo, DisputeInfo, Transaction, WithdrawalInfo,
+    };
+
+    fn accounts() -> Vec<CsvAccount> {
+        let mut client_storage = ClientInfoStorage::new();
+        let transactions = vec![
+            Transaction::Deposit(DepositInfo {
+                client: 3,
+                tx: 1,
+                amount: 1.0,
+            }),
+            Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx: 2,
+                amount: 3.0,
+            }),
+            Transaction::Deposit(DepositInfo {
+                client: 2,
+                tx: 3,
+                amount: 2.0,
+            }),
+            Transaction::Withdrawal(WithdrawalInfo {
+                client: 2,
+                tx: 4,
+                amount: 2.0,
+            }),
+            Transaction::Dispute(DisputeInfo { client: 1, tx: 2 }),
+            Transaction::ChargeBack(ChargeBackInfo { client: 1, tx: 2 }),
+        ];
+        for transaction in transactions {
+            client_storage.update(transaction);
+        }
+        client_storage.get_csv_format_accounts()
+    }
+
+    fn clients(accounts: &[CsvAccount]) -> Vec<ClientId> {
+        accounts.iter().map(|account| account.client()).collect()
+    }
+
+    #[test]
+    fn test_sort_and_filter() {
+        let options = OutputOptions::default();
+        assert_eq!(clients(&options.apply(accounts())), vec![1, 2, 3]);
+
+        let options = OutputOptions {
+            sort_by: AccountColumn::Total,
+            descending: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(clients(&options.apply(accounts())), vec![3, 2, 1]);
+
+        let options = OutputOptions {
+            non_zero: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(clients(&options.apply(accounts())), vec![3]);
+
+        let options = OutputOptions {
+            only_locked: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(clients(&options.apply(accounts())), vec![1]);
+    }
+
+    #[test]
+    fn test_select_columns() {
+        let options = OutputOptions {
+            columns: vec!["total".parse().unwrap(), "Client".parse().unwrap()],
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        options.write(accounts(), &mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "total,client\n0.0,1\n0.0,2\n1.0,3\n"
+        );
+        assert!("balance".parse::<AccountColumn>().is_err());
+    }
+
+    #[test]
+    fn test_external_client_ids() {
+        let ids = ExternalIds::new();
+        for name in ["zed", "amy", "bob"] {
+            ids.client_id(name);
+        }
+        let options = OutputOptions {
+            columns: vec![AccountColumn::Client, AccountColumn::Total],
+            external_ids: Some(ids),
+            ..OutputOptions::default()
+        };
+        assert_eq!(clients(&options.apply(accounts())), vec![2, 3, 1]);
+        let mut output = Vec::new();
+        options.write(accounts(), &mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,total\namy,0.0\nbob,1.0\nzed,0.0\n"
+        );
+        let options = OutputOptions {
+            format: OutputFormat::JsonLines,
+            columns: vec![AccountColumn::Client],
+            ..options
+        };
+        let mut output = Vec::new();
+        options.write(accounts(), &mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"client\":\"amy\"}\n{\"client\":\"bob\"}\n{\"client\":\"zed\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_dialect() {
+        let options = OutputOptions {
+            columns: vec![AccountColumn::Client, AccountColumn::Total],
+            csv_dialect: CsvDialect {
+                delimiter: b';',
+                has_headers: false,
+                ..CsvDialect::default()
+            },
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        options.write(accounts(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "1;0.0\n2;0.0\n3;1.0\n");
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let output = |format: &str| {
+            let options = OutputOptions {
+                format: format.parse().unwrap(),
+                columns: vec![AccountColumn::Client, AccountColumn::Locked],
+                ..OutputOptions::default()
+            };
+            let mut output = Vec::new();
+            options.write(accounts(), &mut output);
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            output("json"),
+            "[{\"client\":1,\"locked\":true},{\"client\":2,\"locked\":false},\
+             {\"client\":3,\"locked\":false}]\n"
+        );
+        assert_eq!(
+            output("jsonl"),
+            "{\"client\":1,\"locked\":true}\n{\"client\":2,\"locked\":false}\n\
+             {\"client\":3,\"locked\":false}\n"
+        );
+        assert_eq!(
+            output("json-columns"),
+            "{\"client\":[1,2,3],\"locked\":[true,false,false]}\n"
+        );
+        assert!("columnar".parse::<OutputFormat>().is_err());
+        assert!("parquet".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json_amounts() {
+        let options = OutputOptions {
+            format: OutputFormat::JsonLines,
+            columns: vec![AccountColumn::Available],
+            non_zero: true,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        options.write(accounts(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"available\":1.0}\n");
+    }
+}
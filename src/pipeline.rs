@@ -0,0 +1,331 @@
+use crate::dialect::CsvDialect;
+use crate::transactions::{csv_headers, read_csv_chunk, Transaction, TransactionError};
+use csv_core::ReadRecordResult;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+type TransactionResult = Result<Transaction, TransactionError>;
+
+/// Controls the threads and the buffering of a pipelined input
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineOptions {
+    /// Number of threads parsing the chunks of a CSV input
+    pub parsers: usize,
+    /// Number of transactions sent to the engine at once by a single parser thread
+    pub batch_size: usize,
+    /// Approximate size in bytes of the chunks of a CSV input parsed in parallel
+    pub chunk_size: usize,
+    /// Number of batches or chunks a parser thread may be ahead of the engine. A parser
+    /// blocks when it is this far ahead, so that memory stays bounded
+    pub capacity: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            parsers: 1,
+            batch_size: 4096,
+            chunk_size: 1 << 20,
+            capacity: 4,
+        }
+    }
+}
+
+/// Receives the batches of transactions of the parser threads and yields them in input order.
+/// A panic of a parser thread is resumed once its transactions are exhausted
+pub struct PipelinedTransactions {
+    receivers: Vec<Receiver<Vec<TransactionResult>>>,
+    next: usize,
+    batch: std::vec::IntoIter<TransactionResult>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PipelinedTransactions {
+    fn new(receivers: Vec<Receiver<Vec<TransactionResult>>>, threads: Vec<JoinHandle<()>>) -> Self {
+        Self {
+            receivers,
+            next: 0,
+            batch: Vec::new().into_iter(),
+            threads,
+        }
+    }
+
+    fn join(&mut self) {
+        // Parser threads blocked on sending to a full channel only stop once the receivers are
+        // gone, e.g. when another parser thread panicked
+        self.receivers.clear();
+        for thread in self.threads.drain(..) {
+            if let Err(panic) = thread.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+impl Iterator for PipelinedTransactions {
+    type Item = TransactionResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(transaction) = self.batch.next() {
+                return Some(transaction);
+            }
+            // Batches are dealt round-robin to the receivers
+            let receiver = self.receivers.get(self.next)?;
+            match receiver.recv() {
+                Ok(batch) => self.batch = batch.into_iter(),
+                Err(_) => {
+                    self.join();
+                    return None;
+                }
+            }
+            self.next = (self.next + 1) % self.receivers.len();
+        }
+    }
+}
+
+/// Reads the transactions of the input opened by `open` on a parser thread, which sends them
+/// to the returned iterator in batches over a bounded channel. The input is opened on the
+/// parser thread, so that it does not have to be `Send`
+pub fn pipelined<I>(
+    open: impl FnOnce() -> I + Send + 'static,
+    options: &PipelineOptions,
+) -> PipelinedTransactions
+where
+    I: Iterator<Item = TransactionResult>,
+{
+    let (sender, receiver) = sync_channel(options.capacity);
+    let batch_size = options.batch_size.max(1);
+    let thread = std::thread::spawn(move || {
+        let mut transactions = open();
+        loop {
+            let batch: Vec<_> = transactions.by_ref().take(batch_size).collect();
+            // The receiver is gone if the engine aborted the input
+            if batch.is_empty() || sender.send(batch).is_err() {
+                break;
+            }
+        }
+    });
+    PipelinedTransactions::new(vec![receiver], vec![thread])
+}
+
+/// A chunk of CSV input: the position of its first record and the offset of its end
+type Chunk = (csv::Position, u64);
+
+/// Splits CSV input into chunks of at least `chunk_size` bytes. The chunks end after a line
+/// feed which terminates a record, as found by the parser of the CSV reader, so that a reader
+/// can continue at the start of every chunk
+struct CsvChunks<T> {
+    input: Arc<T>,
+    parser: csv_core::Reader,
+    start: csv::Position,
+    chunk_size: u64,
+}
+
+impl<T: AsRef<[u8]>> Iterator for CsvChunks<T> {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = (*self.input).as_ref();
+        let mut end = self.start.clone();
+        if end.byte() >= input.len() as u64 && end.byte() > 0 {
+            return None;
+        }
+        // Only the record boundaries are of interest, not the fields
+        let (mut fields, mut ends) = ([0; 1024], [0; 64]);
+        loop {
+            let (result, read, _, _) =
+                self.parser
+                    .read_record(&input[end.byte() as usize..], &mut fields, &mut ends);
+            end.set_byte(end.byte() + read as u64);
+            match result {
+                ReadRecordResult::Record => {
+                    end.set_record(end.record() + 1);
+                    end.set_line(self.parser.line());
+                    let at_line_feed = input[..end.byte() as usize].last() == Some(&b'\n');
+                    if at_line_feed && end.byte() - self.start.byte() >= self.chunk_size {
+                        break;
+                    }
+                }
+                ReadRecordResult::End => {
+                    end.set_byte(input.len() as u64 + 1);
+                    break;
+                }
+                ReadRecordResult::InputEmpty
+                | ReadRecordResult::OutputFull
+                | ReadRecordResult::OutputEndsFull => {}
+            }
+        }
+        let start = std::mem::replace(&mut self.start, end.clone());
+        Some((start, end.byte()))
+    }
+}
+
+/// Reads the transactions of CSV input in parallel: the input is split into chunks at record
+/// boundaries, which `parsers` threads read while the returned iterator yields their
+/// transactions in input order. The transactions and errors, including their positions, are
+/// the same as when reading the input sequentially
+pub fn read_csv_parallel<T>(
+    input: T,
+    dialect: &CsvDialect,
+    options: &PipelineOptions,
+) -> PipelinedTransactions
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    let input = Arc::new(input);
+    let parsers = options.parsers.max(1);
+    // Without a readable header the input is read as a single chunk, which reports the error
+    let chunk_size = match csv_headers((*input).as_ref(), dialect) {
+        Some(_) => options.chunk_size.max(1) as u64,
+        None => u64::MAX,
+    };
+    let chunks = CsvChunks {
+        input: input.clone(),
+        parser: dialect.core_reader(),
+        start: csv::Position::new(),
+        chunk_size,
+    };
+    let mut chunk_senders: Vec<SyncSender<Chunk>> = Vec::new();
+    let mut receivers = Vec::new();
+    let mut threads = Vec::new();
+    for _ in 0..parsers {
+        let (chunk_sender, chunk_receiver) = sync_channel::<Chunk>(options.capacity);
+        let (sender, receiver) = sync_channel(options.capacity);
+        let (input, dialect) = (input.clone(), dialect.clone());
+        threads.push(std::thread::spawn(move || {
+            for (start, end) in chunk_receiver {
+                let transactions = read_csv_chunk((*input).as_ref(), &dialect, &start, end);
+                if sender.send(transactions).is_err() {
+                    break;
+                }
+            }
+        }));
+        chunk_senders.push(chunk_sender);
+        receivers.push(receiver);
+    }
+    // The chunks are dealt round-robin, in the order the receivers are read
+    threads.push(std::thread::spawn(move || {
+        for (index, chunk) in chunks.enumerate() {
+            if chunk_senders[index % parsers].send(chunk).is_err() {
+                break;
+            }
+        }
+    }));
+    PipelinedTransactions::new(receivers, threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::read_transactions_with;
+
+    fn debug(transactions: impl Iterator<Item = TransactionResult>) -> Vec<String> {
+        transactions
+            .map(|transaction| format!("{:?}", transaction))
+            .collect()
+    }
+
+    #[test]
+    fn test_parser_panic_stops_other_parsers() {
+        let (panicking_sender, panicking_receiver) = sync_channel::<Vec<TransactionResult>>(0);
+        let (sender, receiver) = sync_channel(0);
+        let threads = vec![
+            std::thread::spawn(move || {
+                drop(panicking_sender);
+                panic!("parser failed");
+            }),
+            // Blocks on the full channel until the receiver is dropped
+            std::thread::spawn(move || while sender.send(Vec::new()).is_ok() {}),
+        ];
+        let mut transactions =
+            PipelinedTransactions::new(vec![panicking_receiver, receiver], threads);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| transactions.next()));
+        assert!(result.is_err());
+        assert!(transactions.next().is_none());
+    }
+
+    #[test]
+    fn test_pipelined_keeps_order() {
+        let input = std::fs::read("example_inputs/transactions.csv").unwrap();
+        let expected = debug(read_transactions_with(
+            input.as_slice(),
+            &CsvDialect::default(),
+        ));
+        let options = PipelineOptions {
+            batch_size: 2,
+            capacity: 1,
+            ..PipelineOptions::default()
+        };
+        let transactions = pipelined(
+            move || read_transactions_with(std::io::Cursor::new(input), &CsvDialect::default()),
+            &options,
+        );
+        assert_eq!(debug(transactions), expected);
+    }
+
+    #[test]
+    fn test_parallel_csv_matches_sequential() {
+        let mut input = b"type,client,tx,amount\n\ndeposit,1,1,1.0\n".to_vec();
+        for tx in 2..200 {
+            let record = match tx % 7 {
+                0 => format!("deposit,1,{},\n", tx),
+                1 => format!("\"deposit\",\"{}\",\"{}\",\"2.0\"\n", tx % 5, tx),
+                2 => format!("dispute,1,{},\"a\nquoted \"\"line\"\"\"\n", tx - 1),
+                3 => format!("withdrawal,{},{},0.5,extra\n", tx % 3, tx),
+                4 => "\r\n".to_string(),
+                _ => format!("deposit,{},{},1.5\r\n", tx % 4, tx),
+            };
+            input.extend_from_slice(record.as_bytes());
+        }
+        input.extend_from_slice(b"deposit,1,\xff,1.0\ndeposit,9,999,1.0");
+        let dialects = [
+            CsvDialect::default(),
+            CsvDialect {
+                has_headers: false,
+                ..CsvDialect::default()
+            },
+        ];
+        let mut with_bom = b"\xef\xbb\xbf".to_vec();
+        with_bom.extend_from_slice(&input);
+        let inputs = [
+            input,
+            with_bom,
+            b"type,client,tx,amount\n".to_vec(),
+            Vec::new(),
+        ];
+        for (input, dialect) in inputs
+            .iter()
+            .flat_map(|input| dialects.iter().map(move |dialect| (input, dialect)))
+        {
+            let expected = debug(read_transactions_with(input.as_slice(), dialect));
+            for (parsers, chunk_size) in [(1, 1), (3, 1), (4, 64), (2, 1 << 20)] {
+                let options = PipelineOptions {
+                    parsers,
+                    chunk_size,
+                    capacity: 1,
+                    ..PipelineOptions::default()
+                };
+                let transactions = read_csv_parallel(input.clone(), dialect, &options);
+                assert_eq!(debug(transactions), expected, "{:?}", options);
+            }
+        }
+    }
+
+    #[test]
+    fn test_engine_aborts_pipeline() {
+        let input = "type,client,tx,amount\n".to_string() + &"deposit,1,x,1.0\n".repeat(10000);
+        let options = PipelineOptions {
+            parsers: 2,
+            chunk_size: 64,
+            capacity: 1,
+            ..PipelineOptions::default()
+        };
+        let mut transactions =
+            read_csv_parallel(input.into_bytes(), &CsvDialect::default(), &options);
+        assert!(transactions.next().unwrap().is_err());
+        // Dropping the iterator stops the parser threads blocked on the full channels
+        drop(transactions);
+    }
+}
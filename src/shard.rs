@@ -0,0 +1,349 @@
+use crate::accounts::CsvAccount;
+use crate::dialect::TRANSACTION_COLUMNS;
+use crate::engine::CsvRejection;
+use crate::ids::ExternalIds;
+use crate::transactions::{ClientId, Transaction, TransactionError};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the shard of the given client among `shards` shards. The hash does not depend on
+/// the process, platform or version, so that every split of an input assigns a client to the
+/// same shard
+pub fn shard_of(client: ClientId, shards: usize) -> usize {
+    // Finalizer of SplitMix64, which also spreads consecutive client IDs evenly
+    let mut hash = client.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash % shards.max(1) as u64) as usize
+}
+
+/// Returns the shard of the given external client ID among `shards` shards. Like `shard_of`
+/// it does not depend on the order in which the IDs have been read
+pub fn shard_of_name(client: &str, shards: usize) -> usize {
+    // FNV-1a
+    let hash = client
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    shard_of(hash, shards)
+}
+
+/// Returns the path of the given shard file in the given directory
+pub fn shard_path(directory: &Path, shard: usize) -> PathBuf {
+    directory.join(format!("shard-{:04}.csv", shard))
+}
+
+/// Returns the path of the file in the given directory which the records that failed to parse
+/// are written to
+pub fn errors_path(directory: &Path) -> PathBuf {
+    directory.join("errors.csv")
+}
+
+/// A record which failed to parse while splitting, as written to the errors file
+#[derive(Serialize, Debug)]
+pub struct CsvSplitError<'a> {
+    /// Input file of the record
+    pub input: String,
+    /// Number of the record in the input, starting from 1
+    pub record: Option<u64>,
+    /// Line of the record, starting from 1
+    pub line: Option<u64>,
+    /// Field of the record which failed
+    pub field: Option<&'a str>,
+    /// Description of the error
+    pub error: String,
+    /// The record as read from the input
+    pub raw_record: Option<&'a str>,
+}
+
+impl<'a> CsvSplitError<'a> {
+    /// Describes the given parse error of a record of the given input
+    pub fn new(input: &Path, error: &'a TransactionError) -> Self {
+        let position = error.position();
+        Self {
+            input: input.display().to_string(),
+            record: position.map(|position| position.record),
+            line: position.and_then(|position| position.line),
+            field: error.field(),
+            error: error.to_string(),
+            raw_record: error.record(),
+        }
+    }
+}
+
+/// Writes transactions to shard files by their client, as CSV with the columns
+/// type,client,tx,amount,target which the engine reads by default
+pub struct ShardWriter<W: Write> {
+    writers: Vec<csv::Writer<W>>,
+    transactions: Vec<u64>,
+}
+
+impl ShardWriter<File> {
+    /// Creates the files of `shards` shards in the given directory, which is created if needed.
+    /// Existing shard files are replaced
+    pub fn create(directory: &Path, shards: usize) -> io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        let files = (0..shards.max(1))
+            .map(|shard| File::create(shard_path(directory, shard)))
+            .collect::<io::Result<_>>()?;
+        Ok(Self::new(files)?)
+    }
+}
+
+impl<W: Write> ShardWriter<W> {
+    /// Writes the header to every shard. The number of shards is the number of writers
+    pub fn new(shards: Vec<W>) -> csv::Result<Self> {
+        assert!(!shards.is_empty(), "at least one shard is required");
+        let mut writers: Vec<_> = shards.into_iter().map(csv::Writer::from_writer).collect();
+        for writer in &mut writers {
+            writer.write_record(TRANSACTION_COLUMNS)?;
+        }
+        let transactions = vec![0; writers.len()];
+        Ok(Self {
+            writers,
+            transactions,
+        })
+    }
+
+    /// Writes the transaction to the shard of its client and returns the shard
+    pub fn write(&mut self, transaction: &Transaction) -> csv::Result<usize> {
+        let shard = shard_of(transaction.client(), self.writers.len());
+        self.writers[shard].serialize((
+            transaction.transaction_type().name(),
+            transaction.client(),
+            transaction.tx(),
+            transaction.amount(),
+            transaction.target().map(|target| target.name()),
+        ))?;
+        self.transactions[shard] += 1;
+        Ok(shard)
+    }
+
+    /// Writes the transaction with the external IDs it has been read with to the shard of its
+    /// external client ID and returns the shard. The transaction ID is released, as the shard
+    /// keeps the external one
+    pub fn write_with_ids(
+        &mut self,
+        transaction: &Transaction,
+        ids: &ExternalIds,
+    ) -> csv::Result<usize> {
+        let client = ids.client_label(transaction.client());
+        let shard = shard_of_name(&client, self.writers.len());
+        self.writers[shard].serialize((
+            transaction.transaction_type().name(),
+            &client,
+            ids.transaction_label(transaction.tx()),
+            transaction.amount(),
+            transaction.target().map(|target| target.name()),
+        ))?;
+        ids.release_transaction(transaction.tx());
+        self.transactions[shard] += 1;
+        Ok(shard)
+    }
+
+    /// Flushes the shards and returns the number of transactions written to each of them
+    pub fn finish(mut self) -> csv::Result<Vec<u64>> {
+        for writer in &mut self.writers {
+            writer.flush()?;
+        }
+        Ok(self.transactions)
+    }
+}
+
+/// Reads the account outputs of the shards, CSV with a header and all the columns, and
+/// returns their accounts sorted by client. Fails if a client appears in more than one
+/// output, as the outputs then do not come from shards split by client
+pub fn merge_accounts<R: Read>(
+    outputs: impl IntoIterator<Item = R>,
+) -> io::Result<Vec<CsvAccount>> {
+    let mut accounts = Vec::new();
+    let mut clients = HashSet::new();
+    for output in outputs {
+        for account in csv::Reader::from_reader(output).deserialize() {
+            let account: CsvAccount = account?;
+            if !clients.insert(account.client()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("client {} appears in more than one shard", account.client()),
+                ));
+            }
+            accounts.push(account);
+        }
+    }
+    accounts.sort_by_key(CsvAccount::client);
+    Ok(accounts)
+}
+
+/// Reads the rejection reports of the shards and returns their rejections sorted by client.
+/// The rejections of a client keep their order, which is the order of the input. The reports
+/// do not record the input position, so unlike the report of a single run the rejections of
+/// different clients are not interleaved in input order
+pub fn merge_rejections<R: Read>(
+    reports: impl IntoIterator<Item = R>,
+) -> io::Result<Vec<CsvRejection>> {
+    let mut rejections = Vec::new();
+    for report in reports {
+        for rejection in csv::Reader::from_reader(report).deserialize() {
+            rejections.push(rejection?);
+        }
+    }
+    rejections.sort_by_key(|rejection: &CsvRejection| rejection.client);
+    Ok(rejections)
+}
+
+/// Writes merged rejections in the format of the rejection report
+pub fn write_rejections(rejections: &[CsvRejection], writer: impl Write) -> csv::Result<()> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for rejection in rejections {
+        csv_writer.serialize(rejection)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::output::OutputOptions;
+    use crate::transactions::read_transactions;
+
+    #[test]
+    fn test_shard_of() {
+        // The assignment must never change, or shards split by different versions disagree
+        let shards: Vec<usize> = (1..=8).map(|client| shard_of(client, 4)).collect();
+        assert_eq!(shards, [1, 2, 1, 2, 2, 0, 3, 2]);
+        assert!((0..1000).all(|client| shard_of(client, 7) < 7));
+        assert_eq!(shard_of(u64::MAX, 1), 0);
+        assert_eq!(
+            shard_path(Path::new("shards"), 3),
+            PathBuf::from("shards/shard-0003.csv")
+        );
+    }
+
+    /// Runs the engine on the given input and returns its accounts output and rejection report
+    fn run(input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let report = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::new();
+        engine.set_rejection_report(Box::new(SharedBuffer(report.clone())));
+        engine
+            .run_input(
+                read_transactions(input),
+                crate::engine::ErrorLimit::Unlimited,
+            )
+            .unwrap();
+        let mut accounts = Vec::new();
+        engine.output_to_csv_format(&mut accounts);
+        drop(engine);
+        let report = report.lock().unwrap().clone();
+        (accounts, report)
+    }
+
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_split_and_merge_matches_single_run() {
+        let input = std::fs::read("example_inputs/transactions_chargeback.csv").unwrap();
+        let mut input = String::from_utf8(input).unwrap();
+        input.push_str(
+            "withdrawal,3,90,5.0\ndeposit,4,91,1.5\ndispute,4,92,\nwithdrawal,4,93,2.0\n",
+        );
+        let (expected_accounts, expected_report) = run(input.as_bytes());
+
+        let mut writer = ShardWriter::new(vec![Vec::new(); 3]).unwrap();
+        for transaction in read_transactions(input.as_bytes()) {
+            writer.write(&transaction.unwrap()).unwrap();
+        }
+        let shards = writer
+            .writers
+            .into_iter()
+            .map(|writer| writer.into_inner().unwrap());
+        let (accounts, reports): (Vec<_>, Vec<_>) = shards.map(|shard| run(&shard)).unzip();
+
+        let accounts = merge_accounts(accounts.iter().map(Vec::as_slice)).unwrap();
+        let mut merged_accounts = Vec::new();
+        OutputOptions::default().write(accounts, &mut merged_accounts);
+        assert_eq!(merged_accounts, expected_accounts);
+
+        // The merged rejections are the rejections of the single run sorted by client
+        let rejections = merge_rejections(reports.iter().map(Vec::as_slice)).unwrap();
+        let mut expected_rejections: Vec<CsvRejection> =
+            csv::Reader::from_reader(expected_report.as_slice())
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert!(!expected_rejections.is_sorted_by_key(|rejection| rejection.client));
+        expected_rejections.sort_by_key(|rejection| rejection.client);
+        assert_eq!(rejections, expected_rejections);
+    }
+
+    #[test]
+    fn test_split_error() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\n";
+        let error = read_transactions(&input[..]).find_map(Result::err).unwrap();
+        let mut errors = csv::Writer::from_writer(Vec::new());
+        errors
+            .serialize(CsvSplitError::new(Path::new("in.csv"), &error))
+            .unwrap();
+        let errors = String::from_utf8(errors.into_inner().unwrap()).unwrap();
+        let mut lines = errors.lines();
+        assert_eq!(
+            lines.next(),
+            Some("input,record,line,field,error,raw_record")
+        );
+        let line = lines.next().unwrap();
+        assert!(line.starts_with("in.csv,2,3,client,"), "{}", line);
+        assert!(line.ends_with(",\"deposit,x,2,1.0\""), "{}", line);
+    }
+
+    #[test]
+    fn test_split_with_external_ids() {
+        let ids = ExternalIds::new();
+        let input = "type,client,tx,amount,target\n\
+                     deposit,alice,a-1,1.0,\n\
+                     dispute,alice,a-1,,\n\
+                     release,alice,a-1,,dispute\n";
+        let mut writer = ShardWriter::new(vec![Vec::new(); 2]).unwrap();
+        let transactions = crate::transactions::read_transactions_with_ids(
+            input.as_bytes(),
+            &crate::dialect::CsvDialect::default(),
+            Some(ids.clone()),
+        );
+        for transaction in transactions {
+            assert_eq!(
+                writer.write_with_ids(&transaction.unwrap(), &ids).unwrap(),
+                1
+            );
+        }
+        let shard = writer.writers.remove(1).into_inner().unwrap();
+        assert_eq!(String::from_utf8(shard).unwrap(), input);
+        // The assignment depends on the external ID only, not on the order the IDs are read in
+        let shards: Vec<usize> = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|client| shard_of_name(client, 4))
+            .collect();
+        assert_eq!(shards, [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_merge_rejects_overlapping_shards() {
+        let output = "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n";
+        let error = merge_accounts([output.as_bytes(), output.as_bytes()]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
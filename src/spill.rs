@@ -0,0 +1,754 @@
+use crate::accounts::Account;
+use crate::storage::{
+    DepositExpiry, DepositLog, DisputedDeposits, PrunedIds, RemovedDeposit, StorageBackend,
+};
+use crate::transactions::{Amount, ClientId, TransactionId};
+use lru::LruCache;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// Approximate memory used by a deposit in the cache, including the cache's bookkeeping
+pub const CACHED_DEPOSIT_BYTES: usize = 64;
+
+/// Size of a slot of the on-disk index: used flag, disputed flag, amount, client, tx,
+/// sequence and position
+const SLOT_LEN: usize = 40;
+
+/// Size of an entry of the deposit queue: sequence, client and tx
+const QUEUE_ENTRY_LEN: usize = 24;
+
+/// Number of slots of a new on-disk index
+const INITIAL_SLOTS: u64 = 1 << 16;
+
+type DepositKey = (ClientId, TransactionId);
+
+/// A deposit key of the deposit queue along with the sequence number it has been queued with
+type QueuedDeposit = (u64, DepositKey);
+
+/// Open addressing hash table of deposits in a file, keyed by client and transaction ID
+struct DiskIndex {
+    file: File,
+    path: PathBuf,
+    slots: u64,
+    used: u64,
+    /// One bit per slot which is set if the slot is used, so that probes stop at an empty
+    /// slot without reading it and new deposits find their slot without reading the file
+    occupied: Vec<u64>,
+}
+
+impl DiskIndex {
+    fn create(path: PathBuf, slots: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        // A sparse file of zeroes, i.e. of empty slots
+        file.set_len(slots * SLOT_LEN as u64)?;
+        Ok(Self {
+            file,
+            path,
+            slots,
+            used: 0,
+            occupied: vec![0; slots.div_ceil(64) as usize],
+        })
+    }
+
+    fn home_slot(&self, key: DepositKey) -> u64 {
+        // Fibonacci hashing; the number of slots is a power of two
+        let hash = key.1 ^ key.0.rotate_left(32);
+        hash.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - self.slots.trailing_zeros())
+    }
+
+    fn is_occupied(&self, slot: u64) -> bool {
+        self.occupied[(slot / 64) as usize] & (1 << (slot % 64)) != 0
+    }
+
+    fn read_slot(&self, slot: u64) -> io::Result<Option<(DepositKey, DepositLog)>> {
+        let mut bytes = [0; SLOT_LEN];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(slot * SLOT_LEN as u64))?;
+        file.read_exact(&mut bytes)?;
+        Ok(decode_slot(&bytes))
+    }
+
+    fn write_slot(&self, slot: u64, key: DepositKey, deposit: &DepositLog) -> io::Result<()> {
+        let mut bytes = [0; SLOT_LEN];
+        bytes[0] = 1;
+        bytes[1] = u8::from(deposit.disputed);
+        bytes[4..8].copy_from_slice(&deposit.amount.to_le_bytes());
+        bytes[8..16].copy_from_slice(&key.0.to_le_bytes());
+        bytes[16..24].copy_from_slice(&key.1.to_le_bytes());
+        bytes[24..32].copy_from_slice(&deposit.sequence.to_le_bytes());
+        bytes[32..40].copy_from_slice(&deposit.position.to_le_bytes());
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(slot * SLOT_LEN as u64))?;
+        file.write_all(&bytes)
+    }
+
+    /// Empties the given used slot. The deposits after it in the probe sequence move back
+    /// into the gap where they may, so that probes still find them without a tombstone.
+    /// Returns the moved deposits along with their new slots
+    fn remove(&mut self, slot: u64) -> io::Result<Vec<(DepositKey, u64)>> {
+        let distance = |from: u64, to: u64| (to + self.slots - from) % self.slots;
+        let mut moved = Vec::new();
+        let mut gap = slot;
+        let mut next = (slot + 1) % self.slots;
+        while self.is_occupied(next) {
+            if let Some((key, deposit)) = self.read_slot(next)? {
+                // A deposit may move unless its home slot lies between the gap and its slot
+                if distance(self.home_slot(key), next) >= distance(gap, next) {
+                    self.write_slot(gap, key, &deposit)?;
+                    moved.push((key, gap));
+                    gap = next;
+                }
+            }
+            next = (next + 1) % self.slots;
+        }
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(gap * SLOT_LEN as u64))?;
+        file.write_all(&[0; SLOT_LEN])?;
+        self.occupied[(gap / 64) as usize] &= !(1 << (gap % 64));
+        self.used -= 1;
+        Ok(moved)
+    }
+
+    /// Probes the used slots from the home slot of the given deposit. Returns the slot of the
+    /// deposit if it is stored, or else the first empty slot
+    fn probe(&self, key: DepositKey) -> io::Result<(u64, Option<DepositLog>)> {
+        let mut slot = self.home_slot(key);
+        while self.is_occupied(slot) {
+            if let Some((found, deposit)) = self.read_slot(slot)? {
+                if found == key {
+                    return Ok((slot, Some(deposit)));
+                }
+            }
+            slot = (slot + 1) % self.slots;
+        }
+        Ok((slot, None))
+    }
+
+    /// Returns the deposit along with its slot
+    fn get(&self, key: DepositKey) -> io::Result<Option<(u64, DepositLog)>> {
+        let (slot, found) = self.probe(key)?;
+        Ok(found.map(|deposit| (slot, deposit)))
+    }
+
+    /// Writes the deposit to the given slot, which already holds it, or to the slot found by
+    /// probing. Returns true if the index has grown, which moves the deposits to other slots
+    fn put(
+        &mut self,
+        key: DepositKey,
+        deposit: &DepositLog,
+        slot: Option<u64>,
+    ) -> io::Result<bool> {
+        let slot = match slot {
+            Some(slot) => slot,
+            None => self.probe(key)?.0,
+        };
+        self.write_slot(slot, key, deposit)?;
+        if self.is_occupied(slot) {
+            return Ok(false);
+        }
+        self.occupied[(slot / 64) as usize] |= 1 << (slot % 64);
+        self.used += 1;
+        // Keep the load factor below one half, so that probes stay short
+        if self.used * 2 > self.slots {
+            self.rebuild(self.slots * 2, |_, _| true)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Rehashes the deposits accepted by `keep` into a new file with the given number of
+    /// slots, which replaces the current one
+    fn rebuild(
+        &mut self,
+        slots: u64,
+        mut keep: impl FnMut(DepositKey, &DepositLog) -> bool,
+    ) -> io::Result<()> {
+        let mut rebuilt = DiskIndex::create(self.path.with_extension("rebuild"), slots)?;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut bytes = [0; SLOT_LEN];
+        for _ in 0..self.slots {
+            reader.read_exact(&mut bytes)?;
+            if let Some((key, deposit)) = decode_slot(&bytes) {
+                if keep(key, &deposit) {
+                    rebuilt.put(key, &deposit, None)?;
+                }
+            }
+        }
+        std::fs::rename(&rebuilt.path, &self.path)?;
+        rebuilt.path = self.path.clone();
+        *self = rebuilt;
+        Ok(())
+    }
+}
+
+fn decode_slot(bytes: &[u8; SLOT_LEN]) -> Option<(DepositKey, DepositLog)> {
+    if bytes[0] == 0 {
+        return None;
+    }
+    let amount = Amount::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let u64_at = |start: usize| {
+        let mut le_bytes = [0; 8];
+        le_bytes.copy_from_slice(&bytes[start..start + 8]);
+        u64::from_le_bytes(le_bytes)
+    };
+    let (client, tx): DepositKey = (u64_at(8), u64_at(16));
+    let deposit = DepositLog {
+        amount,
+        disputed: bytes[1] != 0,
+        sequence: u64_at(24),
+        position: u64_at(32),
+    };
+    Some(((client, tx), deposit))
+}
+
+/// The keys of the stored deposits in the order of their sequence numbers, in a file next to
+/// the index. Pruning reads the expired deposits from the head of the queue instead of
+/// scanning the index. Entries of deposits which have been replaced or removed since are
+/// skipped when read
+struct DepositQueue {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    /// Number of entries which have been read
+    head: u64,
+    /// Number of entries which have been written
+    len: u64,
+}
+
+impl DepositQueue {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            path,
+            head: 0,
+            len: 0,
+        })
+    }
+
+    fn push(&mut self, sequence: u64, key: DepositKey) -> io::Result<()> {
+        let mut bytes = [0; QUEUE_ENTRY_LEN];
+        bytes[0..8].copy_from_slice(&sequence.to_le_bytes());
+        bytes[8..16].copy_from_slice(&key.0.to_le_bytes());
+        bytes[16..24].copy_from_slice(&key.1.to_le_bytes());
+        self.writer.write_all(&bytes)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Empties the file once all the entries have been read, so that it stays small
+    fn compact(&mut self) -> io::Result<()> {
+        if self.head == self.len && self.len > 0 {
+            self.writer.flush()?;
+            self.writer.get_ref().set_len(0)?;
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.head = 0;
+            self.len = 0;
+        }
+        Ok(())
+    }
+
+    /// Returns the entries from the head on whose sequence number is below the given one and
+    /// moves the head past them
+    fn pop_before(&mut self, sequence: u64) -> io::Result<Vec<QueuedDeposit>> {
+        self.writer.flush()?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.head * QUEUE_ENTRY_LEN as u64))?;
+        let mut reader = BufReader::new(file);
+        let mut entries = Vec::new();
+        let mut bytes = [0; QUEUE_ENTRY_LEN];
+        while self.head < self.len {
+            reader.read_exact(&mut bytes)?;
+            let u64_at = |start: usize| {
+                let mut le_bytes = [0; 8];
+                le_bytes.copy_from_slice(&bytes[start..start + 8]);
+                u64::from_le_bytes(le_bytes)
+            };
+            if u64_at(0) >= sequence {
+                break;
+            }
+            entries.push((u64_at(0), (u64_at(8), u64_at(16))));
+            self.head += 1;
+        }
+        Ok(entries)
+    }
+}
+
+/// A cached deposit. Dirty deposits are written to the on-disk index when evicted
+#[derive(Clone, Copy)]
+struct CachedDeposit {
+    deposit: DepositLog,
+    dirty: bool,
+    /// Slot of the deposit in the on-disk index, if it is known to be stored there
+    slot: Option<u64>,
+}
+
+/// LRU cache of the deposit history in front of the on-disk index
+struct History {
+    cache: LruCache<DepositKey, CachedDeposit>,
+    index: DiskIndex,
+}
+
+impl History {
+    fn cache(&mut self, key: DepositKey, cached: CachedDeposit) -> io::Result<()> {
+        if let Some((evicted_key, evicted)) = self.cache.push(key, cached) {
+            // `push` also returns the replaced entry of the same key
+            if evicted_key != key && evicted.dirty {
+                let grown = self
+                    .index
+                    .put(evicted_key, &evicted.deposit, evicted.slot)?;
+                if grown {
+                    self.forget_slots();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the slots of the cached deposits after the index has been rebuilt
+    fn forget_slots(&mut self) {
+        for (_, cached) in self.cache.iter_mut() {
+            cached.slot = None;
+        }
+    }
+
+    fn get(&mut self, key: DepositKey) -> io::Result<Option<DepositLog>> {
+        if let Some(cached) = self.cache.get(&key) {
+            return Ok(Some(cached.deposit));
+        }
+        let found = self.index.get(key)?;
+        if let Some((slot, deposit)) = found {
+            self.cache(
+                key,
+                CachedDeposit {
+                    deposit,
+                    dirty: false,
+                    slot: Some(slot),
+                },
+            )?;
+        }
+        Ok(found.map(|(_, deposit)| deposit))
+    }
+}
+
+/// Previous values of what the open update has written, so that it can be rolled back
+#[derive(Default)]
+struct Undo {
+    accounts: Vec<(ClientId, Option<Account>)>,
+    /// Previous cached copy and disputed amount of the written deposits
+    deposits: Vec<(DepositKey, Option<CachedDeposit>, Option<Amount>)>,
+    pruned: Option<PruningUndo>,
+}
+
+/// The deposits removed by a pruning, along with the previous state of the deposit queue
+struct PruningUndo {
+    removed: Vec<RemovedDeposit>,
+    head: u64,
+    deferred: Vec<QueuedDeposit>,
+}
+
+/// Keeps the accounts in memory and the deposit history in an LRU cache of bounded size.
+/// Deposits evicted from the cache spill to an on-disk index, from which later disputes,
+/// resolves and charge-backs read them back. The index file is scratch space and is removed
+/// when the storage is dropped.
+///
+/// `rollback` restores the accounts and the cached deposits written by the failed update. An
+/// update writes at most one deposit, which stays in the cache until the update ends, so the
+/// deposits on disk never hold a write of an unfinished update. Deposits removed by a failed
+/// pruning are cached again.
+///
+/// Only the retention by sequence number is supported, which reads the expired deposits from
+/// the deposit queue
+pub struct SpillingStorage {
+    accounts: HashMap<ClientId, Account>,
+    history: RefCell<History>,
+    /// Amounts of the disputed deposits
+    disputed: DisputedDeposits,
+    /// IDs of the deposits removed by `remove_deposits`
+    pruned: PrunedIds,
+    queue: DepositQueue,
+    /// Expired deposits which were disputed when they have been read from the queue
+    deferred: Vec<QueuedDeposit>,
+    /// Writes of the open update
+    undo: Option<Undo>,
+}
+
+impl SpillingStorage {
+    /// Creates the storage with an on-disk index at the given path (replacing an existing
+    /// file) and a cache of the given number of deposits. The deposit queue is kept next to the
+    /// index, with the `queue` extension
+    pub fn create(index_path: impl AsRef<Path>, cached_deposits: usize) -> io::Result<Self> {
+        let index = DiskIndex::create(index_path.as_ref().to_path_buf(), INITIAL_SLOTS)?;
+        let queue = DepositQueue::create(index_path.as_ref().with_extension("queue"))?;
+        let capacity = NonZeroUsize::new(cached_deposits).unwrap_or(NonZeroUsize::MIN);
+        Ok(Self {
+            accounts: HashMap::new(),
+            history: RefCell::new(History {
+                cache: LruCache::new(capacity),
+                index,
+            }),
+            disputed: DisputedDeposits::default(),
+            pruned: PrunedIds::default(),
+            queue,
+            deferred: Vec::new(),
+            undo: None,
+        })
+    }
+
+    /// Creates the storage with a cache of as many deposits as fit into the given number of
+    /// bytes. The accounts are not part of the budget
+    pub fn with_memory_budget(index_path: impl AsRef<Path>, bytes: usize) -> io::Result<Self> {
+        Self::create(index_path, bytes / CACHED_DEPOSIT_BYTES)
+    }
+}
+
+impl Drop for SpillingStorage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.history.get_mut().index.path);
+        let _ = std::fs::remove_file(&self.queue.path);
+    }
+}
+
+impl StorageBackend for SpillingStorage {
+    fn account(&self, client: ClientId) -> io::Result<Option<Account>> {
+        Ok(self.accounts.get(&client).cloned())
+    }
+
+    fn put_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+        if let Some(undo) = &mut self.undo {
+            undo.accounts
+                .push((client, self.accounts.get(&client).cloned()));
+        }
+        self.accounts.entry(client).or_default().clone_from(account);
+        Ok(())
+    }
+
+    fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+        Ok(self
+            .accounts
+            .iter()
+            .map(|(client, account)| (*client, account.clone()))
+            .collect())
+    }
+
+    fn deposit(&self, client: ClientId, tx: TransactionId) -> io::Result<Option<DepositLog>> {
+        self.history.borrow_mut().get((client, tx))
+    }
+
+    fn put_deposit(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        deposit: &DepositLog,
+    ) -> io::Result<()> {
+        let key = (client, tx);
+        let history = self.history.get_mut();
+        let previous = history.cache.peek(&key).copied();
+        let disputed = self
+            .disputed
+            .set(client, tx, deposit.disputed.then_some(deposit.amount));
+        if let Some(undo) = &mut self.undo {
+            undo.deposits.push((key, previous, disputed));
+        }
+        // Disputes, resolves and charge-backs read the deposit first, so it is only queued
+        // when it is new
+        if previous.map(|cached| cached.deposit.sequence) != Some(deposit.sequence) {
+            self.queue.push(deposit.sequence, key)?;
+        }
+        // A deposit which is not cached is written to the slot found when it is evicted,
+        // so new deposits are not looked up on disk
+        history.cache(
+            key,
+            CachedDeposit {
+                deposit: *deposit,
+                dirty: true,
+                slot: previous.and_then(|cached| cached.slot),
+            },
+        )
+    }
+
+    fn open_disputes(&self) -> io::Result<usize> {
+        Ok(self.disputed.count())
+    }
+
+    fn disputed_amount(&self, client: ClientId) -> io::Result<f64> {
+        Ok(self.disputed.amount(client))
+    }
+
+    fn remove_deposits(&mut self, expiry: &DepositExpiry) -> io::Result<Vec<RemovedDeposit>> {
+        let sequence = match expiry {
+            DepositExpiry::Sequence(sequence) => *sequence,
+            DepositExpiry::Position(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The spilling storage only prunes by sequence number",
+                ))
+            }
+        };
+        self.queue.compact()?;
+        if let Some(undo) = &mut self.undo {
+            undo.pruned = Some(PruningUndo {
+                removed: Vec::new(),
+                head: self.queue.head,
+                deferred: self.deferred.clone(),
+            });
+        }
+        let mut candidates = std::mem::take(&mut self.deferred);
+        candidates.extend(self.queue.pop_before(sequence)?);
+        let mut removed = Vec::new();
+        let history = self.history.get_mut();
+        for (queued, key) in candidates {
+            let (slot, deposit) = match history.cache.peek(&key) {
+                Some(cached) => (cached.slot, cached.deposit),
+                None => match history.index.get(key)? {
+                    Some((slot, deposit)) => (Some(slot), deposit),
+                    None => continue,
+                },
+            };
+            // The deposit has been replaced since it has been queued
+            if deposit.sequence != queued {
+                continue;
+            }
+            if deposit.disputed {
+                self.deferred.push((queued, key));
+                continue;
+            }
+            // The slot of a cached deposit is not known after the index has grown
+            let slot = match slot {
+                Some(slot) => Some(slot),
+                None => history.index.get(key)?.map(|(slot, _)| slot),
+            };
+            if let Some(slot) = slot {
+                for (moved, slot) in history.index.remove(slot)? {
+                    if let Some(cached) = history.cache.peek_mut(&moved) {
+                        cached.slot = Some(slot);
+                    }
+                }
+            }
+            history.cache.pop(&key);
+            if let Some(pruned) = self.undo.as_mut().and_then(|undo| undo.pruned.as_mut()) {
+                pruned.removed.push((key.0, key.1, deposit));
+            }
+            self.pruned.insert(key.0, key.1);
+            removed.push((key.0, key.1, deposit));
+        }
+        Ok(removed)
+    }
+
+    fn is_pruned(&self, client: ClientId, tx: TransactionId) -> io::Result<bool> {
+        Ok(self.pruned.contains(client, tx))
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        self.undo = Some(Undo::default());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.undo = None;
+        Ok(())
+    }
+
+    /// Restores what the open update has written, latest first
+    fn rollback(&mut self) -> io::Result<()> {
+        let undo = match self.undo.take() {
+            Some(undo) => undo,
+            None => return Ok(()),
+        };
+        for (client, account) in undo.accounts.into_iter().rev() {
+            match account {
+                Some(account) => self.accounts.insert(client, account),
+                None => self.accounts.remove(&client),
+            };
+        }
+        let history = self.history.get_mut();
+        for (key, cached, disputed) in undo.deposits.into_iter().rev() {
+            match cached {
+                Some(cached) => history.cache(key, cached)?,
+                // The copy on disk, if any, is the previous one
+                None => {
+                    history.cache.pop(&key);
+                }
+            }
+            self.disputed.set(key.0, key.1, disputed);
+        }
+        if let Some(pruned) = undo.pruned {
+            self.queue.head = pruned.head;
+            self.deferred = pruned.deferred;
+            for (client, tx, deposit) in pruned.removed {
+                self.pruned.remove(client, tx);
+                let cached = CachedDeposit {
+                    deposit,
+                    dirty: true,
+                    slot: None,
+                };
+                history.cache((client, tx), cached)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{
+        ClientInfoStorage, LockPolicy, RetentionAge, RetentionPolicy, UpdateOutcome,
+    };
+    use crate::transactions::{DepositInfo, DisputeInfo, ResolveInfo, Transaction};
+
+    #[test]
+    fn test_spilled_deposits() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("deposits.idx");
+        let backend = SpillingStorage::create(&path, 8).unwrap();
+        let mut client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        let mut memory_storage = ClientInfoStorage::new();
+        // Enough deposits to evict most of them and to grow the index
+        let deposits = INITIAL_SLOTS as TransactionId;
+        let mut update = |transaction: fn(TransactionId) -> Transaction, tx| {
+            let outcome = client_storage.update(transaction(tx));
+            assert_eq!(outcome, memory_storage.update(transaction(tx)));
+            outcome
+        };
+        for tx in 1..=deposits {
+            update(
+                |tx| {
+                    Transaction::Deposit(DepositInfo {
+                        client: (tx % 7) as ClientId,
+                        tx,
+                        amount: 1.5,
+                    })
+                },
+                tx,
+            );
+        }
+        for tx in (1..=deposits).step_by(1000) {
+            let dispute = |tx| {
+                Transaction::Dispute(DisputeInfo {
+                    client: (tx % 7) as ClientId,
+                    tx,
+                })
+            };
+            assert_eq!(update(dispute, tx), UpdateOutcome::Applied);
+            assert_ne!(update(dispute, tx), UpdateOutcome::Applied);
+        }
+        let resolve = |tx| {
+            Transaction::Resolve(ResolveInfo {
+                client: (tx % 7) as ClientId,
+                tx,
+            })
+        };
+        assert_eq!(update(resolve, 1), UpdateOutcome::Applied);
+        // Deposits of another client are not found
+        let wrong_client = |tx| Transaction::Dispute(DisputeInfo { client: 1, tx });
+        assert_ne!(update(wrong_client, 7), UpdateOutcome::Applied);
+
+        assert_eq!(
+            client_storage.get_csv_format_accounts(),
+            memory_storage.get_csv_format_accounts()
+        );
+        assert_eq!(
+            client_storage.open_disputes(),
+            memory_storage.open_disputes()
+        );
+        assert_eq!(
+            client_storage.transaction(2001 % 7, 2001).unwrap(),
+            memory_storage.transaction(2001 % 7, 2001).unwrap()
+        );
+
+        // Pruning removes the same deposits from the cache and from the disk
+        let policy = RetentionPolicy {
+            max_age: RetentionAge::Transactions(1000),
+            interval: u64::MAX,
+        };
+        client_storage.set_retention(policy, None);
+        memory_storage.set_retention(policy, None);
+        assert_eq!(client_storage.prune(), memory_storage.prune());
+        assert_eq!(client_storage.transaction(10 % 7, 10).unwrap(), None);
+        assert_eq!(
+            client_storage.transaction(2001 % 7, 2001).unwrap(),
+            memory_storage.transaction(2001 % 7, 2001).unwrap()
+        );
+        // Deposits moved back in the index by the removals are still found
+        for tx in (1..=deposits).step_by(97) {
+            let client = (tx % 7) as ClientId;
+            assert_eq!(
+                client_storage.transaction(client, tx).unwrap(),
+                memory_storage.transaction(client, tx).unwrap()
+            );
+        }
+        // An expired deposit is pruned once its dispute is resolved
+        let resolved = resolve(1001);
+        assert_eq!(client_storage.update(resolved), UpdateOutcome::Applied);
+        assert_eq!(memory_storage.update(resolve(1001)), UpdateOutcome::Applied);
+        assert_eq!(client_storage.prune(), memory_storage.prune());
+        assert_eq!(client_storage.transaction(1001 % 7, 1001).unwrap(), None);
+        let queue_path = path.with_extension("queue");
+        assert!(path.exists() && queue_path.exists());
+        drop(client_storage);
+        assert!(!path.exists() && !queue_path.exists());
+    }
+
+    #[test]
+    fn test_rollback_restores_update() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("deposits.idx");
+        let mut backend = SpillingStorage::create(&path, 2).unwrap();
+        let deposit = |amount| DepositLog {
+            amount,
+            disputed: false,
+            sequence: 1,
+            position: 1,
+        };
+        backend.begin().unwrap();
+        backend.put_account(1, &Account::default()).unwrap();
+        backend.put_deposit(1, 1, &deposit(1.0)).unwrap();
+        backend.commit().unwrap();
+        // Evict deposit 1 to disk
+        for tx in 2..=3 {
+            backend.put_deposit(1, tx, &deposit(2.0)).unwrap();
+        }
+
+        backend.begin().unwrap();
+        let disputed = DepositLog {
+            disputed: true,
+            ..backend.deposit(1, 1).unwrap().unwrap()
+        };
+        backend.put_deposit(1, 1, &disputed).unwrap();
+        backend.put_deposit(2, 4, &deposit(3.0)).unwrap();
+        backend.put_account(2, &Account::default()).unwrap();
+        backend.rollback().unwrap();
+
+        assert_eq!(backend.deposit(1, 1).unwrap(), Some(deposit(1.0)));
+        assert_eq!(backend.deposit(2, 4).unwrap(), None);
+        assert!(backend.account(2).unwrap().is_none());
+        assert_eq!(backend.open_disputes().unwrap(), 0);
+
+        // A failed pruning restores the removed deposits, which are pruned again later
+        let expiry = DepositExpiry::Sequence(2);
+        backend.begin().unwrap();
+        assert_eq!(backend.remove_deposits(&expiry).unwrap().len(), 3);
+        backend.rollback().unwrap();
+        assert_eq!(backend.deposit(1, 1).unwrap(), Some(deposit(1.0)));
+        backend.begin().unwrap();
+        assert_eq!(backend.remove_deposits(&expiry).unwrap().len(), 3);
+        backend.commit().unwrap();
+        assert_eq!(backend.deposit(1, 1).unwrap(), None);
+        assert_eq!(backend.deposit(1, 3).unwrap(), None);
+    }
+}
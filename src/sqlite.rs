@@ -0,0 +1,672 @@
+use crate::accounts::Account;
+use crate::storage::{DepositExpiry, DepositLog, RemovedDeposit, StorageBackend};
+use crate::transactions::{ClientId, TransactionId};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+use std::path::Path;
+
+/// Tables of the database. The account columns besides `state` are kept for ad-hoc queries,
+/// the `state` JSON holds the complete account. `pruned` holds the IDs of the deposits removed
+/// by the retention policy, `meta` the sequence number of the last update, which is not stored
+/// with withdrawals, disputes or rejected transactions
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = FULL;
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        available REAL NOT NULL,
+        held REAL NOT NULL,
+        locked INTEGER NOT NULL,
+        state TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS deposits (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount REAL NOT NULL,
+        disputed INTEGER NOT NULL,
+        sequence INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (client, tx)
+    );
+    DROP INDEX IF EXISTS deposits_disputed;
+    CREATE INDEX IF NOT EXISTS deposits_client_disputed ON deposits (client)
+        WHERE disputed;
+    CREATE INDEX IF NOT EXISTS deposits_sequence ON deposits (sequence);
+    CREATE INDEX IF NOT EXISTS deposits_position ON deposits (client, position);
+    CREATE TABLE IF NOT EXISTS pruned (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        PRIMARY KEY (client, tx)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+fn storage_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// Returns the SQLite integer of a client or transaction ID. SQLite integers are signed, so
+/// IDs are stored with the same bits and IDs above `i64::MAX` are negative in the database
+fn sql_id(id: u64) -> i64 {
+    id as i64
+}
+
+/// Returns the client or transaction ID of a SQLite integer written by `sql_id`
+fn id_from_sql(id: i64) -> u64 {
+    id as u64
+}
+
+/// Columns of a deposit as read by `deposit_from_row`
+const DEPOSIT_COLUMNS: &str = "amount, disputed, sequence, position";
+
+/// Reads a deposit from the `DEPOSIT_COLUMNS` of a row, starting at the given column
+fn deposit_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<DepositLog> {
+    Ok(DepositLog {
+        amount: row.get(first)?,
+        disputed: row.get(first + 1)?,
+        sequence: row.get(first + 2)?,
+        position: row.get(first + 3)?,
+    })
+}
+
+fn json_error(error: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(error))
+}
+
+/// Keeps the accounts, their deposits and the dispute states in a SQLite database file.
+/// Every update of the ClientInfoStorage is applied within a savepoint of a database
+/// transaction, which is committed right after the update unless batching is enabled with
+/// `set_batch_updates`
+pub struct SqliteStorage {
+    connection: Connection,
+    /// Number of updates committed together
+    batch_updates: usize,
+    /// Number of updates of the open batch, which is not committed yet
+    batched: usize,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database at the given path. Accounts of a previous run are kept
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(storage_error)?;
+        connection.execute_batch(SCHEMA).map_err(storage_error)?;
+        Ok(Self {
+            connection,
+            batch_updates: 1,
+            batched: 0,
+        })
+    }
+
+    /// Commits the updates in batches of the given number of updates, and when the storage is
+    /// flushed or dropped, instead of one by one. This is faster, but a crash or an exit
+    /// without dropping the storage loses the updates of the open batch, which have already
+    /// been reported as applied. A failed update is still rolled back on its own
+    pub fn set_batch_updates(&mut self, updates: usize) {
+        self.batch_updates = updates.max(1);
+    }
+}
+
+impl Drop for SqliteStorage {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            log::error!("Unable to commit the SQLite database: {}", error);
+        }
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn account(&self, client: ClientId) -> io::Result<Option<Account>> {
+        let state: Option<String> = self
+            .connection
+            .prepare_cached("SELECT state FROM accounts WHERE client = ?1")
+            .and_then(|mut statement| {
+                statement
+                    .query_row(params![sql_id(client)], |row| row.get(0))
+                    .optional()
+            })
+            .map_err(storage_error)?;
+        state
+            .map(|state| serde_json::from_str(&state).map_err(io::Error::from))
+            .transpose()
+    }
+
+    fn put_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+        let state = serde_json::to_string(account)?;
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO accounts (client, available, held, locked, state)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    sql_id(client),
+                    account.available(),
+                    account.held(),
+                    account.is_locked(),
+                    state
+                ])
+            })
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT client, state FROM accounts")
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                let state: String = row.get(1)?;
+                let account = serde_json::from_str(&state).map_err(json_error)?;
+                Ok((id_from_sql(row.get(0)?), account))
+            })
+            .map_err(storage_error)?;
+        rows.collect::<Result<_, _>>().map_err(storage_error)
+    }
+
+    fn deposit(&self, client: ClientId, tx: TransactionId) -> io::Result<Option<DepositLog>> {
+        self.connection
+            .prepare_cached(&format!(
+                "SELECT {} FROM deposits WHERE client = ?1 AND tx = ?2",
+                DEPOSIT_COLUMNS
+            ))
+            .and_then(|mut statement| {
+                statement
+                    .query_row(params![sql_id(client), sql_id(tx)], |row| {
+                        deposit_from_row(row, 0)
+                    })
+                    .optional()
+            })
+            .map_err(storage_error)
+    }
+
+    fn put_deposit(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        deposit: &DepositLog,
+    ) -> io::Result<()> {
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO deposits
+                 (client, tx, amount, disputed, sequence, position)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    sql_id(client),
+                    sql_id(tx),
+                    deposit.amount,
+                    deposit.disputed,
+                    deposit.sequence,
+                    deposit.position
+                ])
+            })
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn open_disputes(&self) -> io::Result<usize> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM deposits WHERE disputed", [], |row| {
+                row.get(0)
+            })
+            .map_err(storage_error)
+    }
+
+    fn disputed_amount(&self, client: ClientId) -> io::Result<f64> {
+        self.connection
+            .prepare_cached(
+                "SELECT COALESCE(SUM(amount), 0.0) FROM deposits INDEXED BY deposits_client_disputed \
+                 WHERE client = ?1 AND disputed",
+            )
+            .and_then(|mut statement| {
+                statement.query_row(params![sql_id(client)], |row| row.get(0))
+            })
+            .map_err(storage_error)
+    }
+
+    /// Databases written before the `meta` table continue after their latest deposit
+    fn last_sequence(&self) -> io::Result<u64> {
+        self.connection
+            .query_row(
+                "SELECT COALESCE(
+                     (SELECT value FROM meta WHERE key = 'sequence'),
+                     (SELECT MAX(sequence) FROM deposits),
+                     0
+                 )",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(storage_error)
+    }
+
+    fn put_sequence(&mut self, sequence: u64) -> io::Result<()> {
+        self.connection
+            .prepare_cached("INSERT OR REPLACE INTO meta (key, value) VALUES ('sequence', ?1)")
+            .and_then(|mut statement| statement.execute(params![sequence]))
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    /// Deletes the expired deposits with range queries on the `deposits_sequence` and
+    /// `deposits_position` indexes
+    fn remove_deposits(&mut self, expiry: &DepositExpiry) -> io::Result<Vec<RemovedDeposit>> {
+        let returning = format!("RETURNING client, tx, {}", DEPOSIT_COLUMNS);
+        let removed_deposit = |row: &rusqlite::Row| {
+            Ok((
+                id_from_sql(row.get(0)?),
+                id_from_sql(row.get(1)?),
+                deposit_from_row(row, 2)?,
+            ))
+        };
+        let mut removed = Vec::new();
+        match expiry {
+            DepositExpiry::Sequence(sequence) => {
+                let mut statement = self
+                    .connection
+                    .prepare_cached(&format!(
+                        "DELETE FROM deposits WHERE sequence < ?1 AND NOT disputed {}",
+                        returning
+                    ))
+                    .map_err(storage_error)?;
+                let rows = statement
+                    .query_map(params![sequence], removed_deposit)
+                    .map_err(storage_error)?;
+                for row in rows {
+                    removed.push(row.map_err(storage_error)?);
+                }
+            }
+            DepositExpiry::Position(positions) => {
+                let mut statement = self
+                    .connection
+                    .prepare_cached(&format!(
+                        "DELETE FROM deposits
+                         WHERE client = ?1 AND position < ?2 AND NOT disputed {}",
+                        returning
+                    ))
+                    .map_err(storage_error)?;
+                for (client, position) in positions {
+                    let rows = statement
+                        .query_map(params![sql_id(*client), position], removed_deposit)
+                        .map_err(storage_error)?;
+                    for row in rows {
+                        removed.push(row.map_err(storage_error)?);
+                    }
+                }
+            }
+        }
+        let mut statement = self
+            .connection
+            .prepare_cached("INSERT OR IGNORE INTO pruned (client, tx) VALUES (?1, ?2)")
+            .map_err(storage_error)?;
+        for (client, tx, _) in &removed {
+            statement
+                .execute(params![sql_id(*client), sql_id(*tx)])
+                .map_err(storage_error)?;
+        }
+        Ok(removed)
+    }
+
+    fn is_pruned(&self, client: ClientId, tx: TransactionId) -> io::Result<bool> {
+        self.connection
+            .prepare_cached("SELECT EXISTS (SELECT 1 FROM pruned WHERE client = ?1 AND tx = ?2)")
+            .and_then(|mut statement| {
+                statement.query_row(params![sql_id(client), sql_id(tx)], |row| row.get(0))
+            })
+            .map_err(storage_error)
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        if self.connection.is_autocommit() {
+            self.connection
+                .execute_batch("BEGIN")
+                .map_err(storage_error)?;
+        }
+        self.connection
+            .execute_batch("SAVEPOINT update_savepoint")
+            .map_err(storage_error)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.connection
+            .execute_batch("RELEASE update_savepoint")
+            .map_err(storage_error)?;
+        self.batched += 1;
+        if self.batched >= self.batch_updates {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> io::Result<()> {
+        self.connection
+            .execute_batch("ROLLBACK TO update_savepoint; RELEASE update_savepoint")
+            .map_err(storage_error)
+    }
+
+    /// Commits the updates of the open batch, if any, to the file
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.connection.is_autocommit() {
+            self.connection
+                .execute_batch("COMMIT")
+                .map_err(storage_error)?;
+        }
+        self.batched = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{
+        ClientInfoStorage, LockPolicy, Rejection, RetentionAge, RetentionPolicy, UpdateOutcome,
+    };
+    use crate::transactions::{
+        ChargeBackInfo, DepositInfo, DisputeInfo, Transaction, WithdrawalInfo,
+    };
+
+    fn sqlite_storage(path: &Path) -> ClientInfoStorage {
+        let backend = SqliteStorage::open(path).unwrap();
+        ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default())
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let mut client_storage = sqlite_storage(&path);
+        let transactions = || {
+            vec![
+                Transaction::Deposit(DepositInfo {
+                    client: 1,
+                    tx: 1,
+                    amount: 2.0,
+                }),
+                Transaction::Deposit(DepositInfo {
+                    client: 2,
+                    tx: 2,
+                    amount: 3.0,
+                }),
+                Transaction::Withdrawal(WithdrawalInfo {
+                    client: 1,
+                    tx: 3,
+                    amount: 0.5,
+                }),
+                Transaction::Dispute(DisputeInfo { client: 2, tx: 2 }),
+                Transaction::ChargeBack(ChargeBackInfo { client: 2, tx: 2 }),
+            ]
+        };
+        for transaction in transactions() {
+            assert_eq!(client_storage.update(transaction), UpdateOutcome::Applied);
+        }
+        let mut memory_storage = ClientInfoStorage::new();
+        for transaction in transactions() {
+            memory_storage.update(transaction);
+        }
+        assert_eq!(
+            client_storage.get_csv_format_accounts(),
+            memory_storage.get_csv_format_accounts()
+        );
+        assert_eq!(
+            client_storage.lock_history(2).unwrap(),
+            memory_storage.lock_history(2).unwrap()
+        );
+        drop(client_storage);
+
+        // The accounts are kept in the file and can be queried with SQL
+        let mut client_storage = sqlite_storage(&path);
+        assert_eq!(client_storage.gauges().clients, 2);
+        assert_eq!(
+            client_storage
+                .transaction(2, 2)
+                .unwrap()
+                .map(|record| record.client),
+            Some(2)
+        );
+        client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 }));
+        assert_eq!(client_storage.open_disputes(), 1);
+        client_storage.flush().unwrap();
+        let connection = Connection::open(&path).unwrap();
+        let (held, locked): (f64, bool) = connection
+            .query_row(
+                "SELECT held, locked FROM accounts WHERE client = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((held, locked), (2.0, false));
+
+        // Only the deposits which are not disputed are pruned
+        client_storage.set_retention(
+            RetentionPolicy {
+                max_age: RetentionAge::Transactions(0),
+                interval: u64::MAX,
+            },
+            None,
+        );
+        assert_eq!(client_storage.prune(), 1);
+        assert_eq!(
+            client_storage
+                .transaction(1, 1)
+                .unwrap()
+                .map(|record| record.disputed),
+            Some(true)
+        );
+        assert_eq!(client_storage.transaction(2, 2).unwrap(), None);
+        // The pruning deletes ranges of the indexes instead of scanning all the deposits
+        for (condition, index) in [
+            ("sequence < 1", "deposits_sequence"),
+            ("client = 1 AND position < 1", "deposits_position"),
+        ] {
+            let plan: String = connection
+                .query_row(
+                    &format!(
+                        "EXPLAIN QUERY PLAN DELETE FROM deposits WHERE {} AND NOT disputed",
+                        condition
+                    ),
+                    [],
+                    |row| row.get(3),
+                )
+                .unwrap();
+            assert!(plan.contains(index), "{}", plan);
+        }
+        // The invariant check sums the disputed deposits of a client from the partial index,
+        // which the planner would not prefer without statistics
+        let plan: String = connection
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT COALESCE(SUM(amount), 0.0) FROM deposits \
+                 INDEXED BY deposits_client_disputed WHERE client = 1 AND disputed",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("deposits_client_disputed"), "{}", plan);
+    }
+
+    #[test]
+    fn test_sqlite_pruned_ids_survive_reopen() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let mut client_storage = sqlite_storage(&path);
+        for tx in [2, 4] {
+            client_storage.update(Transaction::Deposit(DepositInfo {
+                client: 1,
+                tx,
+                amount: 1.0,
+            }));
+        }
+        client_storage.update(Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 5,
+            amount: 0.5,
+        }));
+        client_storage.set_retention(
+            RetentionPolicy {
+                max_age: RetentionAge::Transactions(0),
+                interval: u64::MAX,
+            },
+            None,
+        );
+        assert_eq!(client_storage.prune(), 2);
+        drop(client_storage);
+
+        // The pruned deposits are still reported as pruned after a restart,
+        // while the IDs between them are unknown
+        let mut client_storage = sqlite_storage(&path);
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 4 })),
+            UpdateOutcome::Rejected(Rejection::Pruned)
+        );
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 3 })),
+            UpdateOutcome::Rejected(Rejection::UnknownTransaction)
+        );
+    }
+
+    #[test]
+    fn test_sqlite_ids_above_i64_max() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let (client, tx) = (u64::MAX as ClientId, u64::MAX - 1);
+        let mut client_storage = sqlite_storage(&path);
+        let deposit = Transaction::Deposit(DepositInfo {
+            client,
+            tx,
+            amount: 2.0,
+        });
+        assert_eq!(client_storage.update(deposit), UpdateOutcome::Applied);
+        let dispute = Transaction::Dispute(DisputeInfo { client, tx });
+        assert_eq!(client_storage.update(dispute), UpdateOutcome::Applied);
+        drop(client_storage);
+
+        let client_storage = sqlite_storage(&path);
+        let accounts = client_storage.get_csv_format_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client(), client);
+        assert_eq!(
+            client_storage
+                .transaction(client, tx)
+                .unwrap()
+                .map(|record| record.disputed),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_sqlite_commits_every_update() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let reader = |path: &Path| -> usize {
+            Connection::open(path)
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM accounts", [], |row| row.get(0))
+                .unwrap()
+        };
+        let deposit = |client| {
+            Transaction::Deposit(DepositInfo {
+                client,
+                tx: client,
+                amount: 1.0,
+            })
+        };
+        let mut client_storage = sqlite_storage(&path);
+        client_storage.update(deposit(1));
+        assert_eq!(reader(&path), 1);
+
+        // A batched update is only visible to other connections once the batch is committed
+        let mut backend = SqliteStorage::open(&path).unwrap();
+        backend.set_batch_updates(10);
+        drop(client_storage);
+        let mut client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        client_storage.update(deposit(2));
+        assert_eq!(reader(&path), 1);
+        client_storage.flush().unwrap();
+        assert_eq!(reader(&path), 2);
+    }
+
+    #[test]
+    fn test_sqlite_sequence_survives_reopen() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let mut client_storage = sqlite_storage(&path);
+        client_storage.update(Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 2.0,
+        }));
+        // Neither of these stores a deposit
+        client_storage.update(Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 2,
+            amount: 1.0,
+        }));
+        client_storage.update(Transaction::Withdrawal(WithdrawalInfo {
+            client: 1,
+            tx: 3,
+            amount: 5.0,
+        }));
+        drop(client_storage);
+        assert_eq!(
+            SqliteStorage::open(&path).unwrap().last_sequence().unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_sqlite_large_input() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.sqlite");
+        let mut backend = SqliteStorage::open(&path).unwrap();
+        backend.set_batch_updates(1000);
+        let mut client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        let mut memory_storage = ClientInfoStorage::new();
+        // More updates than fit into a batch, with disputes of older deposits
+        let transaction = |tx: u64| {
+            let client = (tx % 100) as ClientId;
+            if tx.is_multiple_of(10) && tx > 100 {
+                Transaction::Dispute(DisputeInfo {
+                    client,
+                    tx: tx - 100,
+                })
+            } else {
+                Transaction::Deposit(DepositInfo {
+                    client,
+                    tx,
+                    amount: 1.0,
+                })
+            }
+        };
+        let started = std::time::Instant::now();
+        for tx in 1..=20_000 {
+            assert_eq!(
+                client_storage.update(transaction(tx)),
+                memory_storage.update(transaction(tx))
+            );
+        }
+        drop(client_storage);
+        let elapsed = started.elapsed();
+        let client_storage = sqlite_storage(&path);
+        assert_eq!(
+            client_storage.get_csv_format_accounts(),
+            memory_storage.get_csv_format_accounts()
+        );
+        assert_eq!(
+            client_storage.open_disputes(),
+            memory_storage.open_disputes()
+        );
+        // Batched commits keep a run of this size well within a test's time
+        assert!(
+            elapsed < std::time::Duration::from_secs(30),
+            "{:?}",
+            elapsed
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::accounts::UpdateOutcome;
+use crate::transactions::{TransactionErrorKind, TransactionType};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+/// Counters and processing time of the transactions of one type
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct TypeStats {
+    /// Number of transactions read
+    pub read: u64,
+    /// Number of transactions which have been applied
+    pub applied: u64,
+    /// Number of transactions which have been held for review
+    pub held: u64,
+    /// Number of transactions which have been rejected
+    pub rejected: u64,
+    /// Time spent scoring and applying the transactions
+    pub processing_seconds: f64,
+}
+
+/// Counters and timings of a run of the Payment Engine over all its inputs
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct RunStats {
+    /// Number of records read
+    pub records: u64,
+    /// Number of transactions which have been applied
+    pub applied: u64,
+    /// Number of transactions which have been held for review
+    pub held: u64,
+    /// Number of transactions which have been rejected
+    pub rejected: u64,
+    /// Number of records which failed to parse
+    pub parse_errors: u64,
+    /// Counters by transaction type
+    pub transaction_types: BTreeMap<String, TypeStats>,
+    /// Number of rejected transactions by reason
+    pub rejection_reasons: BTreeMap<String, u64>,
+    /// Number of records which failed to parse by error kind
+    pub parse_error_kinds: BTreeMap<String, u64>,
+    /// Time spent reading and processing the inputs
+    pub elapsed_seconds: f64,
+    /// Number of records read per second
+    pub records_per_second: f64,
+}
+
+impl RunStats {
+    pub(crate) fn record_outcome(
+        &mut self,
+        transaction_type: TransactionType,
+        outcome: UpdateOutcome,
+        processing_time: Duration,
+    ) {
+        self.records += 1;
+        let type_stats = self
+            .transaction_types
+            .entry(transaction_type.to_string())
+            .or_default();
+        type_stats.read += 1;
+        type_stats.processing_seconds += processing_time.as_secs_f64();
+        match outcome {
+            UpdateOutcome::Applied => {
+                self.applied += 1;
+                type_stats.applied += 1;
+            }
+            UpdateOutcome::Held => {
+                self.held += 1;
+                type_stats.held += 1;
+            }
+            UpdateOutcome::Rejected(rejection) => {
+                self.rejected += 1;
+                type_stats.rejected += 1;
+                *self
+                    .rejection_reasons
+                    .entry(rejection.to_string())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    pub(crate) fn record_parse_error(&mut self, kind: TransactionErrorKind) {
+        self.records += 1;
+        self.parse_errors += 1;
+        *self.parse_error_kinds.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub(crate) fn add_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed_seconds += elapsed.as_secs_f64();
+        if self.elapsed_seconds > 0.0 {
+            self.records_per_second = self.records as f64 / self.elapsed_seconds;
+        }
+    }
+
+    /// Writes the statistics as a JSON object
+    pub fn write_json(&self, mut writer: impl Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    /// Writes the statistics in the Prometheus text format
+    pub fn write_prometheus(&self, mut writer: impl Write) -> std::io::Result<()> {
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            writeln!(writer, "# HELP payment_engine_{} {}", name, help)?;
+            writeln!(writer, "# TYPE payment_engine_{} {}", name, kind)?;
+            for (labels, value) in samples {
+                writeln!(writer, "payment_engine_{}{} {}", name, labels, value)?;
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        metric(
+            "records_total",
+            "counter",
+            "Records read from the inputs",
+            vec![(String::new(), self.records as f64)],
+        )?;
+        metric(
+            "transactions_total",
+            "counter",
+            "Transactions by type and outcome",
+            self.transaction_types
+                .iter()
+                .flat_map(|(transaction_type, stats)| {
+                    [
+                        ("applied", stats.applied),
+                        ("held", stats.held),
+                        ("rejected", stats.rejected),
+                    ]
+                    .into_iter()
+                    .map(move |(outcome, count)| {
+                        (
+                            labels(&[("type", transaction_type), ("outcome", outcome)]),
+                            count as f64,
+                        )
+                    })
+                })
+                .collect(),
+        )?;
+        metric(
+            "rejections_total",
+            "counter",
+            "Rejected transactions by reason",
+            counts("reason", &self.rejection_reasons),
+        )?;
+        metric(
+            "parse_errors_total",
+            "counter",
+            "Records which failed to parse by error kind",
+            counts("kind", &self.parse_error_kinds),
+        )?;
+        metric(
+            "processing_seconds_total",
+            "counter",
+            "Time spent scoring and applying transactions by type",
+            self.transaction_types
+                .iter()
+                .map(|(transaction_type, stats)| {
+                    (
+                        labels(&[("type", transaction_type)]),
+                        stats.processing_seconds,
+                    )
+                })
+                .collect(),
+        )?;
+        metric(
+            "run_seconds",
+            "gauge",
+            "Time spent reading and processing the inputs",
+            vec![(String::new(), self.elapsed_seconds)],
+        )?;
+        metric(
+            "records_per_second",
+            "gauge",
+            "Records read per second",
+            vec![(String::new(), self.records_per_second)],
+        )
+    }
+}
+
+/// Formats Prometheus labels, escaping their values
+pub(crate) fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn counts(label: &str, counts: &BTreeMap<String, u64>) -> Vec<(String, f64)> {
+    counts
+        .iter()
+        .map(|(value, count)| (labels(&[(label, value)]), *count as f64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Rejection;
+
+    #[test]
+    fn test_run_stats() {
+        let mut stats = RunStats::default();
+        let millisecond = Duration::from_millis(1);
+        stats.record_outcome(
+            TransactionType::Deposit,
+            UpdateOutcome::Applied,
+            millisecond,
+        );
+        stats.record_outcome(
+            TransactionType::Withdrawal,
+            UpdateOutcome::Rejected(Rejection::InsufficientFunds),
+            millisecond,
+        );
+        stats.record_outcome(TransactionType::Dispute, UpdateOutcome::Held, millisecond);
+        stats.record_parse_error(TransactionErrorKind::WrongFormat);
+        stats.add_elapsed(Duration::from_secs(2));
+        assert_eq!(
+            (stats.records, stats.applied, stats.held, stats.rejected),
+            (4, 1, 1, 1)
+        );
+        assert_eq!(stats.parse_errors, 1);
+        assert_eq!(stats.transaction_types["withdrawal"].rejected, 1);
+        assert_eq!(stats.rejection_reasons["insufficient_funds"], 1);
+        assert_eq!(stats.records_per_second, 2.0);
+
+        let mut output = Vec::new();
+        stats.write_prometheus(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("# TYPE payment_engine_records_total counter\n"));
+        assert!(output.contains("payment_engine_records_total 4\n"));
+        assert!(output.contains(
+            "payment_engine_transactions_total{type=\"deposit\",outcome=\"applied\"} 1\n"
+        ));
+        assert!(
+            output.contains("payment_engine_rejections_total{reason=\"insufficient_funds\"} 1\n")
+        );
+        assert!(output
+            .contains("payment_engine_parse_errors_total{kind=\"wrong transaction format\"} 1\n"));
+        assert!(output.contains("payment_engine_records_per_second 2\n"));
+    }
+}
@@ -0,0 +1,462 @@
+use crate::accounts::Account;
+use crate::transactions::{Amount, ClientId, TransactionId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+/// A deposit as kept in the deposit history of a client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DepositLog {
+    /// Deposited amount
+    pub amount: Amount,
+    /// True while the deposit is disputed
+    pub disputed: bool,
+    /// Sequence number of the deposit in the storage's input
+    pub sequence: u64,
+    /// Position of the deposit in the transactions of its client
+    pub position: u64,
+}
+
+/// A deposit of a client as removed from a StorageBackend
+pub type RemovedDeposit = (ClientId, TransactionId, DepositLog);
+
+/// Which deposits a pruning of the deposit history removes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DepositExpiry {
+    /// The deposits with a sequence number below this one
+    Sequence(u64),
+    /// The deposits of every listed client at a position below the given one in the
+    /// transactions of the client
+    Position(HashMap<ClientId, u64>),
+}
+
+impl DepositExpiry {
+    /// Returns true if the given deposit of the given client has expired
+    pub fn is_expired(&self, client: ClientId, deposit: &DepositLog) -> bool {
+        match self {
+            DepositExpiry::Sequence(sequence) => deposit.sequence < *sequence,
+            DepositExpiry::Position(positions) => positions
+                .get(&client)
+                .is_some_and(|position| deposit.position < *position),
+        }
+    }
+}
+
+/// The amounts of the disputed deposits of every client, so that the disputed amount of a
+/// client is found without scanning its deposit history
+#[derive(Default)]
+pub(crate) struct DisputedDeposits {
+    clients: HashMap<ClientId, HashMap<TransactionId, Amount>>,
+    count: usize,
+}
+
+impl DisputedDeposits {
+    /// Records whether the given deposit is disputed and returns its previous disputed amount
+    pub(crate) fn set(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Amount>,
+    ) -> Option<Amount> {
+        let previous = match amount {
+            Some(amount) => self.clients.entry(client).or_default().insert(tx, amount),
+            None => {
+                let deposits = self.clients.get_mut(&client);
+                let previous = deposits.and_then(|deposits| deposits.remove(&tx));
+                if self.clients.get(&client).is_some_and(HashMap::is_empty) {
+                    self.clients.remove(&client);
+                }
+                previous
+            }
+        };
+        self.count = self.count + usize::from(amount.is_some()) - usize::from(previous.is_some());
+        previous
+    }
+
+    /// Records the disputed state of the given deposit
+    pub(crate) fn update(&mut self, client: ClientId, tx: TransactionId, deposit: &DepositLog) {
+        self.set(client, tx, deposit.disputed.then_some(deposit.amount));
+    }
+
+    /// Returns the sum of the disputed deposits of the given client
+    pub(crate) fn amount(&self, client: ClientId) -> f64 {
+        self.clients.get(&client).map_or(0.0, |deposits| {
+            deposits.values().map(|amount| f64::from(*amount)).sum()
+        })
+    }
+
+    /// Returns the number of disputed deposits
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// The IDs of the deposits removed by the retention policy, kept exactly as ranges of
+/// consecutive IDs of every client, so that a client's deposits with consecutive IDs take a
+/// single range
+#[derive(Default)]
+pub(crate) struct PrunedIds {
+    /// The start of every range of a client, mapped to its inclusive end
+    clients: HashMap<ClientId, BTreeMap<TransactionId, TransactionId>>,
+}
+
+impl PrunedIds {
+    /// Records the given pruned deposit
+    pub(crate) fn insert(&mut self, client: ClientId, tx: TransactionId) {
+        if self.contains(client, tx) {
+            return;
+        }
+        let ranges = self.clients.entry(client).or_default();
+        let start = match ranges.range(..tx).next_back() {
+            Some((start, end)) if end.checked_add(1) == Some(tx) => *start,
+            _ => tx,
+        };
+        let end = tx
+            .checked_add(1)
+            .and_then(|next| ranges.remove(&next))
+            .unwrap_or(tx);
+        ranges.insert(start, end);
+    }
+
+    /// Forgets the given pruned deposit, when its removal is rolled back
+    pub(crate) fn remove(&mut self, client: ClientId, tx: TransactionId) {
+        let ranges = match self.clients.get_mut(&client) {
+            Some(ranges) => ranges,
+            None => return,
+        };
+        let (start, end) = match ranges.range(..=tx).next_back() {
+            Some((start, end)) if tx <= *end => (*start, *end),
+            _ => return,
+        };
+        ranges.remove(&start);
+        if start < tx {
+            ranges.insert(start, tx - 1);
+        }
+        if tx < end {
+            ranges.insert(tx + 1, end);
+        }
+        if ranges.is_empty() {
+            self.clients.remove(&client);
+        }
+    }
+
+    /// Returns true if the given deposit has been pruned
+    pub(crate) fn contains(&self, client: ClientId, tx: TransactionId) -> bool {
+        self.clients
+            .get(&client)
+            .and_then(|ranges| ranges.range(..=tx).next_back())
+            .is_some_and(|(_, end)| tx <= *end)
+    }
+}
+
+/// Reads and writes the accounts and the deposit history of a ClientInfoStorage.
+///
+/// The storage applies every transaction by reading the affected account and deposit, changing
+/// them and writing them back between `begin` and `commit`. When applying fails the storage
+/// calls `rollback` and rejects the transaction, so backends which support transactions stay
+/// consistent
+pub trait StorageBackend: Send {
+    /// Returns the account of the given client
+    fn account(&self, client: ClientId) -> io::Result<Option<Account>>;
+
+    /// Inserts or replaces the account of the given client
+    fn put_account(&mut self, client: ClientId, account: &Account) -> io::Result<()>;
+
+    /// Returns all the accounts in no particular order
+    fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>>;
+
+    /// Returns the deposit with the given ID of the given client
+    fn deposit(&self, client: ClientId, tx: TransactionId) -> io::Result<Option<DepositLog>>;
+
+    /// Inserts or replaces the deposit with the given ID of the given client
+    fn put_deposit(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        deposit: &DepositLog,
+    ) -> io::Result<()>;
+
+    /// Returns the number of disputed deposits
+    fn open_disputes(&self) -> io::Result<usize>;
+
+    /// Returns the sum of the amounts of the disputed deposits of the given client
+    fn disputed_amount(&self, client: ClientId) -> io::Result<f64>;
+
+    /// Removes the expired deposits which are not disputed and keeps their IDs for
+    /// `is_pruned`. Returns the removed deposits
+    fn remove_deposits(&mut self, expiry: &DepositExpiry) -> io::Result<Vec<RemovedDeposit>>;
+
+    /// Returns true if the deposit with the given ID of the given client has been removed by
+    /// `remove_deposits`
+    fn is_pruned(&self, client: ClientId, tx: TransactionId) -> io::Result<bool>;
+
+    /// Returns the sequence number of the last update stored by `put_sequence`, so that the
+    /// sequence of a storage reopened on existing data continues
+    fn last_sequence(&self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    /// Stores the sequence number of the update being applied, so that `last_sequence` does
+    /// not go back after a reopen. Called within every update
+    fn put_sequence(&mut self, _sequence: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called before the reads and writes of a single update
+    fn begin(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after the reads and writes of a single update succeeded
+    fn commit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of `commit` when a read or write of an update failed
+    fn rollback(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the committed updates durable, for backends which commit them in batches
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the accounts and their deposit history in memory. This is the default backend
+#[derive(Default)]
+pub struct MemoryStorage {
+    client_info: HashMap<ClientId, (Account, HashMap<TransactionId, DepositLog>)>,
+    disputed: DisputedDeposits,
+    pruned: PrunedIds,
+}
+
+impl MemoryStorage {
+    /// Creates an empty MemoryStorage
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn account(&self, client: ClientId) -> io::Result<Option<Account>> {
+        Ok(self
+            .client_info
+            .get(&client)
+            .map(|client_info| client_info.0.clone()))
+    }
+
+    fn put_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+        let client_info = self.client_info.entry(client).or_default();
+        client_info.0.clone_from(account);
+        Ok(())
+    }
+
+    fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+        Ok(self
+            .client_info
+            .iter()
+            .map(|(client, client_info)| (*client, client_info.0.clone()))
+            .collect())
+    }
+
+    fn deposit(&self, client: ClientId, tx: TransactionId) -> io::Result<Option<DepositLog>> {
+        Ok(self
+            .client_info
+            .get(&client)
+            .and_then(|client_info| client_info.1.get(&tx))
+            .copied())
+    }
+
+    fn put_deposit(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        deposit: &DepositLog,
+    ) -> io::Result<()> {
+        let client_info = self.client_info.entry(client).or_default();
+        client_info.1.insert(tx, *deposit);
+        self.disputed.update(client, tx, deposit);
+        Ok(())
+    }
+
+    fn open_disputes(&self) -> io::Result<usize> {
+        Ok(self.disputed.count())
+    }
+
+    fn disputed_amount(&self, client: ClientId) -> io::Result<f64> {
+        Ok(self.disputed.amount(client))
+    }
+
+    fn remove_deposits(&mut self, expiry: &DepositExpiry) -> io::Result<Vec<RemovedDeposit>> {
+        let mut removed = Vec::new();
+        for (client, client_info) in &mut self.client_info {
+            client_info.1.retain(|tx, deposit| {
+                if deposit.disputed || !expiry.is_expired(*client, deposit) {
+                    return true;
+                }
+                removed.push((*client, *tx, *deposit));
+                false
+            });
+        }
+        for (client, tx, _) in &removed {
+            self.pruned.insert(*client, *tx);
+        }
+        Ok(removed)
+    }
+
+    fn is_pruned(&self, client: ClientId, tx: TransactionId) -> io::Result<bool> {
+        Ok(self.pruned.contains(client, tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{ClientInfoStorage, LockPolicy, Rejection, UpdateOutcome};
+    use crate::transactions::{DepositInfo, DisputeInfo, Transaction};
+    use std::sync::{Arc, Mutex};
+
+    /// Memory backend whose deposit reads fail and which records the rollbacks
+    struct FailingStorage {
+        memory: MemoryStorage,
+        rollbacks: Arc<Mutex<usize>>,
+    }
+
+    impl StorageBackend for FailingStorage {
+        fn account(&self, client: ClientId) -> io::Result<Option<Account>> {
+            self.memory.account(client)
+        }
+
+        fn put_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+            self.memory.put_account(client, account)
+        }
+
+        fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+            self.memory.accounts()
+        }
+
+        fn deposit(&self, _: ClientId, _: TransactionId) -> io::Result<Option<DepositLog>> {
+            Err(io::Error::other("disk unavailable"))
+        }
+
+        fn put_deposit(
+            &mut self,
+            client: ClientId,
+            tx: TransactionId,
+            deposit: &DepositLog,
+        ) -> io::Result<()> {
+            self.memory.put_deposit(client, tx, deposit)
+        }
+
+        fn open_disputes(&self) -> io::Result<usize> {
+            self.memory.open_disputes()
+        }
+
+        fn disputed_amount(&self, client: ClientId) -> io::Result<f64> {
+            self.memory.disputed_amount(client)
+        }
+
+        fn remove_deposits(&mut self, expiry: &DepositExpiry) -> io::Result<Vec<RemovedDeposit>> {
+            self.memory.remove_deposits(expiry)
+        }
+
+        fn is_pruned(&self, client: ClientId, tx: TransactionId) -> io::Result<bool> {
+            self.memory.is_pruned(client, tx)
+        }
+
+        fn rollback(&mut self) -> io::Result<()> {
+            *self.rollbacks.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backend_failure_rejects_transaction() {
+        let rollbacks = Arc::new(Mutex::new(0));
+        let backend = FailingStorage {
+            memory: MemoryStorage::new(),
+            rollbacks: rollbacks.clone(),
+        };
+        let mut client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        let deposit = Transaction::Deposit(DepositInfo {
+            client: 1,
+            tx: 1,
+            amount: 2.0,
+        });
+        assert_eq!(client_storage.update(deposit), UpdateOutcome::Applied);
+        assert_eq!(
+            client_storage.update(Transaction::Dispute(DisputeInfo { client: 1, tx: 1 })),
+            UpdateOutcome::Rejected(Rejection::StorageFailure)
+        );
+        assert_eq!(*rollbacks.lock().unwrap(), 1);
+        assert_eq!(client_storage.account(1).unwrap().unwrap().held(), 0.0);
+        assert_eq!(client_storage.open_disputes(), 0);
+        // Lookups report the failed read instead of a missing deposit
+        assert!(client_storage.transaction(1, 1).is_err());
+    }
+
+    #[test]
+    fn test_existing_backend_gauges() {
+        let mut backend = MemoryStorage::new();
+        let account = Account::default();
+        backend.put_account(1, &account).unwrap();
+        backend
+            .put_deposit(
+                1,
+                1,
+                &DepositLog {
+                    amount: 1.0,
+                    disputed: true,
+                    sequence: 1,
+                    position: 1,
+                },
+            )
+            .unwrap();
+        let client_storage =
+            ClientInfoStorage::with_backend(Box::new(backend), LockPolicy::default());
+        assert_eq!(client_storage.gauges().clients, 1);
+        assert_eq!(client_storage.gauges().open_disputes, 1);
+    }
+
+    #[test]
+    fn test_disputed_deposits() {
+        let mut disputed = DisputedDeposits::default();
+        assert_eq!(disputed.set(1, 1, Some(1.5)), None);
+        assert_eq!(disputed.set(1, 2, Some(2.0)), None);
+        assert_eq!(disputed.set(2, 3, Some(4.0)), None);
+        assert_eq!(
+            (disputed.amount(1), disputed.amount(2), disputed.count()),
+            (3.5, 4.0, 3)
+        );
+        assert_eq!(disputed.set(1, 1, None), Some(1.5));
+        assert_eq!(disputed.set(1, 1, None), None);
+        assert_eq!(disputed.set(2, 3, None), Some(4.0));
+        assert_eq!(
+            (disputed.amount(1), disputed.amount(2), disputed.count()),
+            (2.0, 0.0, 1)
+        );
+        assert!(!disputed.clients.contains_key(&2));
+    }
+
+    #[test]
+    fn test_pruned_ids() {
+        let mut pruned = PrunedIds::default();
+        for tx in [1, 3, 2, 7, u64::MAX] {
+            pruned.insert(1, tx);
+        }
+        assert_eq!(pruned.clients[&1].len(), 3);
+        assert!([1, 2, 3, 7, u64::MAX]
+            .iter()
+            .all(|tx| pruned.contains(1, *tx)));
+        assert!(![0, 4, 6, 8].iter().any(|tx| pruned.contains(1, *tx)));
+        assert!(!pruned.contains(2, 1));
+        pruned.remove(1, 2);
+        assert!(pruned.contains(1, 1) && !pruned.contains(1, 2) && pruned.contains(1, 3));
+        for tx in [1, 3, 7, u64::MAX] {
+            pruned.remove(1, tx);
+        }
+        assert!(pruned.clients.is_empty());
+    }
+}
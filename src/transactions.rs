@@ -1,26 +1,109 @@
-use serde::Deserialize;
+use crate::dialect::CsvDialect;
+use crate::ids::ExternalIds;
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
 
-pub type ClientId = u16;
-pub type TransactionId = u32;
+pub type ClientId = u64;
+pub type TransactionId = u64;
 pub type Amount = f32;
 
+/// A raw transaction record as deserialized from CSV or JSON input. External IDs are
+/// deserialized as strings and mapped to internal IDs by `with_internal_ids`
 #[derive(Deserialize, Debug)]
-struct CsvTransaction {
+pub(crate) struct CsvTransaction<C = ClientId, T = TransactionId> {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
-    pub client: ClientId,
-    pub tx: TransactionId,
+    pub client: C,
+    pub tx: T,
     pub amount: Option<Amount>,
+    /// Type of the held transaction a release or deny refers to
+    #[serde(default)]
+    pub target: Option<TransactionType>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
+impl CsvTransaction<String, String> {
+    /// Maps the external client and transaction IDs of the record to internal IDs
+    pub fn with_internal_ids(self, ids: &ExternalIds) -> CsvTransaction {
+        CsvTransaction {
+            transaction_type: self.transaction_type,
+            client: ids.client_id(&self.client),
+            tx: ids.transaction_id(&self.tx),
+            amount: self.amount,
+            target: self.target,
+        }
+    }
+}
+
+/// The type of a transaction as it appears in the input. Type names are case-insensitive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     ChargeBack,
+    Release,
+    Deny,
+}
+
+impl TransactionType {
+    /// All the transaction types, in the order of their type codes
+    pub(crate) const ALL: [TransactionType; 7] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::ChargeBack,
+        TransactionType::Release,
+        TransactionType::Deny,
+    ];
+
+    /// Returns the name of the type as it appears in the input
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::ChargeBack => "chargeback",
+            TransactionType::Release => "release",
+            TransactionType::Deny => "deny",
+        }
+    }
+
+    /// Returns the type of the given case-insensitive name without allocating
+    fn from_name(name: &str) -> Option<Self> {
+        Self::from_name_bytes(name.as_bytes())
+    }
+
+    fn from_name_bytes(name: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|transaction_type| {
+            transaction_type
+                .name()
+                .as_bytes()
+                .eq_ignore_ascii_case(name)
+        })
+    }
+}
+
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::from_name(name).ok_or_else(|| format!("unknown transaction type `{}`", name))
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Debug)]
@@ -30,6 +113,70 @@ pub enum Transaction {
     Dispute(DisputeInfo),
     Resolve(ResolveInfo),
     ChargeBack(ChargeBackInfo),
+    /// Admin transaction which applies a transaction held for review
+    Release(ReleaseInfo),
+    /// Admin transaction which drops a transaction held for review
+    Deny(DenyInfo),
+}
+
+impl Transaction {
+    /// Returns the type of the transaction
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit(_) => TransactionType::Deposit,
+            Transaction::Withdrawal(_) => TransactionType::Withdrawal,
+            Transaction::Dispute(_) => TransactionType::Dispute,
+            Transaction::Resolve(_) => TransactionType::Resolve,
+            Transaction::ChargeBack(_) => TransactionType::ChargeBack,
+            Transaction::Release(_) => TransactionType::Release,
+            Transaction::Deny(_) => TransactionType::Deny,
+        }
+    }
+
+    /// Returns the client the transaction refers to
+    pub fn client(&self) -> ClientId {
+        match self {
+            Transaction::Deposit(info) => info.client,
+            Transaction::Withdrawal(info) => info.client,
+            Transaction::Dispute(info) => info.client,
+            Transaction::Resolve(info) => info.client,
+            Transaction::ChargeBack(info) => info.client,
+            Transaction::Release(info) => info.client,
+            Transaction::Deny(info) => info.client,
+        }
+    }
+
+    /// Returns the transaction ID (for disputes, resolves, charge-backs and admin
+    /// transactions this is the ID of the referenced transaction)
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            Transaction::Deposit(info) => info.tx,
+            Transaction::Withdrawal(info) => info.tx,
+            Transaction::Dispute(info) => info.tx,
+            Transaction::Resolve(info) => info.tx,
+            Transaction::ChargeBack(info) => info.tx,
+            Transaction::Release(info) => info.tx,
+            Transaction::Deny(info) => info.tx,
+        }
+    }
+
+    /// Returns the amount of the transaction, if the transaction carries one
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Transaction::Deposit(info) => Some(info.amount),
+            Transaction::Withdrawal(info) => Some(info.amount),
+            _ => None,
+        }
+    }
+
+    /// Returns the type of the held transaction an admin transaction refers to, if given
+    pub fn target(&self) -> Option<TransactionType> {
+        match self {
+            Transaction::Release(info) => info.target,
+            Transaction::Deny(info) => info.target,
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -64,6 +211,24 @@ pub struct ChargeBackInfo {
     pub tx: TransactionId,
 }
 
+#[derive(Debug)]
+pub struct ReleaseInfo {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Type of the held transaction, e.g. a dispute of a held deposit with the same ID.
+    /// Without a type the held transaction is taken in the order of the transaction types
+    pub target: Option<TransactionType>,
+}
+
+#[derive(Debug)]
+pub struct DenyInfo {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Type of the held transaction, e.g. a dispute of a held deposit with the same ID.
+    /// Without a type the held transaction is taken in the order of the transaction types
+    pub target: Option<TransactionType>,
+}
+
 impl TryFrom<CsvTransaction> for Transaction {
     type Error = TransactionError;
     fn try_from(csv_transaction: CsvTransaction) -> Result<Self, Self::Error> {
@@ -77,7 +242,8 @@ impl TryFrom<CsvTransaction> for Transaction {
                         amount,
                     }))
                 } else {
-                    Err(TransactionError::WrongFormat)
+                    Err(TransactionError::new(TransactionErrorKind::WrongFormat)
+                        .with_field("amount"))
                 }
             }
             Withdrawal => {
@@ -88,7 +254,8 @@ impl TryFrom<CsvTransaction> for Transaction {
                         amount,
                     }))
                 } else {
-                    Err(TransactionError::WrongFormat)
+                    Err(TransactionError::new(TransactionErrorKind::WrongFormat)
+                        .with_field("amount"))
                 }
             }
             Dispute => {
@@ -112,6 +279,22 @@ impl TryFrom<CsvTransaction> for Transaction {
                     tx: csv_transaction.tx,
                 }))
             }
+            Release => {
+                // Intentionally ignore amount if present. Do not consider it an error
+                Ok(Transaction::Release(ReleaseInfo {
+                    client: csv_transaction.client,
+                    tx: csv_transaction.tx,
+                    target: csv_transaction.target,
+                }))
+            }
+            Deny => {
+                // Intentionally ignore amount if present. Do not consider it an error
+                Ok(Transaction::Deny(DenyInfo {
+                    client: csv_transaction.client,
+                    tx: csv_transaction.tx,
+                    target: csv_transaction.target,
+                }))
+            }
         }
     }
 }
@@ -120,21 +303,755 @@ impl TryFrom<CsvTransaction> for Transaction {
 pub fn read_transactions(
     reader: impl std::io::Read,
 ) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    read_transactions_with(reader, &CsvDialect::default())
+}
+
+/// Read transactions of the given CSV dialect from input reader
+pub fn read_transactions_with(
+    reader: impl std::io::Read,
+    dialect: &CsvDialect,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    read_transactions_with_ids(reader, dialect, None)
+}
+
+/// Read transactions of the given CSV dialect from input reader. With external IDs the client
+/// and tx fields are read as strings, e.g. UUIDs, and mapped to internal IDs
+pub fn read_transactions_with_ids(
+    reader: impl std::io::Read,
+    dialect: &CsvDialect,
+    ids: Option<ExternalIds>,
+) -> impl Iterator<Item = Result<Transaction, TransactionError>> {
+    csv_transactions(reader, dialect, ids)
+}
+
+fn csv_transactions<R: std::io::Read>(
+    reader: R,
+    dialect: &CsvDialect,
+    ids: Option<ExternalIds>,
+) -> CsvTransactions<R> {
     // Try to read deserialized transactions from input reader
-    let reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(reader);
-    let csv_transactions = reader.into_deserialize::<CsvTransaction>();
-    // Map CSV transaction structs to a more flexible type
-    csv_transactions.map(|csv_transaction_result| {
-        csv_transaction_result
-            .map_err(|_| TransactionError::CsvDeserializeError)
-            .and_then(|csv_transaction| csv_transaction.try_into())
+    let mut reader = dialect
+        .reader_builder()
+        .from_reader(RecordedReader::new(reader));
+    // Without a readable header the fields are deserialized in their default order
+    let headers = if dialect.has_headers {
+        reader.headers().ok().cloned()
+    } else {
+        Some(CsvDialect::default_headers())
+    };
+    CsvTransactions::new(reader, headers, dialect, ids)
+}
+
+/// Returns the header of the given CSV input as it is used to read its records, i.e. None if it
+/// cannot be read and the default header for input without one
+pub(crate) fn csv_headers(input: &[u8], dialect: &CsvDialect) -> Option<csv::StringRecord> {
+    if dialect.has_headers {
+        dialect
+            .reader_builder()
+            .from_reader(input)
+            .headers()
+            .ok()
+            .cloned()
+    } else {
+        Some(CsvDialect::default_headers())
+    }
+}
+
+/// Reads the transactions of the chunk of the given CSV input which starts at the record at
+/// `start` and ends before the byte offset `end`. The reader is moved to the chunk after the
+/// header has been read, so that the transactions, the errors and their positions are the
+/// same as when reading the whole input
+pub(crate) fn read_csv_chunk(
+    input: &[u8],
+    dialect: &CsvDialect,
+    start: &csv::Position,
+    end: u64,
+) -> Vec<Result<Transaction, TransactionError>> {
+    let mut transactions = csv_transactions(std::io::Cursor::new(input), dialect, None);
+    if start.byte() > 0 {
+        let seek = std::io::SeekFrom::Start(start.byte());
+        if let Err(error) = transactions.reader.seek_raw(seek, start.clone()) {
+            let headers = transactions.headers.as_ref();
+            return vec![Err(TransactionError::from_csv(error, headers))];
+        }
+    }
+    let mut chunk = Vec::new();
+    while transactions.reader.position().byte() < end {
+        match transactions.next() {
+            Some(transaction) => chunk.push(transaction),
+            None => break,
+        }
+    }
+    chunk
+}
+
+/// Indices of the transaction columns in the CSV records
+struct CsvColumns {
+    transaction_type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    target: Option<usize>,
+}
+
+impl CsvColumns {
+    /// Finds the columns by their expected names. Returns None if a column is missing or
+    /// ambiguous, which is left to serde to report
+    fn find(headers: &csv::StringRecord) -> Option<Self> {
+        let index = |name: &str| -> Option<Option<usize>> {
+            let mut indices = headers
+                .iter()
+                .enumerate()
+                .filter(|(_, header)| *header == name)
+                .map(|(index, _)| index);
+            match (indices.next(), indices.next()) {
+                (_, Some(_)) => None,
+                (index, None) => Some(index),
+            }
+        };
+        Some(Self {
+            transaction_type: index("type")??,
+            client: index("client")??,
+            tx: index("tx")??,
+            amount: index("amount")?,
+            target: index("target")?,
+        })
+    }
+}
+
+/// Parses a decimal ID. Returns None for anything else, e.g. signs or hexadecimal IDs
+fn parse_id(field: &[u8]) -> Option<u64> {
+    if field.is_empty() {
+        return None;
+    }
+    field.iter().try_fold(0u64, |id, byte| {
+        let digit = byte.checked_sub(b'0').filter(|digit| *digit < 10)?;
+        id.checked_mul(10)?.checked_add(u64::from(digit))
     })
 }
 
-#[derive(Debug)]
-pub enum TransactionError {
+/// Iterator over the transactions of a CSV input. Every record is read into the same buffer
+/// and its fields are decoded in place from their bytes, so only the decoded fields are
+/// checked for UTF-8. Records which the decoder does not accept are deserialized with serde,
+/// so that both paths yield the same transactions and errors
+struct CsvTransactions<R> {
+    reader: csv::Reader<RecordedReader<R>>,
+    record: csv::ByteRecord,
+    /// The reader only trims string records, byte records are trimmed here
+    trim: bool,
+    headers: Option<csv::StringRecord>,
+    expected_headers: Option<csv::ByteRecord>,
+    columns: Option<CsvColumns>,
+    /// Added to the record numbers of the reader, which count the header as record 0
+    record_offset: u64,
+    ids: Option<ExternalIds>,
+}
+
+impl<R: std::io::Read> CsvTransactions<R> {
+    fn new(
+        reader: csv::Reader<RecordedReader<R>>,
+        headers: Option<csv::StringRecord>,
+        dialect: &CsvDialect,
+        ids: Option<ExternalIds>,
+    ) -> Self {
+        // Fields are deserialized by their expected names, errors refer to the names of the input
+        let expected_headers = headers.as_ref().map(|headers| dialect.map_headers(headers));
+        Self {
+            reader,
+            record: csv::ByteRecord::new(),
+            trim: dialect.trim,
+            columns: expected_headers.as_ref().and_then(CsvColumns::find),
+            headers,
+            expected_headers: expected_headers.map(csv::StringRecord::into_byte_record),
+            record_offset: u64::from(!dialect.has_headers),
+            ids,
+        }
+    }
+
+    /// Decodes the current record without allocating. Returns None if the record has to be
+    /// deserialized with serde, e.g. because a field does not parse
+    fn decode_record(&self) -> Option<CsvTransaction> {
+        let columns = self.columns.as_ref()?;
+        let record = &self.record;
+        let transaction_type =
+            TransactionType::from_name_bytes(record.get(columns.transaction_type)?)?;
+        let client = record.get(columns.client)?;
+        let tx = record.get(columns.tx)?;
+        let amount = match columns.amount.and_then(|index| record.get(index)) {
+            None | Some(b"") => None,
+            Some(amount) => Some(std::str::from_utf8(amount).ok()?.parse().ok()?),
+        };
+        let target = match columns.target.and_then(|index| record.get(index)) {
+            None | Some(b"") => None,
+            Some(target) => Some(TransactionType::from_name_bytes(target)?),
+        };
+        // External IDs are mapped last, so that rejected records do not assign IDs
+        let (client, tx) = match &self.ids {
+            Some(ids) => {
+                let (client, tx) = (
+                    std::str::from_utf8(client).ok()?,
+                    std::str::from_utf8(tx).ok()?,
+                );
+                (ids.client_id(client), ids.transaction_id(tx))
+            }
+            None => (parse_id(client)?, parse_id(tx)?),
+        };
+        Some(CsvTransaction {
+            transaction_type,
+            client,
+            tx,
+            amount,
+            target,
+        })
+    }
+
+    fn deserialize_record(&self) -> Result<CsvTransaction, TransactionError> {
+        let headers = self.expected_headers.as_ref();
+        let csv_transaction = match &self.ids {
+            Some(ids) => self
+                .record
+                .deserialize::<CsvTransaction<String, String>>(headers)
+                .map(|csv_transaction| csv_transaction.with_internal_ids(ids)),
+            None => self.record.deserialize::<CsvTransaction>(headers),
+        };
+        csv_transaction.map_err(|error| TransactionError::from_csv(error, self.headers.as_ref()))
+    }
+}
+
+impl<R: std::io::Read> Iterator for CsvTransactions<R> {
+    type Item = Result<Transaction, TransactionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The bytes of the previous records are no longer needed
+        let start = self.reader.position().byte();
+        self.reader.get_mut().discard(start);
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(true) if self.trim => self.record.trim(),
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(error) => {
+                let position = error
+                    .position()
+                    .map(|position| self.input_position(position));
+                return Some(Err(TransactionError::from_csv(
+                    error,
+                    self.headers.as_ref(),
+                )
+                .with_position(position)));
+            }
+        }
+        let csv_transaction = match self.decode_record() {
+            Some(csv_transaction) => Ok(csv_transaction),
+            None => self.deserialize_record(),
+        };
+        // Map CSV transaction structs to a more flexible type
+        let transaction = csv_transaction.and_then(|csv_transaction| csv_transaction.try_into());
+        Some(transaction.map_err(|error| {
+            let position = self.record.position();
+            let raw_record = position.map(|position| {
+                let end = self.reader.position().byte();
+                self.reader.get_ref().raw_record(position.byte(), end)
+            });
+            let position = position.map(|position| self.input_position(position));
+            let error = error.with_position(position);
+            match raw_record {
+                Some(raw_record) => error.with_record(raw_record),
+                None => error,
+            }
+        }))
+    }
+}
+
+impl<R> CsvTransactions<R> {
+    /// Returns the position of a record, numbered from 1 without the header
+    fn input_position(&self, position: &csv::Position) -> InputPosition {
+        InputPosition {
+            record: position.record() + self.record_offset,
+            line: Some(position.line()),
+            byte: position.byte(),
+        }
+    }
+}
+
+/// Reader which keeps the bytes read from the input since the start of the current record, so
+/// that a record which fails can be reported exactly as it is in the input
+struct RecordedReader<R> {
+    inner: R,
+    bytes: Vec<u8>,
+    /// Byte offset of the first kept byte in the input
+    offset: u64,
+}
+
+impl<R> RecordedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Drops the bytes before the given byte offset. The kept bytes are only moved once at
+    /// least half of them can be dropped
+    fn discard(&mut self, before: u64) {
+        let dropped = before.saturating_sub(self.offset) as usize;
+        if dropped > 0 && dropped >= self.bytes.len() / 2 {
+            let dropped = dropped.min(self.bytes.len());
+            self.bytes.drain(..dropped);
+            self.offset += dropped as u64;
+        }
+    }
+
+    /// Returns the record between the given byte offsets without line terminators, which
+    /// a record may also start with after a `\r\n` terminator. Bytes which are not UTF-8 are
+    /// replaced
+    fn raw_record(&self, start: u64, end: u64) -> String {
+        let index =
+            |offset: u64| (offset.saturating_sub(self.offset) as usize).min(self.bytes.len());
+        let raw_record = String::from_utf8_lossy(&self.bytes[index(start)..index(end)]);
+        raw_record.trim_matches(['\n', '\r']).to_string()
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for RecordedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: std::io::Seek> std::io::Seek for RecordedReader<R> {
+    fn seek(&mut self, position: std::io::SeekFrom) -> std::io::Result<u64> {
+        let offset = self.inner.seek(position)?;
+        self.bytes.clear();
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+/// What went wrong while reading a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionErrorKind {
     CsvDeserializeError,
+    JsonDeserializeError,
+    BinaryDecodeError,
     WrongFormat,
 }
+
+impl std::fmt::Display for TransactionErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            TransactionErrorKind::CsvDeserializeError => "invalid CSV record",
+            TransactionErrorKind::JsonDeserializeError => "invalid JSON record",
+            TransactionErrorKind::BinaryDecodeError => "invalid binary record",
+            TransactionErrorKind::WrongFormat => "wrong transaction format",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Where a record starts in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputPosition {
+    /// Number of the record in the input, starting from 1. The CSV header is not counted
+    pub record: u64,
+    /// Line of the record, starting from 1. Not available for binary input
+    pub line: Option<u64>,
+    /// Byte offset of the record, starting from 0
+    pub byte: u64,
+}
+
+/// Error of a transaction which could not be read from the input
+#[derive(Debug)]
+pub struct TransactionError {
+    kind: TransactionErrorKind,
+    position: Option<InputPosition>,
+    record: Option<String>,
+    field: Option<String>,
+    cause: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl TransactionError {
+    /// Creates a new TransactionError of the given kind without any context
+    pub fn new(kind: TransactionErrorKind) -> Self {
+        Self {
+            kind,
+            position: None,
+            record: None,
+            field: None,
+            cause: None,
+        }
+    }
+
+    fn from_csv(error: csv::Error, headers: Option<&csv::StringRecord>) -> Self {
+        let field = match error.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err
+                .field()
+                .and_then(|index| headers.and_then(|headers| headers.get(index as usize)))
+                .map(String::from),
+            _ => None,
+        };
+        let error = Self::new(TransactionErrorKind::CsvDeserializeError).with_cause(error);
+        match field {
+            Some(field) => error.with_field(field),
+            None => error,
+        }
+    }
+
+    /// Sets where the record starts in the input
+    pub fn with_position(mut self, position: Option<InputPosition>) -> Self {
+        self.position = position.or(self.position);
+        self
+    }
+
+    /// Sets the raw record which failed
+    pub fn with_record(mut self, record: impl Into<String>) -> Self {
+        self.record = Some(record.into());
+        self
+    }
+
+    /// Sets the field of the record which failed
+    pub fn with_field(mut self, field: impl Into<String>) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
+    /// Sets the underlying cause of the error
+    pub fn with_cause(
+        mut self,
+        cause: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        self.cause = Some(cause.into());
+        self
+    }
+
+    /// Returns what went wrong
+    pub fn kind(&self) -> TransactionErrorKind {
+        self.kind
+    }
+
+    /// Returns where the record starts in the input, if known
+    pub fn position(&self) -> Option<InputPosition> {
+        self.position
+    }
+
+    /// Returns the raw record which failed, if known
+    pub fn record(&self) -> Option<&str> {
+        self.record.as_deref()
+    }
+
+    /// Returns the field of the record which failed, if known
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(position) = self.position {
+            match position.line {
+                Some(line) => write!(f, " at line {} (byte {})", line, position.byte)?,
+                None => write!(f, " at record {} (byte {})", position.record, position.byte)?,
+            }
+        }
+        if let Some(field) = &self.field {
+            write!(f, " in field `{}`", field)?;
+        }
+        if let Some(cause) = &self.cause {
+            write!(f, ": {}", cause)?;
+        }
+        if let Some(record) = &self.record {
+            write!(f, " (record: {:?})", record)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_ref()
+            .map(|cause| cause.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads transactions by deserializing every record with serde, as the reference of the
+    /// decoding of `CsvTransactions`
+    fn serde_transactions(
+        input: &[u8],
+        dialect: &CsvDialect,
+        ids: Option<ExternalIds>,
+    ) -> Vec<Result<Transaction, TransactionError>> {
+        let mut reader = dialect.reader_builder().from_reader(input);
+        let headers = if dialect.has_headers {
+            reader.headers().ok().cloned()
+        } else {
+            Some(CsvDialect::default_headers())
+        };
+        let expected_headers = headers
+            .as_ref()
+            .map(|headers| dialect.map_headers(headers).into_byte_record());
+        let position = |position: &csv::Position| InputPosition {
+            record: position.record() + u64::from(!dialect.has_headers),
+            line: Some(position.line()),
+            byte: position.byte(),
+        };
+        let mut transactions = Vec::new();
+        let mut record = csv::ByteRecord::new();
+        loop {
+            match reader.read_byte_record(&mut record) {
+                Ok(true) if dialect.trim => record.trim(),
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) => {
+                    let error_position = error.position().map(position);
+                    transactions.push(Err(TransactionError::from_csv(error, headers.as_ref())
+                        .with_position(error_position)));
+                    continue;
+                }
+            }
+            let record_position = record.position().unwrap();
+            let raw_record =
+                &input[record_position.byte() as usize..reader.position().byte() as usize];
+            let raw_record = String::from_utf8_lossy(raw_record)
+                .trim_matches(['\n', '\r'])
+                .to_string();
+            let csv_transaction = match &ids {
+                Some(ids) => record
+                    .deserialize::<CsvTransaction<String, String>>(expected_headers.as_ref())
+                    .map(|csv_transaction| csv_transaction.with_internal_ids(ids)),
+                None => record.deserialize::<CsvTransaction>(expected_headers.as_ref()),
+            };
+            transactions.push(
+                csv_transaction
+                    .map_err(|error| TransactionError::from_csv(error, headers.as_ref()))
+                    .and_then(|csv_transaction| csv_transaction.try_into())
+                    .map_err(|error| {
+                        error
+                            .with_position(Some(position(record_position)))
+                            .with_record(raw_record)
+                    }),
+            );
+        }
+        transactions
+    }
+
+    /// Asserts that the decoder and serde read the same transactions, errors and external IDs
+    fn assert_equivalent(input: &[u8], dialect: &CsvDialect, external_ids: bool) {
+        let (decoder_ids, serde_ids) = (ExternalIds::new(), ExternalIds::new());
+        let debug =
+            |transaction: Result<Transaction, TransactionError>| format!("{:?}", transaction);
+        let decoded: Vec<_> =
+            read_transactions_with_ids(input, dialect, external_ids.then(|| decoder_ids.clone()))
+                .map(debug)
+                .collect();
+        let deserialized: Vec<_> =
+            serde_transactions(input, dialect, external_ids.then(|| serde_ids.clone()))
+                .into_iter()
+                .map(debug)
+                .collect();
+        assert!(!decoded.is_empty());
+        assert_eq!(decoded, deserialized);
+        let (mut decoder_map, mut serde_map) = (Vec::new(), Vec::new());
+        decoder_ids.write_csv(&mut decoder_map).unwrap();
+        serde_ids.write_csv(&mut serde_map).unwrap();
+        assert_eq!(decoder_map, serde_map);
+    }
+
+    #[test]
+    fn test_decoder_matches_serde_on_example_inputs() {
+        let mut inputs = 0;
+        for entry in std::fs::read_dir("example_inputs").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "csv") {
+                let input = std::fs::read(&path).unwrap();
+                assert_equivalent(&input, &CsvDialect::default(), false);
+                assert_equivalent(&input, &CsvDialect::default(), true);
+                inputs += 1;
+            }
+        }
+        assert!(inputs >= 5);
+    }
+
+    #[test]
+    fn test_decoder_matches_serde_on_edge_cases() {
+        let mut input = b"type,client,tx,amount,note
+deposit,1,1,1.5,x
+DEPOSIT , 2 , 2 , 2.25 ,
+Withdrawal,0x10,3,1.0,
+deposit,+4,4,1.0,
+deposit,5,0x5,1e2,
+deposit,6,6,abc,
+dispute,1,1,oops,
+resolve,1,1,,
+chargeback,18446744073709551616,7,,
+refund,1,8,1.0,
+deposit,-1,9,1.0,
+deposit,,10,1.0,
+withdrawal,1,11,,
+deposit,1,12,NaN,
+deposit,1,13,inf,
+deposit,1,14
+\"deposit\",\"1\",\"15\",\"1.0\",\"a,b\"
+"
+        .to_vec();
+        input.extend_from_slice(b"deposit,1,\xff,1.0,\ndeposit,1,16,1.0,\n");
+        let dialect = CsvDialect::default();
+        assert_equivalent(&input, &dialect, false);
+        assert_equivalent(&input, &dialect, true);
+        let untrimmed = CsvDialect {
+            trim: false,
+            ..CsvDialect::default()
+        };
+        assert_equivalent(&input, &untrimmed, false);
+
+        // Records without a header, with missing and extra columns
+        let headerless = CsvDialect {
+            has_headers: false,
+            ..CsvDialect::default()
+        };
+        assert_equivalent(b"deposit,1,1\ndispute,1,1\n", &headerless, false);
+        assert_equivalent(b"deposit,1,1,1.0,x\ndispute,1,1,,y\n", &headerless, false);
+
+        // Renamed, reordered, missing and ambiguous columns
+        let renamed = CsvDialect {
+            delimiter: b';',
+            column_names: [("client".to_string(), "client_id".to_string())]
+                .into_iter()
+                .collect(),
+            ..CsvDialect::default()
+        };
+        assert_equivalent(
+            b"amount;tx;client_id;type\n1.0;1;7;deposit\n",
+            &renamed,
+            false,
+        );
+        assert_equivalent(b"type,client,amount\ndeposit,1,1.0\n", &dialect, false);
+        assert_equivalent(b"type,client,tx\ndeposit,1,1\n", &dialect, false);
+        assert_equivalent(b"type,client,client,tx\ndeposit,1,2,1\n", &dialect, false);
+    }
+
+    #[test]
+    fn test_decoder_checks_only_decoded_fields_for_utf8() {
+        let input = b"type,client,tx,amount,note\ndeposit,1,1,1.0,\xff\ndeposit,1,\xff,1.0,\n";
+        let transactions: Vec<_> =
+            read_transactions_with_ids(&input[..], &CsvDialect::default(), None).collect();
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions[0].is_ok());
+        assert!(transactions[1].is_err());
+    }
+
+    #[test]
+    fn test_decoder_accepts_plain_records() {
+        let input = std::fs::read("example_inputs/transactions.csv").unwrap();
+        let mut transactions = csv_transactions(input.as_slice(), &CsvDialect::default(), None);
+        let mut records = 0;
+        while transactions
+            .reader
+            .read_byte_record(&mut transactions.record)
+            .unwrap()
+        {
+            assert!(transactions.decode_record().is_some());
+            records += 1;
+        }
+        assert!(records > 0);
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id(b"0"), Some(0));
+        assert_eq!(parse_id(b"18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_id(b"18446744073709551616"), None);
+        assert_eq!(parse_id(b""), None);
+        assert_eq!(parse_id(b"+1"), None);
+        assert_eq!(parse_id(b"0x1"), None);
+        assert_eq!(
+            TransactionType::from_name("ChargeBack"),
+            Some(TransactionType::ChargeBack)
+        );
+        assert_eq!(TransactionType::from_name("refund"), None);
+    }
+
+    #[test]
+    fn test_csv_error_context() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\ndeposit,-1,3,1.0\n";
+        let transactions: Vec<_> = read_transactions(input.as_bytes()).collect();
+        assert_eq!(transactions.len(), 3);
+        assert!(transactions[0].is_ok());
+
+        let error = transactions[1].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::WrongFormat);
+        assert_eq!(error.field(), Some("amount"));
+        assert_eq!(error.record(), Some("deposit,1,2,"));
+        let expected_position = InputPosition {
+            record: 2,
+            line: Some(3),
+            byte: 38,
+        };
+        assert_eq!(error.position(), Some(expected_position));
+
+        let error = transactions[2].as_ref().unwrap_err();
+        assert_eq!(error.kind(), TransactionErrorKind::CsvDeserializeError);
+        assert_eq!(error.field(), Some("client"));
+        assert_eq!(error.record(), Some("deposit,-1,3,1.0"));
+        assert_eq!(error.position().unwrap().line, Some(4));
+        assert!(std::error::Error::source(error).is_some());
+        assert!(error
+            .to_string()
+            .starts_with("invalid CSV record at line 4 (byte 51) in field `client`: "));
+    }
+
+    #[test]
+    fn test_csv_raw_record_and_numbering() {
+        // The raw record keeps the quotes, whitespace and line terminator free bytes of the input
+        let input = "type,client,tx,amount\r\ndeposit, 1 ,\"2\",\r\n";
+        let transactions: Vec<_> = read_transactions(input.as_bytes()).collect();
+        let error = transactions[0].as_ref().unwrap_err();
+        assert_eq!(error.record(), Some("deposit, 1 ,\"2\","));
+        assert_eq!(error.position().unwrap().record, 1);
+
+        // Without a header the first record is still record 1
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..CsvDialect::default()
+        };
+        let input = "deposit,1,1,1.0\ndeposit,1,2,\n";
+        let transactions: Vec<_> = read_transactions_with(input.as_bytes(), &dialect).collect();
+        let error = transactions[1].as_ref().unwrap_err();
+        assert_eq!(error.record(), Some("deposit,1,2,"));
+        assert_eq!(error.position().unwrap().record, 2);
+        assert_equivalent(input.as_bytes(), &dialect, false);
+    }
+
+    #[test]
+    fn test_wide_and_external_ids() {
+        let input = "type,client,tx,amount\ndeposit,70000,5000000000,1.0\n";
+        let transactions: Vec<_> = read_transactions(input.as_bytes()).collect();
+        let transaction = transactions[0].as_ref().unwrap();
+        assert_eq!(
+            (transaction.client(), transaction.tx()),
+            (70000, 5000000000)
+        );
+
+        let input = "type,client,tx,amount\n\
+                     deposit,alice,a1,1.0\n\
+                     deposit,bob,b1,2.0\n\
+                     dispute,alice,a1,\n";
+        let ids = ExternalIds::new();
+        let transactions: Vec<_> =
+            read_transactions_with_ids(input.as_bytes(), &CsvDialect::default(), Some(ids.clone()))
+                .map(|transaction| {
+                    let transaction = transaction.unwrap();
+                    (transaction.client(), transaction.tx())
+                })
+                .collect();
+        assert_eq!(transactions, vec![(1, 1), (2, 2), (1, 1)]);
+        assert_eq!(ids.client_name(2).as_deref(), Some("bob"));
+    }
+}
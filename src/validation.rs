@@ -0,0 +1,160 @@
+use crate::engine::{ErrorLimit, PaymentEngine};
+use crate::transactions::{Transaction, TransactionError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Summary of a dry run of an input through a PaymentEngine
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ValidationSummary {
+    /// Number of records read from the input
+    pub records: usize,
+    /// Number of parsed transactions by type
+    pub transactions: BTreeMap<String, usize>,
+    /// Number of records which failed to parse by error kind
+    pub parse_errors: BTreeMap<String, usize>,
+    /// Number of transactions which have been rejected by reason
+    pub rejections: BTreeMap<String, usize>,
+    /// Number of transactions which have been held for review by the fraud scorer
+    pub held: usize,
+    /// Number of clients created by the input
+    pub clients_created: usize,
+    /// Number of disputes which have been neither resolved nor charged back
+    pub open_disputes: usize,
+    /// Number of accounts which are locked at the end of the input
+    pub locked_accounts: usize,
+}
+
+impl ValidationSummary {
+    /// Returns true if every record has been parsed and applied
+    pub fn is_valid(&self) -> bool {
+        self.parse_errors.is_empty() && self.rejections.is_empty()
+    }
+
+    /// Writes the summary in a human readable format
+    pub fn write_text(&self, mut writer: impl std::io::Write) -> std::io::Result<()> {
+        let total = |counts: &BTreeMap<String, usize>| counts.values().sum::<usize>();
+        writeln!(writer, "records: {}", self.records)?;
+        for (title, counts) in [
+            ("transactions", &self.transactions),
+            ("parse errors", &self.parse_errors),
+            ("rejections", &self.rejections),
+        ] {
+            writeln!(writer, "{}: {}", title, total(counts))?;
+            for (key, count) in counts {
+                writeln!(writer, "  {}: {}", key, count)?;
+            }
+        }
+        writeln!(writer, "held for review: {}", self.held)?;
+        writeln!(writer, "clients created: {}", self.clients_created)?;
+        writeln!(writer, "open disputes: {}", self.open_disputes)?;
+        writeln!(writer, "locked accounts: {}", self.locked_accounts)
+    }
+}
+
+/// Runs the given transactions through the given PaymentEngine, with its fraud scorer, lock
+/// policies and storage, and summarizes the outcome. The counts cover all the inputs the engine
+/// has processed, so the engine is expected to be new
+pub fn validate(
+    transactions: impl Iterator<Item = Result<Transaction, TransactionError>>,
+    payment_engine: &mut PaymentEngine,
+) -> ValidationSummary {
+    let clients_before = payment_engine.client_count();
+    // An unlimited run is never aborted
+    let _ = payment_engine.run_input(transactions, ErrorLimit::Unlimited);
+    let stats = payment_engine.stats();
+    let counts = |counts: &BTreeMap<String, u64>| {
+        counts
+            .iter()
+            .map(|(key, count)| (key.clone(), *count as usize))
+            .collect()
+    };
+    ValidationSummary {
+        records: stats.records as usize,
+        transactions: stats
+            .transaction_types
+            .iter()
+            .map(|(transaction_type, type_stats)| {
+                (transaction_type.clone(), type_stats.read as usize)
+            })
+            .collect(),
+        parse_errors: counts(&stats.parse_error_kinds),
+        rejections: counts(&stats.rejection_reasons),
+        held: stats.held as usize,
+        clients_created: payment_engine.client_count() - clients_before,
+        open_disputes: payment_engine.open_disputes(),
+        locked_accounts: payment_engine.locked_accounts(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{ClientInfoStorage, LockPolicy};
+    use crate::fraud::ApproveAll;
+    use crate::transactions::read_transactions;
+
+    #[test]
+    fn test_validation_summary() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     deposit,2,2,\n\
+                     deposit,2,3,1.0\n\
+                     withdrawal,1,4,3.0\n\
+                     dispute,1,1,\n\
+                     dispute,2,3,\n\
+                     chargeback,2,3,\n\
+                     deposit,2,5,1.0\n";
+        let summary = validate(
+            read_transactions(input.as_bytes()),
+            &mut PaymentEngine::new(),
+        );
+        let counts = |entries: &[(&str, usize)]| {
+            entries
+                .iter()
+                .map(|(key, count)| (key.to_string(), *count))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(summary.records, 8);
+        assert_eq!(
+            summary.transactions,
+            counts(&[
+                ("chargeback", 1),
+                ("deposit", 3),
+                ("dispute", 2),
+                ("withdrawal", 1)
+            ])
+        );
+        assert_eq!(
+            summary.parse_errors,
+            counts(&[("wrong transaction format", 1)])
+        );
+        assert_eq!(
+            summary.rejections,
+            counts(&[("account_locked", 1), ("insufficient_funds", 1)])
+        );
+        assert_eq!(summary.clients_created, 2);
+        assert_eq!(summary.open_disputes, 1);
+        assert_eq!(summary.locked_accounts, 1);
+        assert!(!summary.is_valid());
+    }
+
+    #[test]
+    fn test_validation_applies_engine_policies() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,1,2,5.0\n\
+                     dispute,1,1,\n\
+                     deposit,1,3,1.0\n";
+        let lock_policy = LockPolicy {
+            max_disputed_ratio: Some(0.5),
+            ..LockPolicy::default()
+        };
+        let mut payment_engine = PaymentEngine::with(
+            ClientInfoStorage::with_lock_policy(lock_policy),
+            Box::new(ApproveAll),
+        );
+        let summary = validate(read_transactions(input.as_bytes()), &mut payment_engine);
+        assert_eq!(summary.locked_accounts, 1);
+        assert_eq!(summary.rejections.get("account_locked"), Some(&1));
+    }
+}
@@ -1,5 +1,14 @@
-use payment_engine::engine::PaymentEngine;
-use payment_engine::transactions::read_transactions;
+use payment_engine::accounts::{
+    ClientInfoStorage, Rejection, RetentionAge, RetentionPolicy, UpdateOutcome,
+};
+use payment_engine::dialect::CsvDialect;
+use payment_engine::engine::{ErrorLimit, InputStats, PaymentEngine, RecordError};
+use payment_engine::fraud::{ApproveAll, ClientHistory, FraudScorer, RulesScorer, Verdict};
+use payment_engine::ids::ExternalIds;
+use payment_engine::input::{read_transactions_as, write_binary_transaction, InputFormat};
+use payment_engine::transactions::{
+    read_transactions, read_transactions_with_ids, DepositInfo, Transaction, WithdrawalInfo,
+};
 
 #[test]
 fn integration_test() {
@@ -8,20 +17,15 @@ fn integration_test() {
     let transactions = read_transactions(input_file);
 
     let engine = PaymentEngine::run(transactions);
-    let output_file_path = "output.csv";
-    engine.output_to_csv_format(std::fs::File::create(output_file_path).unwrap());
+    let mut output = Vec::new();
+    engine.output_to_csv_format(&mut output);
 
-    let mut output = csv::Reader::from_reader(std::fs::File::open(output_file_path).unwrap());
+    let mut output = csv::Reader::from_reader(output.as_slice());
     let records: Vec<csv::StringRecord> = output.records().flatten().collect();
     let client_1 = vec!["1", "0.8", "0.0", "0.8", "false"];
     let client_2 = vec!["2", "0.5", "0.0", "0.5", "true"];
-    if &records[0][0] == "1" {
-        assert_eq!(records[0], client_1);
-        assert_eq!(records[1], client_2);
-    } else {
-        assert_eq!(records[1], client_1);
-        assert_eq!(records[0], client_2);
-    }
+    assert_eq!(records[0], client_1);
+    assert_eq!(records[1], client_2);
 }
 
 #[test]
@@ -31,18 +35,356 @@ fn integration_test_wrong_format() {
     let transactions = read_transactions(input_file);
 
     let engine = PaymentEngine::run(transactions);
-    let output_file_path = "output.csv";
-    engine.output_to_csv_format(std::fs::File::create(output_file_path).unwrap());
+    let mut output = Vec::new();
+    engine.output_to_csv_format(&mut output);
 
-    let mut output = csv::Reader::from_reader(std::fs::File::open(output_file_path).unwrap());
+    let mut output = csv::Reader::from_reader(output.as_slice());
     let records: Vec<csv::StringRecord> = output.records().flatten().collect();
     let client_1 = vec!["1", "0.8", "0.0", "0.8", "false"];
     let client_2 = vec!["2", "0.5", "0.0", "0.5", "true"];
-    if &records[0][0] == "1" {
-        assert_eq!(records[0], client_1);
-        assert_eq!(records[1], client_2);
-    } else {
-        assert_eq!(records[1], client_1);
-        assert_eq!(records[0], client_2);
+    assert_eq!(records[0], client_1);
+    assert_eq!(records[1], client_2);
+
+    let stats = engine.stats();
+    assert_eq!(
+        (
+            stats.records,
+            stats.applied,
+            stats.rejected,
+            stats.parse_errors
+        ),
+        (16, 9, 5, 2)
+    );
+    assert_eq!(stats.transaction_types["deposit"].read, 4);
+    assert_eq!(stats.rejection_reasons["insufficient_funds"], 1);
+}
+
+#[test]
+fn integration_test_error_limit() {
+    let run = |error_limit| {
+        let input_file = std::fs::File::open("example_inputs/transactions_wrong_format.csv")
+            .expect("Unable to open input file");
+        PaymentEngine::try_run_with(
+            read_transactions(input_file),
+            ClientInfoStorage::new(),
+            Box::new(ApproveAll),
+            error_limit,
+        )
+    };
+    // Strict mode aborts on the first record with a missing amount
+    let error = run(ErrorLimit::STRICT).err().unwrap();
+    assert_eq!((error.errors, error.record), (1, 2));
+    match error.error {
+        RecordError::Parse(error) => assert_eq!(error.field(), Some("amount")),
+        error => panic!("Unexpected error: {}", error),
     }
+    // The sixth error is a dispute of a withdrawal
+    let error = run(ErrorLimit::Max(5)).err().unwrap();
+    assert_eq!((error.errors, error.record), (6, 11));
+    assert!(matches!(
+        error.error,
+        RecordError::Rejected {
+            client: 2,
+            tx: 5,
+            rejection: Rejection::UnknownTransaction,
+            ..
+        }
+    ));
+    assert!(run(ErrorLimit::Max(100)).is_ok());
+}
+
+#[test]
+fn integration_test_multiple_inputs() {
+    let mut engine = PaymentEngine::with(ClientInfoStorage::new(), Box::new(ApproveAll));
+    let mut stats = Vec::new();
+    for path in [
+        "example_inputs/transactions.csv",
+        "example_inputs/simple_transactions.csv",
+    ] {
+        let input_file = std::fs::File::open(path).expect("Unable to open input file");
+        let transactions = read_transactions(input_file);
+        stats.push(
+            engine
+                .run_input(transactions, ErrorLimit::Unlimited)
+                .unwrap(),
+        );
+    }
+    assert_eq!(
+        stats,
+        vec![
+            InputStats {
+                records: 14,
+                applied: 9,
+                held: 0,
+                parse_errors: 0,
+                rejected: 5,
+            },
+            InputStats {
+                records: 5,
+                applied: 2,
+                held: 0,
+                parse_errors: 0,
+                rejected: 3,
+            },
+        ]
+    );
+
+    let mut output = Vec::new();
+    engine.output_to_csv_format(&mut output);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,1.3,0.0,1.3,false\n2,0.5,0.0,0.5,true\n"
+    );
+}
+
+#[test]
+fn integration_test_deterministic_output() {
+    let run = || {
+        let input_file = std::fs::File::open("example_inputs/transactions_chargeback.csv")
+            .expect("Unable to open input file");
+        let engine = PaymentEngine::run(read_transactions(input_file));
+        let mut output = Vec::new();
+        engine.output_to_csv_format(&mut output);
+        output
+    };
+    let output = run();
+    for _ in 0..10 {
+        assert_eq!(run(), output);
+    }
+}
+
+#[test]
+fn integration_test_fraud_rules() {
+    let input_file = std::fs::File::open("example_inputs/transactions_fraud.csv")
+        .expect("Unable to open input file");
+    let transactions = read_transactions(input_file);
+
+    let engine = PaymentEngine::run_with_scorer(transactions, Box::new(RulesScorer::default()));
+    assert_eq!(engine.pending_review().count(), 0);
+    let mut output = Vec::new();
+    engine.output_to_csv_format(&mut output);
+
+    let mut output = csv::Reader::from_reader(output.as_slice());
+    let records: Vec<csv::StringRecord> = output.records().flatten().collect();
+    let client_1 = vec!["1", "0.5", "0.0", "0.5", "false"];
+    let client_2 = vec!["2", "6.0", "0.0", "6.0", "false"];
+    assert_eq!(records[0], client_1);
+    assert_eq!(records[1], client_2);
+}
+
+/// Holds every deposit and dispute for review
+struct HoldAll;
+
+impl FraudScorer for HoldAll {
+    fn score(&mut self, transaction: &Transaction, _history: &ClientHistory) -> Verdict {
+        match transaction {
+            Transaction::Deposit(_) | Transaction::Dispute(_) => Verdict::Hold("review".into()),
+            _ => Verdict::Approve,
+        }
+    }
+}
+
+#[test]
+fn integration_test_review_target() {
+    // The held deposit and the held dispute share their ID, the target selects the dispute
+    let input = "type,client,tx,amount,target\n\
+                 deposit,1,1,5.0,\n\
+                 dispute,1,1,,\n\
+                 deny,1,1,,dispute\n\
+                 release,1,1,,\n";
+    let transactions = read_transactions(input.as_bytes());
+    let engine = PaymentEngine::run_with_scorer(transactions, Box::new(HoldAll));
+    assert_eq!(engine.pending_review().count(), 0);
+    assert_eq!(engine.stats().rejected, 0);
+    let mut output = Vec::new();
+    engine.output_to_csv_format(&mut output);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n"
+    );
+}
+
+#[test]
+fn integration_test_input_formats() {
+    let run = |transactions| {
+        let engine = PaymentEngine::run(transactions);
+        let mut output = Vec::new();
+        engine.output_to_csv_format(&mut output);
+        output
+    };
+    let csv_input =
+        std::fs::File::open("example_inputs/transactions.csv").expect("Unable to open input file");
+    let csv_output = run(read_transactions_as(InputFormat::Csv, csv_input));
+
+    let json_input = std::fs::File::open("example_inputs/transactions.jsonl")
+        .expect("Unable to open input file");
+    assert_eq!(
+        run(read_transactions_as(InputFormat::JsonLines, json_input)),
+        csv_output
+    );
+
+    let csv_input =
+        std::fs::File::open("example_inputs/transactions.csv").expect("Unable to open input file");
+    let mut binary_input = Vec::new();
+    for transaction in read_transactions(csv_input).flatten() {
+        write_binary_transaction(&mut binary_input, &transaction).unwrap();
+    }
+    let binary_input = std::io::Cursor::new(binary_input);
+    assert_eq!(
+        run(read_transactions_as(InputFormat::Binary, binary_input)),
+        csv_output
+    );
+}
+
+#[test]
+fn integration_test_incremental_api() {
+    let input_file =
+        std::fs::File::open("example_inputs/transactions.csv").expect("Unable to open input file");
+    let mut engine = PaymentEngine::new();
+    for transaction in read_transactions(input_file).flatten() {
+        engine.apply(transaction);
+    }
+    let batch = PaymentEngine::run(read_transactions(
+        std::fs::File::open("example_inputs/transactions.csv").expect("Unable to open input file"),
+    ));
+    let sorted_accounts = |engine: &PaymentEngine| {
+        let mut accounts: Vec<_> = engine.accounts().unwrap().collect();
+        accounts.sort_by_key(|account| account.client());
+        accounts
+    };
+    assert_eq!(sorted_accounts(&engine), sorted_accounts(&batch));
+
+    assert_eq!(
+        engine.apply(Transaction::Deposit(DepositInfo {
+            client: 7,
+            tx: 100,
+            amount: 2.0,
+        })),
+        UpdateOutcome::Applied
+    );
+    assert_eq!(
+        engine.apply(Transaction::Withdrawal(WithdrawalInfo {
+            client: 7,
+            tx: 101,
+            amount: 5.0,
+        })),
+        UpdateOutcome::Rejected(Rejection::InsufficientFunds)
+    );
+    assert_eq!(
+        engine.account(7).unwrap().map(|account| account.total()),
+        Some(2.0)
+    );
+    assert_eq!(
+        engine
+            .transaction(7, 100)
+            .unwrap()
+            .map(|record| record.amount),
+        Some(2.0)
+    );
+    assert_eq!(engine.stats().rejected, batch.stats().rejected + 1);
+}
+
+#[test]
+fn integration_test_external_ids_in_reports() {
+    let input = "type,client,tx,amount\n\
+                 deposit,alice,d-1,2.0\n\
+                 withdrawal,alice,w-1,5.0\n\
+                 dispute,alice,d-1,\n\
+                 chargeback,alice,d-1,\n";
+    let directory = tempfile::tempdir().unwrap();
+    let rejections_path = directory.path().join("rejections.csv");
+    let ids = ExternalIds::new();
+    let mut engine = PaymentEngine::new();
+    let rejections_file = std::fs::File::create(&rejections_path).unwrap();
+    engine.set_rejection_report(Box::new(rejections_file));
+    engine.set_external_ids(ids.clone());
+    let transactions =
+        read_transactions_with_ids(input.as_bytes(), &CsvDialect::default(), Some(ids));
+    engine
+        .run_input(transactions, ErrorLimit::Unlimited)
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&rejections_path).unwrap(),
+        "client,tx,type,reason\nalice,w-1,withdrawal,insufficient_funds\n"
+    );
+    let mut details = Vec::new();
+    engine.output_account_details(&mut details).unwrap();
+    let details = String::from_utf8(details).unwrap();
+    assert!(
+        details.contains("\nalice,0.0,0.0,0.0,true,charge_back,d-1,4,1\n"),
+        "{}",
+        details
+    );
+    let mut history = Vec::new();
+    engine.output_lock_history(&mut history).unwrap();
+    assert_eq!(
+        String::from_utf8(history).unwrap(),
+        "client,reason,tx,sequence\nalice,charge_back,d-1,4\n"
+    );
+}
+
+#[test]
+fn integration_test_external_ids_of_pruned_deposits() {
+    let input = "type,client,tx,amount\n\
+                 deposit,alice,d-1,2.0\n\
+                 deposit,alice,d-2,3.0\n\
+                 dispute,alice,d-1,\n";
+    let ids = ExternalIds::new();
+    let mut client_storage = ClientInfoStorage::new();
+    client_storage.set_retention(
+        RetentionPolicy {
+            max_age: RetentionAge::Transactions(0),
+            interval: 1,
+        },
+        None,
+    );
+    let mut engine = PaymentEngine::with(client_storage, Box::new(ApproveAll));
+    engine.set_external_ids(ids.clone());
+    let transactions =
+        read_transactions_with_ids(input.as_bytes(), &CsvDialect::default(), Some(ids));
+    let stats = engine
+        .run_input(transactions, ErrorLimit::Unlimited)
+        .unwrap();
+
+    // The pruned deposit keeps its ID, so its dispute is rejected as pruned
+    assert_eq!(stats.rejected, 1);
+    assert_eq!(
+        engine
+            .stats()
+            .rejection_reasons
+            .get(&Rejection::Pruned.to_string())
+            .copied(),
+        Some(1)
+    );
+}
+
+#[test]
+fn integration_test_validate_leaves_sqlite_untouched() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    std::fs::write(
+        &input_path,
+        "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,2,2,4.0\n",
+    )
+    .unwrap();
+    let database = directory.path().join("accounts.sqlite");
+    let run = |command: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .args(["--log", "none", "--sqlite"])
+            .arg(&database)
+            .args(command)
+            .arg(&input_path)
+            .output()
+            .unwrap()
+    };
+
+    let validation = run(&["validate"]);
+    assert!(validation.status.success(), "{:?}", validation);
+    let output = run(&[]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,4.0,0.0,4.0,false\n"
+    );
 }